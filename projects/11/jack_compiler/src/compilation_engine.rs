@@ -1,5 +1,5 @@
 use crate::tokenizer::{Token, TokenType};
-use crate::vm_writer::{VMWriter, Segment, Command};
+use crate::vm_writer::{VMWriter, Segment, Op};
 use crate::symbol_table::{SymbolTable, Kind};
 
 pub struct CompilationEngine {
@@ -282,7 +282,7 @@ impl CompilationEngine {
             self.consume_symbol("]");
             
             // arr + i
-            self.vm_writer.write_arithmetic(Command::Add);
+            self.vm_writer.write_arithmetic(Op::Add);
             
             // '='
             self.consume_symbol("=");
@@ -331,7 +331,7 @@ impl CompilationEngine {
         self.consume_symbol(")");
 
         // Negate condition for if-goto
-        self.vm_writer.write_arithmetic(Command::Not);
+        self.vm_writer.write_arithmetic(Op::Not);
 
         let else_label = self.vm_writer.generate_label("IF_ELSE");
         let end_label = self.vm_writer.generate_label("IF_END");
@@ -380,7 +380,7 @@ impl CompilationEngine {
         self.consume_symbol(")");
 
         // Negate condition
-        self.vm_writer.write_arithmetic(Command::Not);
+        self.vm_writer.write_arithmetic(Op::Not);
         self.vm_writer.write_if(&end_label);
 
         // '{'
@@ -439,15 +439,15 @@ impl CompilationEngine {
             
             // Generate VM command for operator
             match op.as_str() {
-                "+" => self.vm_writer.write_arithmetic(Command::Add),
-                "-" => self.vm_writer.write_arithmetic(Command::Sub),
+                "+" => self.vm_writer.write_arithmetic(Op::Add),
+                "-" => self.vm_writer.write_arithmetic(Op::Sub),
                 "*" => self.vm_writer.write_call("Math.multiply", 2),
                 "/" => self.vm_writer.write_call("Math.divide", 2),
-                "&" => self.vm_writer.write_arithmetic(Command::And),
-                "|" => self.vm_writer.write_arithmetic(Command::Or),
-                "<" => self.vm_writer.write_arithmetic(Command::Lt),
-                ">" => self.vm_writer.write_arithmetic(Command::Gt),
-                "=" => self.vm_writer.write_arithmetic(Command::Eq),
+                "&" => self.vm_writer.write_arithmetic(Op::And),
+                "|" => self.vm_writer.write_arithmetic(Op::Or),
+                "<" => self.vm_writer.write_arithmetic(Op::Lt),
+                ">" => self.vm_writer.write_arithmetic(Op::Gt),
+                "=" => self.vm_writer.write_arithmetic(Op::Eq),
                 _ => panic!("Unknown operator: {}", op),
             }
         }
@@ -470,7 +470,7 @@ impl CompilationEngine {
                     match keyword.as_str() {
                         "true" => {
                             self.vm_writer.write_push(Segment::Constant, 1);
-                            self.vm_writer.write_arithmetic(Command::Neg);
+                            self.vm_writer.write_arithmetic(Op::Neg);
                         }
                         "false" | "null" => {
                             self.vm_writer.write_push(Segment::Constant, 0);
@@ -497,7 +497,7 @@ impl CompilationEngine {
                         self.consume_symbol("]");
                         
                         // Add base + index
-                        self.vm_writer.write_arithmetic(Command::Add);
+                        self.vm_writer.write_arithmetic(Op::Add);
                         
                         // Pop address to that pointer and push that[0]
                         self.vm_writer.write_pop(Segment::Pointer, 1);
@@ -523,8 +523,8 @@ impl CompilationEngine {
                         self.compile_term_vm();
                         
                         match op.as_str() {
-                            "-" => self.vm_writer.write_arithmetic(Command::Neg),
-                            "~" => self.vm_writer.write_arithmetic(Command::Not),
+                            "-" => self.vm_writer.write_arithmetic(Op::Neg),
+                            "~" => self.vm_writer.write_arithmetic(Op::Not),
                             _ => panic!("Unknown unary operator: {}", op),
                         }
                     }
@@ -1,67 +1,11 @@
-use std::fmt;
-
-/// VM commands for the Hack computer
-#[derive(Debug, Clone)]
-pub enum Segment {
-    Constant,
-    Local,
-    Argument,
-    Static,
-    This,
-    That,
-    Pointer,
-    Temp,
-}
+use vm_translator::parser::VmCommand;
 
-impl fmt::Display for Segment {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let s = match self {
-            Segment::Constant => "constant",
-            Segment::Local => "local",
-            Segment::Argument => "argument",
-            Segment::Static => "static",
-            Segment::This => "this",
-            Segment::That => "that",
-            Segment::Pointer => "pointer",
-            Segment::Temp => "temp",
-        };
-        write!(f, "{}", s)
-    }
-}
-
-#[derive(Debug, Clone)]
-pub enum Command {
-    Add,
-    Sub,
-    Neg,
-    Eq,
-    Gt,
-    Lt,
-    And,
-    Or,
-    Not,
-}
-
-impl fmt::Display for Command {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let s = match self {
-            Command::Add => "add",
-            Command::Sub => "sub",
-            Command::Neg => "neg",
-            Command::Eq => "eq",
-            Command::Gt => "gt",
-            Command::Lt => "lt",
-            Command::And => "and",
-            Command::Or => "or",
-            Command::Not => "not",
-        };
-        write!(f, "{}", s)
-    }
-}
+/// VM commands for the Hack computer, shared with the VM translator
+pub use vm_translator::parser::{Op, Segment};
 
 /// VM Writer generates VM code
 pub struct VMWriter {
-    output: Vec<String>,
+    output: Vec<VmCommand>,
     label_count: usize,
 }
 
@@ -75,47 +19,55 @@ impl VMWriter {
 
     /// Write a VM push command
     pub fn write_push(&mut self, segment: Segment, index: usize) {
-        self.output.push(format!("push {} {}", segment, index));
+        let index = to_u16(index);
+        self.output.push(VmCommand::Push { segment, index });
     }
 
     /// Write a VM pop command
     pub fn write_pop(&mut self, segment: Segment, index: usize) {
-        self.output.push(format!("pop {} {}", segment, index));
+        let index = to_u16(index);
+        self.output.push(VmCommand::Pop { segment, index });
     }
 
     /// Write a VM arithmetic command
-    pub fn write_arithmetic(&mut self, command: Command) {
-        self.output.push(format!("{}", command));
+    pub fn write_arithmetic(&mut self, op: Op) {
+        self.output.push(VmCommand::Arithmetic(op));
     }
 
     /// Write a VM label command
     pub fn write_label(&mut self, label: &str) {
-        self.output.push(format!("label {}", label));
+        self.output.push(VmCommand::Label(label.to_string()));
     }
 
     /// Write a VM goto command
     pub fn write_goto(&mut self, label: &str) {
-        self.output.push(format!("goto {}", label));
+        self.output.push(VmCommand::Goto(label.to_string()));
     }
 
     /// Write a VM if-goto command
     pub fn write_if(&mut self, label: &str) {
-        self.output.push(format!("if-goto {}", label));
+        self.output.push(VmCommand::IfGoto(label.to_string()));
     }
 
     /// Write a VM call command
     pub fn write_call(&mut self, name: &str, n_args: usize) {
-        self.output.push(format!("call {} {}", name, n_args));
+        self.output.push(VmCommand::Call {
+            name: name.to_string(),
+            n_args: to_u16(n_args),
+        });
     }
 
     /// Write a VM function command
     pub fn write_function(&mut self, name: &str, n_locals: usize) {
-        self.output.push(format!("function {} {}", name, n_locals));
+        self.output.push(VmCommand::Function {
+            name: name.to_string(),
+            n_locals: to_u16(n_locals),
+        });
     }
 
     /// Write a VM return command
     pub fn write_return(&mut self) {
-        self.output.push("return".to_string());
+        self.output.push(VmCommand::Return);
     }
 
     /// Generate a unique label
@@ -127,7 +79,8 @@ impl VMWriter {
 
     /// Get the complete VM code as a string
     pub fn get_output(&self) -> String {
-        self.output.join("\n") + "\n"
+        let lines: Vec<String> = self.output.iter().map(|command| command.to_string()).collect();
+        lines.join("\n") + "\n"
     }
 
     /// Clear the output (useful for testing)
@@ -137,6 +90,11 @@ impl VMWriter {
     }
 }
 
+/// Converts an index or count to the 16-bit range of VM commands
+fn to_u16(value: usize) -> u16 {
+    u16::try_from(value).unwrap_or_else(|_| panic!("Value too large for a VM command: {}", value))
+}
+
 impl Default for VMWriter {
     fn default() -> Self {
         Self::new()
@@ -160,8 +118,8 @@ mod tests {
     #[test]
     fn test_arithmetic() {
         let mut writer = VMWriter::new();
-        writer.write_arithmetic(Command::Add);
-        writer.write_arithmetic(Command::Neg);
+        writer.write_arithmetic(Op::Add);
+        writer.write_arithmetic(Op::Neg);
         
         let output = writer.get_output();
         assert_eq!(output, "add\nneg\n");
@@ -187,4 +145,10 @@ mod tests {
         assert_eq!(label1, "LOOP_0");
         assert_eq!(label2, "END_1");
     }
+
+    #[test]
+    fn test_empty_output() {
+        let writer = VMWriter::new();
+        assert_eq!(writer.get_output(), "\n");
+    }
 }
\ No newline at end of file
@@ -0,0 +1,292 @@
+//! The Hack CPU with its instruction and data memories.
+
+use std::fs;
+use std::path::Path;
+
+/// Number of words of the instruction memory
+pub const ROM_SIZE: usize = 32768;
+/// Number of words of the data memory
+pub const RAM_SIZE: usize = 32768;
+/// Base address of the screen memory map: 256 rows of 32 words
+pub const SCREEN: usize = 16384;
+/// Number of words of the screen memory map
+pub const SCREEN_SIZE: usize = 8192;
+/// Address of the keyboard register
+pub const KBD: usize = 24576;
+
+/// Why a run stopped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// The program entered a loop that cannot exit, such as `(END) @END
+    /// 0;JMP`
+    Halted,
+    /// The cycle limit was reached
+    CycleLimit,
+    /// Execution ran past the last instruction of the program
+    End,
+}
+
+pub struct Cpu {
+    /// Program, padded with zeros (`@0`) to the full ROM
+    pub rom: Vec<u16>,
+    pub ram: Vec<i16>,
+    pub a: i16,
+    pub d: i16,
+    pub pc: u16,
+    /// Number of instructions executed so far
+    pub cycles: u64,
+    /// Number of instructions of the loaded program
+    program_len: usize,
+}
+
+impl Cpu {
+    pub fn new(program: &[u16]) -> Self {
+        let mut rom = program.to_vec();
+        rom.resize(ROM_SIZE, 0);
+        Self {
+            rom,
+            ram: vec![0; RAM_SIZE],
+            a: 0,
+            d: 0,
+            pc: 0,
+            cycles: 0,
+            program_len: program.len(),
+        }
+    }
+
+    /// Runs until the program halts, runs past its end, or `max_cycles`
+    /// instructions have executed in total.
+    pub fn run(&mut self, max_cycles: u64) -> Outcome {
+        while self.cycles < max_cycles {
+            if self.pc as usize >= self.program_len {
+                return Outcome::End;
+            }
+            let pc = self.pc;
+            self.step();
+            if self.is_halt_loop(pc) {
+                return Outcome::Halted;
+            }
+        }
+        Outcome::CycleLimit
+    }
+
+    /// Whether the instruction at `pc`, just executed, jumped into a loop
+    /// that does nothing else: onto itself, or back to an `@` loading the
+    /// address of that `@`.
+    fn is_halt_loop(&self, pc: u16) -> bool {
+        let instruction = self.rom[pc as usize % ROM_SIZE];
+        let is_jump = instruction & 0x8000 != 0 && instruction & 0b111 != 0;
+        // Any destination could change the next iteration
+        let writes = instruction & 0b111000 != 0;
+        is_jump
+            && !writes
+            && (self.pc == pc
+                || (self.pc.wrapping_add(1) == pc && self.rom[self.pc as usize] == self.pc))
+    }
+
+    /// Reads a word of RAM, through the 15-bit address bus.
+    pub fn read(&self, address: u16) -> i16 {
+        self.ram[address as usize % RAM_SIZE]
+    }
+
+    /// Writes a word of RAM, through the 15-bit address bus.
+    pub fn write(&mut self, address: u16, value: i16) {
+        self.ram[address as usize % RAM_SIZE] = value;
+    }
+
+    /// Returns the screen memory map, row by row: bit `i` of a word is the
+    /// pixel `i` columns right of the word's first pixel, 1 for black.
+    pub fn screen(&self) -> &[i16] {
+        &self.ram[SCREEN..SCREEN + SCREEN_SIZE]
+    }
+
+    /// Sets the keyboard register to the code of the key being pressed, or
+    /// 0 for none.
+    pub fn set_key(&mut self, key: i16) {
+        self.ram[KBD] = key;
+    }
+
+    /// Executes one instruction. A C-instruction ignores bits 13 and 14,
+    /// like the hardware, so every 16-bit word is a valid instruction.
+    pub fn step(&mut self) {
+        let instruction = self.rom[self.pc as usize % ROM_SIZE];
+        self.cycles += 1;
+        if instruction & 0x8000 == 0 {
+            self.a = instruction as i16;
+            self.pc = self.pc.wrapping_add(1);
+            return;
+        }
+
+        let address = self.a as u16;
+        let y = if instruction & 0x1000 != 0 {
+            self.read(address)
+        } else {
+            self.a
+        };
+        let out = alu(self.d, y, (instruction >> 6) as u8 & 0x3f);
+
+        // Destinations and the jump all see A as it was before the
+        // instruction: `AM=M+1` writes M at the old address and
+        // `A=D;JMP` jumps to the old A
+        let jump = match instruction & 0b111 {
+            0b000 => false,
+            0b001 => out > 0,
+            0b010 => out == 0,
+            0b011 => out >= 0,
+            0b100 => out < 0,
+            0b101 => out != 0,
+            0b110 => out <= 0,
+            _ => true,
+        };
+        self.pc = if jump {
+            self.a as u16
+        } else {
+            self.pc.wrapping_add(1)
+        };
+        if instruction & 0b001000 != 0 {
+            self.write(address, out);
+        }
+        if instruction & 0b100000 != 0 {
+            self.a = out;
+        }
+        if instruction & 0b010000 != 0 {
+            self.d = out;
+        }
+    }
+}
+
+/// Computes the ALU output for the six control bits zx nx zy ny f no, the
+/// highest bit first.
+pub fn alu(x: i16, y: i16, control: u8) -> i16 {
+    let bit = |n: u8| control & (1 << (5 - n)) != 0;
+    let x = if bit(0) { 0 } else { x };
+    let x = if bit(1) { !x } else { x };
+    let y = if bit(2) { 0 } else { y };
+    let y = if bit(3) { !y } else { y };
+    let out = if bit(4) { x.wrapping_add(y) } else { x & y };
+    if bit(5) { !out } else { out }
+}
+
+/// Reads a program from a .hack file, or assembles a .asm file.
+pub fn read_program(path: &Path) -> Result<Vec<u16>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|error| format!("cannot read {}: {}", path.display(), error))?;
+    let program = match path.extension() {
+        Some(extension) if extension == "asm" => hack_assembler::assemble(&text).instructions,
+        Some(extension) if extension == "hack" => parse_hack(&text).map_err(|message| {
+            format!(
+                "{}:{}",
+                path.file_name().unwrap_or_default().to_string_lossy(),
+                message
+            )
+        })?,
+        _ => return Err(format!("{} is not a .hack or .asm file", path.display())),
+    };
+    if program.len() > ROM_SIZE {
+        return Err(format!(
+            "{} instructions do not fit in the ROM",
+            program.len()
+        ));
+    }
+    Ok(program)
+}
+
+/// Parses machine code: one 16-bit binary word per line.
+pub fn parse_hack(text: &str) -> Result<Vec<u16>, String> {
+    text.lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(number, line)| {
+            if line.len() != 16 {
+                return Err(format!("{}: expected 16 binary digits", number));
+            }
+            u16::from_str_radix(line, 2)
+                .map_err(|_| format!("{}: expected 16 binary digits", number))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(source: &str, cycles: usize) -> Cpu {
+        let mut cpu = Cpu::new(&hack_assembler::assemble(source).instructions);
+        for _ in 0..cycles {
+            cpu.step();
+        }
+        cpu
+    }
+
+    #[test]
+    fn test_step() {
+        // RAM[0] = 2 + 3
+        let cpu = run("@2\nD=A\n@3\nD=D+A\n@0\nM=D", 6);
+        assert_eq!(cpu.ram[0], 5);
+        assert_eq!(cpu.pc, 6);
+
+        // AM=M+1 increments M at the old address, then A
+        let mut cpu = Cpu::new(&hack_assembler::assemble("@0\nAM=M+1").instructions);
+        cpu.ram[0] = 41;
+        cpu.step();
+        cpu.step();
+        assert_eq!((cpu.ram[0], cpu.a), (42, 42));
+
+        // A=D;JMP jumps to the old A
+        let cpu = run("@7\nD=A\n@5\nA=D;JMP", 4);
+        assert_eq!((cpu.pc, cpu.a), (5, 7));
+
+        let cpu = run("@3\nD=-A\n@END\nD;JLT\nD=0\n(END)\nD=D-1", 5);
+        assert_eq!(cpu.d, -4);
+    }
+
+    #[test]
+    fn test_run() {
+        let source = "@3\nD=A\n(LOOP)\nD=D-1\n@LOOP\nD;JGT\n(END)\n@END\n0;JMP";
+        let mut cpu = Cpu::new(&hack_assembler::assemble(source).instructions);
+        assert_eq!(cpu.run(5), Outcome::CycleLimit);
+        assert_eq!(cpu.run(100), Outcome::Halted);
+        assert_eq!((cpu.d, cpu.pc, cpu.cycles), (0, 5, 13));
+
+        let mut cpu = Cpu::new(&hack_assembler::assemble("@1\nD=A\n@KBD\nM=D").instructions);
+        assert_eq!(cpu.run(100), Outcome::End);
+        assert_eq!(cpu.read(KBD as u16), 1);
+        // Only the low 15 bits of an address reach the memory
+        cpu.write(0x8000 + 3, 7);
+        assert_eq!(cpu.ram[3], 7);
+
+        // `D;JMP` on the `@` of its loop keeps going while D changes
+        let mut cpu = Cpu::new(&hack_assembler::assemble("(L)\n@L\nD=D+1;JMP").instructions);
+        assert_eq!(cpu.run(10), Outcome::CycleLimit);
+    }
+
+    #[test]
+    fn test_alu() {
+        let comps = [
+            (0b101010, 0),
+            (0b111111, 1),
+            (0b111010, -1),
+            (0b001100, 7),
+            (0b110000, 3),
+            (0b001101, !7),
+            (0b001111, -7),
+            (0b011111, 8),
+            (0b110010, 2),
+            (0b000010, 10),
+            (0b010011, 4),
+            (0b000111, -4),
+            (0b000000, 3),
+            (0b010101, 7),
+        ];
+        for (control, expected) in comps {
+            assert_eq!(alu(7, 3, control), expected, "{:06b}", control);
+        }
+        assert_eq!(alu(i16::MAX, 1, 0b000010), i16::MIN);
+        assert_eq!(
+            parse_hack("0000000000000101\n\n1110110000010000\n"),
+            Ok(vec![5, 0xec10])
+        );
+        assert!(parse_hack("0101").is_err());
+    }
+}
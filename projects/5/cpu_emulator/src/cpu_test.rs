@@ -0,0 +1,125 @@
+//! Runs the CPU emulator test scripts of projects 4, 7 and 8, such as
+//! `Mult.tst` and `BasicLoop.tst`.
+//!
+//! Besides the commands shared with the other emulators (see `script`),
+//! scripts can `load` a .hack or .asm file, assembled in-process, and
+//! run it with `ticktock`. Variables are `RAM[i]`, `ROM[i]`, `PC`, `A`
+//! and `D`.
+
+use std::path::Path;
+
+use crate::cpu::{self, Cpu, RAM_SIZE, ROM_SIZE};
+use crate::script::{self, Target};
+
+/// Runs a test script, writing its output table to its `output-file`.
+pub fn run(path: &Path) -> Result<(), String> {
+    let statements = script::read(path)?;
+    script::run(path, &statements, &mut Emulator { cpu: None })
+}
+
+struct Emulator {
+    /// The CPU, once a program is loaded
+    cpu: Option<Cpu>,
+}
+
+impl Emulator {
+    fn cpu(&self) -> Result<&Cpu, String> {
+        self.cpu
+            .as_ref()
+            .ok_or_else(|| "no program is loaded".to_string())
+    }
+
+    fn cpu_mut(&mut self) -> Result<&mut Cpu, String> {
+        self.cpu
+            .as_mut()
+            .ok_or_else(|| "no program is loaded".to_string())
+    }
+}
+
+impl Target for Emulator {
+    fn command(&mut self, words: &[String], directory: &Path) -> Result<bool, String> {
+        match words[0].as_str() {
+            "load" => {
+                let file = words
+                    .get(1)
+                    .ok_or_else(|| "load expects a .hack or .asm file".to_string())?;
+                let program = cpu::read_program(&directory.join(file))?;
+                self.cpu = Some(Cpu::new(&program));
+            }
+            "ticktock" => self.cpu_mut()?.step(),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn get(&self, variable: &str) -> Result<i16, String> {
+        let cpu = self.cpu()?;
+        match (variable, script::indexed(variable)) {
+            ("PC", _) => Ok(cpu.pc as i16),
+            ("A", _) => Ok(cpu.a),
+            ("D", _) => Ok(cpu.d),
+            (_, Some(("RAM", index))) if index < RAM_SIZE => Ok(cpu.ram[index]),
+            (_, Some(("ROM", index))) if index < ROM_SIZE => Ok(cpu.rom[index] as i16),
+            _ => Err(format!("unknown variable: {}", variable)),
+        }
+    }
+
+    fn set(&mut self, variable: &str, value: i16) -> Result<(), String> {
+        let cpu = self.cpu_mut()?;
+        match (variable, script::indexed(variable)) {
+            ("PC", _) => cpu.pc = value as u16,
+            ("A", _) => cpu.a = value,
+            ("D", _) => cpu.d = value,
+            (_, Some(("RAM", index))) if index < RAM_SIZE => cpu.ram[index] = value,
+            (_, Some(("ROM", index))) if index < ROM_SIZE => cpu.rom[index] = value as u16,
+            _ => return Err(format!("unknown variable: {}", variable)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_run() {
+        let directory = std::env::temp_dir().join(format!("cpu_test_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(
+            directory.join("Double.asm"),
+            "@0\nD=M\nM=D+M\n(END)\n@END\n0;JMP",
+        )
+        .unwrap();
+        fs::write(
+            directory.join("Double.cmp"),
+            "|  RAM[0]  |  PC   |\n|      42  |     3 |\n",
+        )
+        .unwrap();
+        let script = "load Double.asm, output-file Double.out, compare-to Double.cmp,
+                      output-list RAM[0]%D2.6.2 PC%D1.5.1;
+                      set RAM[0] 21;";
+        fs::write(
+            directory.join("Double.tst"),
+            format!("{} repeat 5 {{ ticktock; }} output;", script),
+        )
+        .unwrap();
+        assert_eq!(run(&directory.join("Double.tst")), Ok(()));
+        assert_eq!(
+            fs::read_to_string(directory.join("Double.out")).unwrap(),
+            fs::read_to_string(directory.join("Double.cmp")).unwrap()
+        );
+
+        fs::write(
+            directory.join("Double.tst"),
+            format!("{} ticktock; output;", script),
+        )
+        .unwrap();
+        assert_eq!(
+            run(&directory.join("Double.tst")),
+            Err("Double.tst:3: comparison failure at line 2, column 1 (RAM[0]): expected \"42\", found \"21\"".to_string())
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,254 @@
+//! Keyboard input scripts, which press keys at fixed cycles (or VM steps)
+//! so that runs of interactive programs are reproducible.
+//!
+//! Each line of a script is `<cycle> <key> <duration>`: the key is held
+//! in the keyboard register from `cycle` for `duration` cycles. A key is
+//! a Hack key code, a single character, or the name of a special key
+//! such as `left` or `f1`; `space` and `hash` stand for ` ` and `#`, and
+//! digits are key codes, so the key `0` is written `48`.
+//! Text after `#` is a comment. Overlapping presses are resolved in
+//! favour of the latest one, and the register reads 0 between presses.
+
+use std::fs;
+use std::path::Path;
+
+use crate::terminal::{
+    BACKSPACE, DELETE, DOWN, END, ESCAPE, F1, HOME, INSERT, LEFT, NEWLINE, PAGE_DOWN, PAGE_UP,
+    RIGHT, UP,
+};
+
+/// A key held for a number of cycles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Press {
+    pub at: u64,
+    pub key: i16,
+    pub duration: u64,
+}
+
+/// Names of the keys that are not printable characters, or that cannot
+/// be written as one in a script
+const NAMES: [(&str, i16); 15] = [
+    ("space", b' ' as i16),
+    ("hash", b'#' as i16),
+    ("newline", NEWLINE),
+    ("backspace", BACKSPACE),
+    ("left", LEFT),
+    ("up", UP),
+    ("right", RIGHT),
+    ("down", DOWN),
+    ("home", HOME),
+    ("end", END),
+    ("pageup", PAGE_UP),
+    ("pagedown", PAGE_DOWN),
+    ("insert", INSERT),
+    ("delete", DELETE),
+    ("escape", ESCAPE),
+];
+
+/// Parses a key: a name, a single character or a key code.
+fn parse_key(word: &str) -> Option<i16> {
+    let lower = word.to_ascii_lowercase();
+    if let Some(&(_, key)) = NAMES.iter().find(|(name, _)| *name == lower) {
+        return Some(key);
+    }
+    if let Some(number) = lower.strip_prefix('f').and_then(|n| n.parse::<i16>().ok())
+        && (1..=12).contains(&number)
+    {
+        return Some(F1 + number - 1);
+    }
+    let mut chars = word.chars();
+    match (chars.next(), chars.next()) {
+        (Some(char), None) if char.is_ascii_graphic() && !char.is_ascii_digit() => {
+            Some(char as i16)
+        }
+        _ => word.parse().ok().filter(|&key| key > 0),
+    }
+}
+
+/// Returns the name of a key as written in scripts.
+pub fn key_name(key: i16) -> String {
+    if let Some(&(name, _)) = NAMES.iter().find(|&&(_, code)| code == key) {
+        return name.to_string();
+    }
+    match key {
+        F1..=152 => format!("f{}", key - F1 + 1),
+        // Digits would read as key codes
+        0x21..=0x7e if !(key as u8).is_ascii_digit() => (key as u8 as char).to_string(),
+        _ => key.to_string(),
+    }
+}
+
+/// Parses the text of a script, sorted by cycle.
+pub fn parse(text: &str) -> Result<Vec<Press>, String> {
+    let mut presses = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split_once('#').map_or(line, |(line, _)| line);
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        let press = match words[..] {
+            [at, key, duration] => (|| {
+                Some(Press {
+                    at: at.parse().ok()?,
+                    key: parse_key(key)?,
+                    duration: duration.parse().ok()?,
+                })
+            })(),
+            _ => None,
+        };
+        presses.push(press.ok_or_else(|| {
+            format!(
+                "{}: expected <cycle> <key> <duration>, found \"{}\"",
+                index + 1,
+                line.trim()
+            )
+        })?);
+    }
+    presses.sort_by_key(|press| press.at);
+    Ok(presses)
+}
+
+/// Reads a script file.
+pub fn read(path: &Path) -> Result<Vec<Press>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|error| format!("cannot read {}: {}", path.display(), error))?;
+    parse(&text).map_err(|message| {
+        format!(
+            "{}:{}",
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            message
+        )
+    })
+}
+
+/// Formats presses as a script.
+pub fn format(presses: &[Press]) -> String {
+    presses
+        .iter()
+        .map(|press| format!("{} {} {}\n", press.at, key_name(press.key), press.duration))
+        .collect()
+}
+
+/// Plays a script: the key held at each cycle of a run.
+pub struct Player {
+    presses: Vec<Press>,
+    /// Presses that started before the last cycle asked for
+    started: usize,
+    /// Those of them still held, the latest last
+    held: Vec<Press>,
+}
+
+impl Player {
+    pub fn new(presses: Vec<Press>) -> Self {
+        Self {
+            presses,
+            started: 0,
+            held: Vec::new(),
+        }
+    }
+
+    /// Returns the key held at `cycle`, or 0. Cycles must not decrease
+    /// from one call to the next.
+    pub fn key(&mut self, cycle: u64) -> i16 {
+        while let Some(&press) = self.presses.get(self.started)
+            && press.at <= cycle
+        {
+            self.held.push(press);
+            self.started += 1;
+        }
+        self.held.retain(|press| cycle < press.at + press.duration);
+        self.held.last().map_or(0, |press| press.key)
+    }
+
+    /// Returns the first cycle after `cycle` where a press starts or ends,
+    /// to run up to it in one go.
+    pub fn next(&self, cycle: u64) -> Option<u64> {
+        self.presses
+            .iter()
+            .flat_map(|press| [press.at, press.at + press.duration])
+            .filter(|&change| change > cycle)
+            .min()
+    }
+}
+
+/// Records the keys of an interactive session as presses.
+#[derive(Default)]
+pub struct Recorder {
+    presses: Vec<Press>,
+    /// Key held and the cycle it was pressed at
+    held: Option<(i16, u64)>,
+}
+
+impl Recorder {
+    /// Notes the key held at `cycle`, 0 for none.
+    pub fn key(&mut self, cycle: u64, key: i16) {
+        if self.held.is_some_and(|(held, _)| held == key) {
+            return;
+        }
+        self.release(cycle);
+        if key != 0 {
+            self.held = Some((key, cycle));
+        }
+    }
+
+    /// Ends the recording at `cycle` and returns its presses.
+    pub fn finish(mut self, cycle: u64) -> Vec<Press> {
+        self.release(cycle);
+        self.presses
+    }
+
+    fn release(&mut self, cycle: u64) {
+        if let Some((key, at)) = self.held.take() {
+            self.presses.push(Press {
+                at,
+                key,
+                duration: cycle - at,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let presses =
+            parse("# Pong\n2000 left 500\n100 F1 10 # help\n3000 space 1\n4000 65 2\n").unwrap();
+        assert_eq!(
+            presses.iter().map(|press| press.key).collect::<Vec<_>>(),
+            [F1, LEFT, 32, 65]
+        );
+        assert_eq!(presses[1].at, 2000);
+        assert_eq!(parse("7 q 1\n8 7 1").unwrap()[1].key, 7);
+        assert_eq!(
+            parse("10 left\n"),
+            Err("1: expected <cycle> <key> <duration>, found \"10 left\"".to_string())
+        );
+        assert!(parse("10 f13 1").is_err());
+        let text = "1 left 2\n3 q 4\n5 55 6\n7 newline 8\n9 hash 10\n";
+        assert_eq!(format(&parse(text).unwrap()), text);
+    }
+
+    #[test]
+    fn test_player() {
+        let mut recorder = Recorder::default();
+        for (cycle, key) in [(0, 0), (10, LEFT), (20, LEFT), (30, 0), (40, 81), (45, 82)] {
+            recorder.key(cycle, key);
+        }
+        let presses = recorder.finish(50);
+        assert_eq!(format(&presses), "10 left 20\n40 Q 5\n45 R 5\n");
+
+        let mut player = Player::new(presses);
+        assert_eq!(player.next(0), Some(10));
+        assert_eq!(player.next(30), Some(40));
+        assert_eq!(player.next(50), None);
+        let keys: Vec<i16> = [0, 10, 29, 30, 44, 45, 49, 50]
+            .iter()
+            .map(|&cycle| player.key(cycle))
+            .collect();
+        assert_eq!(keys, [0, LEFT, LEFT, 0, 81, 82, 82, 0]);
+    }
+}
@@ -0,0 +1,16 @@
+//! CPU emulator library: executes Hack machine code, as a reference for
+//! the assembler and the code generated by the VM translator.
+//!
+//! A `Cpu` runs a program from ROM on 32K words of RAM, with the screen
+//! memory map at RAM[16384] and the keyboard at RAM[24576]. `script` runs
+//! the course's test scripts, shared with the VM emulator, and `cpu_test`
+//! drives the CPU from them. `screen` saves images of the screen and
+//! `terminal` runs programs interactively, for both emulators, while
+//! `keys` replays or records their key presses.
+
+pub mod cpu;
+pub mod cpu_test;
+pub mod keys;
+pub mod screen;
+pub mod script;
+pub mod terminal;
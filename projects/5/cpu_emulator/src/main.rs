@@ -0,0 +1,242 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+use cpu_emulator::cpu::{self, Cpu, Outcome};
+use cpu_emulator::cpu_test;
+use cpu_emulator::keys::{self, Player, Press};
+use cpu_emulator::screen::{self, Schedule};
+use cpu_emulator::terminal::{self, Render, Terminal};
+
+const DEFAULT_MAX_CYCLES: u64 = 100_000_000;
+/// Speed of interactive runs, in cycles per second
+const DEFAULT_SPEED: u64 = 2_000_000;
+
+struct Options {
+    max_cycles: u64,
+    /// Initial RAM values, e.g. the inputs of projects/4 Mult
+    set: Vec<(usize, i16)>,
+    dump_ram: Vec<(usize, usize)>,
+    /// Image of the screen written at the end of the run
+    screenshot: Option<PathBuf>,
+    /// Further images during the run, named after their cycle
+    screenshots: Schedule,
+    /// Run interactively in the terminal
+    tty: bool,
+    render: Render,
+    /// Cycles per second of interactive runs, `None` for full speed
+    speed: Option<u64>,
+    key_hold: Duration,
+    /// Keyboard input script driving the run
+    keys: Option<PathBuf>,
+    /// Where to write the keys pressed during a --tty run, as a script
+    record_keys: Option<PathBuf>,
+    input: String,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Option<Self> {
+        let mut options = Options {
+            max_cycles: DEFAULT_MAX_CYCLES,
+            set: Vec::new(),
+            dump_ram: Vec::new(),
+            screenshot: None,
+            screenshots: Schedule::default(),
+            tty: false,
+            render: Render::Braille,
+            speed: Some(DEFAULT_SPEED),
+            key_hold: Duration::from_millis(300),
+            keys: None,
+            record_keys: None,
+            input: String::new(),
+        };
+        let mut input = None;
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--max-cycles" => options.max_cycles = args.next()?.parse().ok()?,
+                "--set" => {
+                    let (address, value) = args.next()?.split_once('=')?;
+                    options
+                        .set
+                        .push((address.parse().ok()?, value.parse().ok()?));
+                }
+                "--dump-ram" => {
+                    let range = args.next()?;
+                    let (first, last) = range.split_once('-').unwrap_or((range, range));
+                    options
+                        .dump_ram
+                        .push((first.parse().ok()?, last.parse().ok()?));
+                }
+                "--screenshot" => options.screenshot = Some(PathBuf::from(args.next()?)),
+                "--screenshot-at" => options.screenshots.at.push(args.next()?.parse().ok()?),
+                "--screenshot-every" => match args.next()?.parse().ok()? {
+                    0 => return None,
+                    every => options.screenshots.every = Some(every),
+                },
+                "--tty" => options.tty = true,
+                "--render" => {
+                    options.render = match args.next()?.as_str() {
+                        "braille" => Render::Braille,
+                        "blocks" => Render::Blocks,
+                        _ => return None,
+                    }
+                }
+                "--speed" => {
+                    options.speed = match args.next()?.as_str() {
+                        "max" => None,
+                        speed => Some(speed.parse().ok().filter(|&speed| speed > 0)?),
+                    }
+                }
+                "--key-hold" => {
+                    options.key_hold = Duration::from_millis(args.next()?.parse().ok()?)
+                }
+                "--keys" => options.keys = Some(PathBuf::from(args.next()?)),
+                "--record-keys" => options.record_keys = Some(PathBuf::from(args.next()?)),
+                _ if arg.starts_with("--") => return None,
+                _ if input.is_none() => input = Some(arg.clone()),
+                _ => return None,
+            }
+        }
+        options.input = input?;
+        // Captures during the run are named after the end-of-run one
+        if options.screenshot.is_none() && options.screenshots.next(0).is_some() {
+            return None;
+        }
+        // Only keys typed in the terminal can be recorded
+        if options.record_keys.is_some() && (!options.tty || options.keys.is_some()) {
+            return None;
+        }
+        Some(options)
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let Some(options) = Options::parse(&args) else {
+        eprintln!(
+            "Usage: {} [--max-cycles <n>] [--set <address>=<value>] [--dump-ram <first>-<last>] [--screenshot <file.png|file.pbm> [--screenshot-at <cycle>] [--screenshot-every <n>]] [--tty [--render braille|blocks] [--speed <cycles per second>|max] [--key-hold <ms>] [--record-keys <file>]] [--keys <file>] <file.hack, file.asm or script.tst>",
+            args[0]
+        );
+        process::exit(1);
+    };
+
+    let input = Path::new(&options.input);
+    if input
+        .extension()
+        .is_some_and(|extension| extension == "tst")
+    {
+        match cpu_test::run(input) {
+            Ok(()) => println!("End of script - Comparison ended successfully"),
+            Err(error) => {
+                eprintln!("Error: {}", error);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let program = cpu::read_program(input).unwrap_or_else(|error| {
+        eprintln!("Error: {}", error);
+        process::exit(1);
+    });
+    let mut cpu = Cpu::new(&program);
+    for &(address, value) in &options.set {
+        if address >= cpu::RAM_SIZE {
+            eprintln!("Error: RAM[{}] is out of range", address);
+            process::exit(1);
+        }
+        cpu.ram[address] = value;
+    }
+
+    let mut player = options.keys.as_deref().map(|path| {
+        Player::new(keys::read(path).unwrap_or_else(|error| {
+            eprintln!("Error: {}", error);
+            process::exit(1);
+        }))
+    });
+    let mut terminal = options.tty.then(|| {
+        Terminal::open(terminal::Options {
+            render: options.render,
+            speed: options.speed,
+            key_hold: options.key_hold,
+            unit: "cycle",
+            keyboard: player.is_none(),
+        })
+        .unwrap_or_else(|error| {
+            eprintln!("Error: {}", error);
+            process::exit(1);
+        })
+    });
+    let outcome = loop {
+        let mut limit = options.screenshots.next(cpu.cycles).unwrap_or(u64::MAX);
+        if let Some(player) = &mut player {
+            cpu.set_key(player.key(cpu.cycles));
+            // Stop where the key changes
+            limit = limit.min(player.next(cpu.cycles).unwrap_or(u64::MAX));
+        }
+        if terminal.is_some() {
+            // Short runs, so that the terminal is updated often
+            limit = limit.min(cpu.cycles + 1024);
+        }
+        let outcome = cpu.run(limit.min(options.max_cycles));
+        if outcome != Outcome::CycleLimit || cpu.cycles >= options.max_cycles {
+            break Some(outcome);
+        }
+        if options.screenshots.is_due(cpu.cycles) {
+            let path = options.screenshot.as_deref().unwrap();
+            save_screenshot(&screen::path_at(path, cpu.cycles), &cpu);
+        }
+        if let Some(terminal) = &mut terminal
+            && !terminal.update(cpu.cycles, &mut cpu.ram)
+        {
+            break None;
+        }
+    };
+    let status = match outcome {
+        Some(Outcome::Halted) => format!("Halted after {} cycles", cpu.cycles),
+        Some(Outcome::CycleLimit) => format!("Stopped at the cycle limit of {}", cpu.cycles),
+        Some(Outcome::End) => format!("Ran past the last instruction after {} cycles", cpu.cycles),
+        None => format!("Quit after {} cycles", cpu.cycles),
+    };
+    if let Some(mut terminal) = terminal {
+        let presses = terminal.recording(cpu.cycles);
+        if outcome.is_some() {
+            terminal.finish(cpu.screen(), &status);
+        }
+        terminal.restore();
+        if let Some(path) = &options.record_keys {
+            save_keys(path, &presses);
+        }
+    }
+    println!("{}", status);
+    if let Some(path) = &options.screenshot {
+        save_screenshot(path, &cpu);
+    }
+    println!("PC={} A={} D={}", cpu.pc, cpu.a, cpu.d);
+    // `address value` lines, as read by vm-profile
+    for &(first, last) in &options.dump_ram {
+        for address in first..=last.min(cpu::RAM_SIZE - 1) {
+            println!("{} {}", address, cpu.ram[address]);
+        }
+    }
+}
+
+/// Writes an image of the screen, exiting on errors.
+fn save_screenshot(path: &Path, cpu: &Cpu) {
+    screen::write(path, cpu.screen()).unwrap_or_else(|error| {
+        eprintln!("Error: {}", error);
+        process::exit(1);
+    });
+}
+
+/// Writes the keys pressed in the terminal as a keyboard input script,
+/// exiting on errors.
+fn save_keys(path: &Path, presses: &[Press]) {
+    fs::write(path, keys::format(presses)).unwrap_or_else(|error| {
+        eprintln!("Error: cannot write {}: {}", path.display(), error);
+        process::exit(1);
+    });
+}
@@ -0,0 +1,189 @@
+//! Images of the 512x256 screen memory map, as PBM or PNG files.
+//!
+//! Both formats are written without compression so that no image library
+//! is needed: a PBM is the raw bitmap behind a short header, and a PNG
+//! stores its bitmap in uncompressed deflate blocks.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const WIDTH: usize = 512;
+pub const HEIGHT: usize = 256;
+/// Bytes of one row of pixels, 8 pixels per byte
+const ROW_BYTES: usize = WIDTH / 8;
+
+/// Returns the rows of the screen as bytes holding 8 pixels each, the
+/// leftmost in the highest bit, 1 for black. In the memory map, the
+/// leftmost pixel of a word is its lowest bit.
+fn bitmap(screen: &[i16]) -> Vec<u8> {
+    screen
+        .iter()
+        .flat_map(|&word| (word as u16).to_le_bytes())
+        .map(u8::reverse_bits)
+        .collect()
+}
+
+/// Encodes the screen as a binary PBM (P4) image.
+pub fn pbm(screen: &[i16]) -> Vec<u8> {
+    let mut image = format!("P4\n{} {}\n", WIDTH, HEIGHT).into_bytes();
+    image.extend(bitmap(screen));
+    image
+}
+
+/// Encodes the screen as a 1-bit grayscale PNG image.
+pub fn png(screen: &[i16]) -> Vec<u8> {
+    // Each row starts with filter type 0, and PNG uses 1 for white
+    let mut raw = Vec::with_capacity(HEIGHT * (ROW_BYTES + 1));
+    for row in bitmap(screen).chunks(ROW_BYTES) {
+        raw.push(0);
+        raw.extend(row.iter().map(|byte| !byte));
+    }
+
+    let mut header = Vec::new();
+    header.extend((WIDTH as u32).to_be_bytes());
+    header.extend((HEIGHT as u32).to_be_bytes());
+    // Bit depth 1, grayscale, deflate, no filtering, no interlace
+    header.extend([1, 0, 0, 0, 0]);
+
+    let mut image = b"\x89PNG\r\n\x1a\n".to_vec();
+    chunk(&mut image, b"IHDR", &header);
+    chunk(&mut image, b"IDAT", &zlib_stored(&raw));
+    chunk(&mut image, b"IEND", &[]);
+    image
+}
+
+fn chunk(image: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    image.extend((data.len() as u32).to_be_bytes());
+    let start = image.len();
+    image.extend(kind);
+    image.extend(data);
+    let crc = crc32(&image[start..]);
+    image.extend(crc.to_be_bytes());
+}
+
+/// Wraps data in a zlib stream of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = data.chunks(u16::MAX as usize).collect();
+    for (index, block) in blocks.iter().enumerate() {
+        stream.push((index + 1 == blocks.len()) as u8);
+        let len = block.len() as u16;
+        stream.extend(len.to_le_bytes());
+        stream.extend((!len).to_le_bytes());
+        stream.extend(*block);
+    }
+    stream.extend(adler32(data).to_be_bytes());
+    stream
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+/// Writes the screen to a .png or .pbm file.
+pub fn write(path: &Path, screen: &[i16]) -> Result<(), String> {
+    let image = match path.extension() {
+        Some(extension) if extension == "png" => png(screen),
+        Some(extension) if extension == "pbm" => pbm(screen),
+        _ => return Err(format!("{} is not a .png or .pbm file", path.display())),
+    };
+    fs::write(path, image).map_err(|error| format!("cannot write {}: {}", path.display(), error))
+}
+
+/// When to capture the screen during a run, besides at its end.
+#[derive(Clone, Default)]
+pub struct Schedule {
+    /// Cycles (or VM steps) to capture at
+    pub at: Vec<u64>,
+    /// Capture every this many cycles
+    pub every: Option<u64>,
+}
+
+impl Schedule {
+    pub fn is_due(&self, cycle: u64) -> bool {
+        cycle > 0
+            && (self.at.contains(&cycle)
+                || self.every.is_some_and(|every| cycle.is_multiple_of(every)))
+    }
+
+    /// Returns the first cycle after `cycle` to capture at.
+    pub fn next(&self, cycle: u64) -> Option<u64> {
+        let at = self.at.iter().copied().filter(|&at| at > cycle).min();
+        let every = self
+            .every
+            .map(|every| (cycle / every + 1).saturating_mul(every));
+        at.into_iter().chain(every).min()
+    }
+}
+
+/// Returns the file of a capture made during a run: the end-of-run file
+/// `path` with the cycle appended to its name, e.g. `screen-1000.png`.
+pub fn path_at(path: &Path, cycle: u64) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut name = format!("{}-{}", stem, cycle);
+    if let Some(extension) = path.extension() {
+        name = format!("{}.{}", name, extension.to_string_lossy());
+    }
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bitmap() {
+        let mut screen = vec![0; 8192];
+        // Leftmost pixel of the first row, and the last pixel of the screen
+        screen[0] = 1;
+        screen[8191] = i16::MIN;
+        let image = pbm(&screen);
+        let pixels = &image[b"P4\n512 256\n".len()..];
+        assert_eq!(pixels.len(), 8192 * 2);
+        assert_eq!((pixels[0], pixels[1], pixels[16383]), (0x80, 0, 0x01));
+
+        let image = png(&screen);
+        assert_eq!(&image[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&image[12..16], b"IHDR");
+        assert!(image.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]));
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn test_schedule() {
+        let schedule = Schedule {
+            at: vec![150, 30],
+            every: Some(100),
+        };
+        assert!(schedule.is_due(30) && schedule.is_due(200) && !schedule.is_due(0));
+        assert_eq!(schedule.next(0), Some(30));
+        assert_eq!(schedule.next(30), Some(100));
+        assert_eq!(schedule.next(100), Some(150));
+        assert_eq!(Schedule::default().next(0), None);
+        assert_eq!(
+            path_at(Path::new("out/screen.png"), 1000),
+            Path::new("out/screen-1000.png")
+        );
+    }
+}
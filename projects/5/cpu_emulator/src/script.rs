@@ -0,0 +1,579 @@
+//! Test scripts (`.tst`) of the nand2tetris tools and their output tables.
+//!
+//! A script is a sequence of commands, each a list of words ended by `,`,
+//! `;` or `!`, with `repeat n { ... }` blocks. `run` executes the commands
+//! shared by all emulators, such as the `output-list` table whose rows are
+//! compared with a `.cmp` file, and leaves the others to a `Target`.
+
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A statement of a test script.
+#[derive(Debug, PartialEq)]
+pub enum Statement {
+    Command {
+        words: Vec<String>,
+        line: usize,
+    },
+    /// `repeat n { ... }`, or `repeat { ... }` to repeat forever
+    Repeat {
+        count: Option<usize>,
+        body: Vec<Statement>,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    /// `,`, `;` or `!`
+    End,
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => {}
+            ',' | ';' | '!' => tokens.push((Token::End, line)),
+            '{' => tokens.push((Token::Open, line)),
+            '}' => tokens.push((Token::Close, line)),
+            '/' if chars.peek() == Some(&'/') => while chars.next_if(|&c| c != '\n').is_some() {},
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if previous == '*' => break,
+                        Some(c) => {
+                            line += (c == '\n') as usize;
+                            previous = c;
+                        }
+                        None => return Err(format!("line {}: unterminated comment", line)),
+                    }
+                }
+            }
+            '"' => {
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\n') | None => {
+                            return Err(format!("line {}: unterminated string", line));
+                        }
+                        Some(c) => word.push(c),
+                    }
+                }
+                tokens.push((Token::Word(word), line));
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|&c| !c.is_whitespace() && !",;!{}".contains(c)) {
+                    word.push(c);
+                }
+                tokens.push((Token::Word(word), line));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parses a test script.
+pub fn parse(text: &str) -> Result<Vec<Statement>, String> {
+    let tokens = tokenize(text)?;
+    let mut position = 0;
+    let statements = parse_block(&tokens, &mut position)?;
+    match tokens.get(position) {
+        Some((_, line)) => Err(format!("line {}: unexpected }}", line)),
+        None => Ok(statements),
+    }
+}
+
+/// Parses statements up to a closing brace or the end of the script.
+fn parse_block(tokens: &[(Token, usize)], position: &mut usize) -> Result<Vec<Statement>, String> {
+    let mut statements = Vec::new();
+    let mut words = Vec::new();
+    let mut start = 0;
+    while let Some((token, line)) = tokens.get(*position) {
+        *position += 1;
+        match token {
+            Token::Word(word) if words.is_empty() && word == "repeat" => {
+                let count = match tokens.get(*position) {
+                    Some((Token::Word(count), _)) => {
+                        *position += 1;
+                        let count = count.parse().map_err(|_| {
+                            format!("line {}: invalid repeat count: {}", line, count)
+                        })?;
+                        Some(count)
+                    }
+                    _ => None,
+                };
+                if tokens.get(*position).map(|(token, _)| token) != Some(&Token::Open) {
+                    return Err(format!("line {}: expected {{ after repeat", line));
+                }
+                *position += 1;
+                let body = parse_block(tokens, position)?;
+                if tokens.get(*position).map(|(token, _)| token) != Some(&Token::Close) {
+                    return Err(format!("line {}: missing }} after repeat", line));
+                }
+                *position += 1;
+                statements.push(Statement::Repeat { count, body });
+            }
+            Token::Word(word) => {
+                if words.is_empty() {
+                    start = *line;
+                }
+                words.push(word.clone());
+            }
+            Token::End => {
+                if !words.is_empty() {
+                    statements.push(Statement::Command {
+                        words: std::mem::take(&mut words),
+                        line: start,
+                    });
+                }
+            }
+            Token::Open => return Err(format!("line {}: unexpected {{", line)),
+            Token::Close => {
+                *position -= 1;
+                break;
+            }
+        }
+    }
+    if !words.is_empty() {
+        return Err(format!("line {}: missing , or ; after {}", start, words[0]));
+    }
+    Ok(statements)
+}
+
+/// Parses a value written in a script: decimal, or `%B`, `%X` or `%D`
+/// followed by a binary, hexadecimal or decimal number.
+pub fn parse_value(text: &str) -> Result<i16, String> {
+    let invalid = || format!("invalid value: {}", text);
+    let (radix, digits) = match text.get(..2) {
+        Some("%B") => (2, &text[2..]),
+        Some("%X") => (16, &text[2..]),
+        Some("%D") => (10, &text[2..]),
+        _ => (10, text),
+    };
+    let value = i32::from_str_radix(digits, radix).map_err(|_| invalid())?;
+    match radix {
+        10 if (-32768..=65535).contains(&value) => Ok(value as i16),
+        2 | 16 if (0..=0xffff).contains(&value) => Ok(value as u16 as i16),
+        _ => Err(invalid()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Binary,
+    Decimal,
+    Hex,
+    String,
+}
+
+/// A column of the output table: `name%Fl.w.r`, with `l` and `r` spaces
+/// around a value `w` characters wide.
+#[derive(Debug, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub format: Format,
+    pub left: usize,
+    pub width: usize,
+    pub right: usize,
+}
+
+impl Column {
+    /// Parses a column specification. Without a format, values are shown
+    /// in decimal in 6 characters with a space on each side.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid output column: {}", spec);
+        let Some((name, format)) = spec.split_once('%') else {
+            return Ok(Self {
+                name: spec.to_string(),
+                format: Format::Decimal,
+                left: 1,
+                width: 6,
+                right: 1,
+            });
+        };
+        let mut chars = format.chars();
+        let format = match chars.next() {
+            Some('B') => Format::Binary,
+            Some('D') => Format::Decimal,
+            Some('X') => Format::Hex,
+            Some('S') => Format::String,
+            _ => return Err(invalid()),
+        };
+        let sizes: Vec<usize> = chars
+            .as_str()
+            .split('.')
+            .map(|size| size.parse().map_err(|_| invalid()))
+            .collect::<Result<_, _>>()?;
+        let [left, width, right] = sizes[..] else {
+            return Err(invalid());
+        };
+        Ok(Self {
+            name: name.to_string(),
+            format,
+            left,
+            width,
+            right,
+        })
+    }
+
+    fn total_width(&self) -> usize {
+        self.left + self.width + self.right
+    }
+
+    /// Returns the column's name centered in its width, or truncated if
+    /// it does not fit.
+    pub fn header(&self) -> String {
+        let width = self.total_width();
+        let name: String = self.name.chars().take(width).collect();
+        let left = (width - name.chars().count()) / 2;
+        format!("{:left$}{:<rest$}", "", name, rest = width - left)
+    }
+
+    /// Formats a numeric value: right aligned in decimal, or the low `w`
+    /// digits in binary and hexadecimal.
+    pub fn format(&self, value: i16) -> String {
+        let bits = value as u16;
+        let text = match self.format {
+            Format::Decimal => format!("{:>1$}", value, self.width),
+            Format::Binary => format!("{:016b}", bits),
+            Format::Hex => format!("{:04X}", bits),
+            Format::String => return self.format_text(&value.to_string()),
+        };
+        let digits = &text[text.len().saturating_sub(self.width)..];
+        format!(
+            "{:left$}{:>width$}{:right$}",
+            "",
+            digits,
+            "",
+            left = self.left,
+            width = self.width,
+            right = self.right
+        )
+    }
+
+    /// Formats a text value, left aligned.
+    pub fn format_text(&self, text: &str) -> String {
+        let text: String = text.chars().take(self.width).collect();
+        format!(
+            "{:left$}{:<width$}{:right$}",
+            "",
+            text,
+            "",
+            left = self.left,
+            width = self.width,
+            right = self.right
+        )
+    }
+}
+
+/// Joins the cells of a table row.
+pub fn row(cells: &[String]) -> String {
+    let mut row = String::from("|");
+    for cell in cells {
+        write!(row, "{}|", cell).unwrap();
+    }
+    row
+}
+
+/// Compares an output row with the expected one, where `*` matches any
+/// character. Returns the index of the first differing cell.
+pub fn compare_row(expected: &str, actual: &str) -> Option<usize> {
+    let matches = |expected: &str, actual: &str| {
+        expected.chars().count() == actual.chars().count()
+            && expected
+                .chars()
+                .zip(actual.chars())
+                .all(|(e, a)| e == '*' || e == a)
+    };
+    let expected: Vec<&str> = expected.trim_end().split('|').collect();
+    let actual: Vec<&str> = actual.trim_end().split('|').collect();
+    let cells = expected.len().max(actual.len());
+    (0..cells)
+        .find(|&index| {
+            !matches(
+                expected.get(index).unwrap_or(&""),
+                actual.get(index).unwrap_or(&""),
+            )
+        })
+        // Cells are counted from the first `|`
+        .map(|index| index.saturating_sub(1))
+}
+
+/// The output table of a script run, checked against the `.cmp` file as
+/// each row is written.
+#[derive(Default)]
+pub struct Output {
+    pub columns: Vec<Column>,
+    pub lines: Vec<String>,
+    pub compare: Option<Vec<String>>,
+}
+
+impl Output {
+    /// Sets the columns and writes their header.
+    pub fn set_columns(&mut self, specs: &[String]) -> Result<(), String> {
+        self.columns = specs
+            .iter()
+            .map(|spec| Column::parse(spec))
+            .collect::<Result<_, _>>()?;
+        let headers: Vec<String> = self.columns.iter().map(Column::header).collect();
+        self.push(row(&headers))
+    }
+
+    /// Writes a row, failing if it differs from the `.cmp` file.
+    pub fn push(&mut self, line: String) -> Result<(), String> {
+        let number = self.lines.len() + 1;
+        let expected = self
+            .compare
+            .as_ref()
+            .map(|lines| lines.get(number - 1).map_or("", String::as_str));
+        self.lines.push(line);
+        let Some(expected) = expected else {
+            return Ok(());
+        };
+        let actual = &self.lines[number - 1];
+        match compare_row(expected, actual) {
+            None => Ok(()),
+            Some(index) => {
+                let cell = |line: &str| {
+                    line.split('|')
+                        .nth(index + 1)
+                        .unwrap_or("")
+                        .trim()
+                        .to_string()
+                };
+                let name = self
+                    .columns
+                    .get(index)
+                    .map_or(String::new(), |column| format!(" ({})", column.name));
+                Err(format!(
+                    "comparison failure at line {}, column {}{}: expected {:?}, found {:?}",
+                    number,
+                    index + 1,
+                    name,
+                    cell(expected),
+                    cell(actual)
+                ))
+            }
+        }
+    }
+}
+
+/// An emulator driven by a test script.
+pub trait Target {
+    /// Runs a command specific to the emulator, such as `load` or
+    /// `ticktock`. Returns false if the command is unknown.
+    fn command(&mut self, words: &[String], directory: &Path) -> Result<bool, String>;
+
+    /// Returns the value of a variable, e.g. `RAM[256]`.
+    fn get(&self, variable: &str) -> Result<i16, String>;
+
+    /// Sets a variable.
+    fn set(&mut self, variable: &str, value: i16) -> Result<(), String>;
+}
+
+/// Reads and parses a test script, locating errors in the file.
+pub fn read(path: &Path) -> Result<Vec<Statement>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|error| format!("cannot read {}: {}", path.display(), error))?;
+    parse(&text).map_err(|message| format!("{}:{}", file_name(path), message))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Runs the statements of the script at `path`: `output-file`,
+/// `compare-to`, `output-list`, `output`, `set`, `echo` and `clear-echo`
+/// here, other commands on `target`. Files are relative to the script's
+/// directory, and the output table is written to its `output-file` even
+/// when the comparison fails.
+pub fn run(path: &Path, statements: &[Statement], target: &mut impl Target) -> Result<(), String> {
+    let mut runner = Runner {
+        directory: path.parent().unwrap_or(Path::new(".")),
+        output: Output::default(),
+        output_file: None,
+        target,
+    };
+    let result = runner
+        .run_block(statements)
+        .map_err(|(line, message)| format!("{}:{}: {}", file_name(path), line, message));
+    if let Some(output_file) = &runner.output_file {
+        let mut text = runner.output.lines.join("\n");
+        text.push('\n');
+        fs::write(output_file, text)
+            .map_err(|error| format!("cannot write {}: {}", output_file.display(), error))?;
+    }
+    result
+}
+
+struct Runner<'a, T> {
+    directory: &'a Path,
+    output: Output,
+    output_file: Option<PathBuf>,
+    target: &'a mut T,
+}
+
+impl<T: Target> Runner<'_, T> {
+    fn run_block(&mut self, statements: &[Statement]) -> Result<(), (usize, String)> {
+        for statement in statements {
+            match statement {
+                Statement::Command { words, line } => {
+                    self.run_command(words)
+                        .map_err(|message| (*line, message))?;
+                }
+                Statement::Repeat {
+                    count: Some(count),
+                    body,
+                } => {
+                    for _ in 0..*count {
+                        self.run_block(body)?;
+                    }
+                }
+                Statement::Repeat { count: None, body } => {
+                    let line = match body.first() {
+                        Some(Statement::Command { line, .. }) => *line,
+                        _ => 0,
+                    };
+                    return Err((line, "repeat needs a count".to_string()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn run_command(&mut self, words: &[String]) -> Result<(), String> {
+        let argument = |index: usize| {
+            words
+                .get(index)
+                .map(String::as_str)
+                .ok_or_else(|| format!("{} expects {} argument(s)", words[0], index))
+        };
+        match words[0].as_str() {
+            "output-file" => self.output_file = Some(self.directory.join(argument(1)?)),
+            "compare-to" => {
+                let path = self.directory.join(argument(1)?);
+                let text = fs::read_to_string(&path)
+                    .map_err(|error| format!("cannot read {}: {}", path.display(), error))?;
+                self.output.compare = Some(text.lines().map(str::to_string).collect());
+            }
+            "output-list" => self.output.set_columns(&words[1..])?,
+            "output" => {
+                let cells = self
+                    .output
+                    .columns
+                    .iter()
+                    .map(|column| Ok(column.format(self.target.get(&column.name)?)))
+                    .collect::<Result<Vec<_>, String>>()?;
+                self.output.push(row(&cells))?;
+            }
+            "set" => self.target.set(argument(1)?, parse_value(argument(2)?)?)?,
+            "echo" => println!("{}", argument(1)?),
+            "clear-echo" => {}
+            command => {
+                if !self.target.command(words, self.directory)? {
+                    return Err(format!("unsupported command: {}", command));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses the index of a variable such as `RAM[256]`, returning the name
+/// and the index.
+pub fn indexed(variable: &str) -> Option<(&str, usize)> {
+    let (name, index) = variable.split_once('[')?;
+    Some((name, index.strip_suffix(']')?.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let script = "load Sys.vm, /* comment\n */ output-list RAM[0]%D1.6.1\n  RAM[261]%D1.6.1;\n\
+                      repeat 3 { vmstep; } // steps\necho \"a, b\";";
+        let statements = parse(script).unwrap();
+        let command = |words: &[&str], line| Statement::Command {
+            words: words.iter().map(|word| word.to_string()).collect(),
+            line,
+        };
+        assert_eq!(
+            statements,
+            [
+                command(&["load", "Sys.vm"], 1),
+                command(&["output-list", "RAM[0]%D1.6.1", "RAM[261]%D1.6.1"], 2),
+                Statement::Repeat {
+                    count: Some(3),
+                    body: vec![command(&["vmstep"], 4)],
+                },
+                command(&["echo", "a, b"], 5),
+            ]
+        );
+        assert!(parse("repeat 2 { vmstep; ").is_err());
+        assert!(parse("vmstep").is_err());
+        assert_eq!(parse_value("%B101"), Ok(5));
+        assert_eq!(parse_value("%XFFFF"), Ok(-1));
+        assert_eq!(parse_value("-3"), Ok(-3));
+    }
+
+    #[test]
+    fn test_columns() {
+        let column = Column::parse("RAM[0]%D2.6.2").unwrap();
+        assert_eq!(column.header(), "  RAM[0]  ");
+        assert_eq!(column.format(257), "     257  ");
+        let column = Column::parse("RAM[3006]%D1.6.1").unwrap();
+        assert_eq!(column.header(), "RAM[3006");
+        assert_eq!(Column::parse("time%S1.3.1").unwrap().header(), "time ");
+        assert_eq!(
+            Column::parse("a%B1.15.1").unwrap().format(5),
+            " 000000000000101 "
+        );
+        assert_eq!(Column::parse("a%X0.4.0").unwrap().format(-2), "FFFE");
+        assert!(Column::parse("a%Q1.2.3").is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        let mut output = Output {
+            compare: Some(vec![
+                "| RAM[0] |RAM[261]|".to_string(),
+                "|    262 |***    |".to_string(),
+            ]),
+            ..Default::default()
+        };
+        output
+            .set_columns(&["RAM[0]%D1.6.1".to_string(), "RAM[261]%D1.6.1".to_string()])
+            .unwrap();
+        assert_eq!(
+            output.push("|    261 |      3 |".to_string()),
+            Err(
+                "comparison failure at line 2, column 1 (RAM[0]): expected \"262\", found \"261\""
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            compare_row("|    262 |***    |", "|    262 |     3 |"),
+            Some(1)
+        );
+        assert_eq!(
+            compare_row("|    262 |****** |", "|    262 |    3  |"),
+            None
+        );
+    }
+}
@@ -0,0 +1,355 @@
+//! Interactive terminal frontend: draws the screen with Unicode characters
+//! and feeds key presses to the keyboard register.
+//!
+//! The terminal is put in raw mode with `stty`, so this works over SSH
+//! without any terminal library. Terminals only report key presses, not
+//! releases, so a key stays pressed for a fixed time after its last press
+//! or repeat.
+
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cpu::{KBD, SCREEN, SCREEN_SIZE};
+use crate::keys::{Press, Recorder};
+use crate::screen::{HEIGHT, WIDTH};
+
+/// Hack key codes of the keys that are not characters
+pub const NEWLINE: i16 = 128;
+pub const BACKSPACE: i16 = 129;
+pub const LEFT: i16 = 130;
+pub const UP: i16 = 131;
+pub const RIGHT: i16 = 132;
+pub const DOWN: i16 = 133;
+pub const HOME: i16 = 134;
+pub const END: i16 = 135;
+pub const PAGE_UP: i16 = 136;
+pub const PAGE_DOWN: i16 = 137;
+pub const INSERT: i16 = 138;
+pub const DELETE: i16 = 139;
+pub const ESCAPE: i16 = 140;
+/// F1; F2-F12 follow
+pub const F1: i16 = 141;
+
+/// Cycles between two checks of the clock, the keys and the screen
+const CHECK_INTERVAL: u64 = 1024;
+const FRAME_TIME: Duration = Duration::from_millis(33);
+
+/// How the screen is drawn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Render {
+    /// Braille characters of 2x4 pixels: 256 columns, 64 rows
+    Braille,
+    /// Half blocks for 2x2 pixels, pixels of a pair merged: 256 columns,
+    /// 128 rows
+    Blocks,
+}
+
+pub struct Options {
+    pub render: Render,
+    /// Cycles (or VM steps) per second, `None` to run at full speed
+    pub speed: Option<u64>,
+    /// How long a key stays pressed after its last press or repeat
+    pub key_hold: Duration,
+    /// What the status line counts, "cycle" or "step"
+    pub unit: &'static str,
+    /// Whether key presses reach the keyboard register, rather than a
+    /// script driving it
+    pub keyboard: bool,
+}
+
+pub struct Terminal {
+    options: Options,
+    /// `stty` settings to restore, `None` once restored
+    saved: Option<String>,
+    input: Receiver<Vec<u8>>,
+    /// Key currently pressed and when it was last pressed
+    key: Option<(i16, Instant)>,
+    /// Keys pressed so far, by cycle
+    recorder: Recorder,
+    /// Lines drawn on the terminal, to redraw only the changed ones
+    lines: Vec<String>,
+    last_frame: Instant,
+    /// Clock and cycle count the speed is measured from
+    start: (Instant, u64),
+    last_check: u64,
+}
+
+impl Terminal {
+    /// Switches the terminal to raw mode and clears it.
+    pub fn open(options: Options) -> Result<Self, String> {
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+        let (sender, input) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = [0; 64];
+            let mut stdin = io::stdin();
+            while let Ok(count @ 1..) = stdin.read(&mut buffer) {
+                if sender.send(buffer[..count].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+        // Hide the cursor and clear the screen
+        print!("\x1b[?25l\x1b[2J");
+        Ok(Self {
+            options,
+            saved: Some(saved.trim().to_string()),
+            input,
+            key: None,
+            recorder: Recorder::default(),
+            lines: Vec::new(),
+            last_frame: Instant::now(),
+            start: (Instant::now(), 0),
+            last_check: 0,
+        })
+    }
+
+    /// Restores the terminal. Also done when the terminal is dropped.
+    pub fn restore(&mut self) {
+        if let Some(saved) = self.saved.take() {
+            print!("\x1b[?25h\x1b[{}H\r\n", self.lines.len() + 2);
+            io::stdout().flush().ok();
+            stty(&[&saved]).ok();
+        }
+    }
+
+    /// Called after each cycle of a run: every so often, reads the keys
+    /// into the keyboard register, redraws the screen and waits to keep
+    /// the speed. Returns false when the user quits with Ctrl-C.
+    pub fn update(&mut self, cycle: u64, ram: &mut [i16]) -> bool {
+        if cycle < self.last_check + CHECK_INTERVAL {
+            return true;
+        }
+        self.last_check = cycle;
+
+        let now = Instant::now();
+        while let Ok(bytes) = self.input.try_recv() {
+            if bytes.contains(&3) {
+                return false;
+            }
+            if let Some(&key) = decode(&bytes).last() {
+                self.key = Some((key, now));
+            }
+        }
+        if self
+            .key
+            .is_some_and(|(_, pressed)| now - pressed > self.options.key_hold)
+        {
+            self.key = None;
+        }
+        if self.options.keyboard {
+            ram[KBD] = self.key.map_or(0, |(key, _)| key);
+            self.recorder.key(cycle, ram[KBD]);
+        }
+
+        if now - self.last_frame >= FRAME_TIME {
+            self.last_frame = now;
+            self.draw(
+                &ram[SCREEN..SCREEN + SCREEN_SIZE],
+                &format!("{} {}", self.options.unit, cycle),
+            );
+        }
+        if let Some(speed) = self.options.speed {
+            let (start, start_cycle) = self.start;
+            let due = start + Duration::from_secs_f64((cycle - start_cycle) as f64 / speed as f64);
+            if let Some(ahead) = due.checked_duration_since(now) {
+                thread::sleep(ahead);
+            } else if now - due > Duration::from_millis(250) {
+                // Too slow to keep up: measure from now instead of racing
+                self.start = (now, cycle);
+            }
+        }
+        true
+    }
+
+    /// Returns the keys pressed up to `cycle`, as a keyboard input script
+    /// would press them.
+    pub fn recording(&mut self, cycle: u64) -> Vec<Press> {
+        std::mem::take(&mut self.recorder).finish(cycle)
+    }
+
+    /// Draws the screen with a status line, then waits for a key press.
+    pub fn finish(&mut self, screen: &[i16], status: &str) {
+        self.draw(screen, &format!("{} - press any key", status));
+        while self.input.recv().is_ok_and(|bytes| bytes.is_empty()) {}
+    }
+
+    fn draw(&mut self, screen: &[i16], status: &str) {
+        let mut lines = render(screen, self.options.render);
+        lines.push(format!("{:<40}", status));
+        let mut output = String::new();
+        for (row, line) in lines.iter().enumerate() {
+            if self.lines.get(row) != Some(line) {
+                output.push_str(&format!("\x1b[{};1H{}", row + 1, line));
+            }
+        }
+        self.lines = lines;
+        print!("{}", output);
+        io::stdout().flush().ok();
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+fn stty(args: &[&str]) -> Result<String, String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()
+        .map_err(|error| format!("cannot run stty: {}", error))?;
+    if !output.status.success() {
+        return Err("stty failed: is the input a terminal?".to_string());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Bits of the braille dots of a character, by pixel row and column: dots
+/// 1-3 and 4-6 run down the two columns, then come 7 and 8
+const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+/// Returns the lines of characters showing the screen.
+pub fn render(screen: &[i16], render: Render) -> Vec<String> {
+    let pixel = |x: usize, y: usize| screen[y * WIDTH / 16 + x / 16] >> (x % 16) & 1 != 0;
+    match render {
+        Render::Braille => (0..HEIGHT / 4)
+            .map(|row| {
+                (0..WIDTH / 2)
+                    .map(|column| {
+                        let mut bits = 0;
+                        for (dy, dots) in DOTS.iter().enumerate() {
+                            for (dx, dot) in dots.iter().enumerate() {
+                                if pixel(column * 2 + dx, row * 4 + dy) {
+                                    bits |= dot;
+                                }
+                            }
+                        }
+                        char::from_u32(0x2800 + bits).unwrap()
+                    })
+                    .collect()
+            })
+            .collect(),
+        Render::Blocks => (0..HEIGHT / 2)
+            .map(|row| {
+                (0..WIDTH / 2)
+                    .map(|column| {
+                        let x = column * 2;
+                        let top = pixel(x, row * 2) || pixel(x + 1, row * 2);
+                        let bottom = pixel(x, row * 2 + 1) || pixel(x + 1, row * 2 + 1);
+                        match (top, bottom) {
+                            (false, false) => ' ',
+                            (true, false) => '▀',
+                            (false, true) => '▄',
+                            (true, true) => '█',
+                        }
+                    })
+                    .collect()
+            })
+            .collect(),
+    }
+}
+
+/// Decodes the bytes of a terminal read into Hack key codes. A lone
+/// escape is the Esc key; escape sequences are the arrows and other keys.
+pub fn decode(bytes: &[u8]) -> Vec<i16> {
+    let mut keys = Vec::new();
+    let mut rest = bytes;
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        let key = match byte {
+            b'\r' | b'\n' => NEWLINE,
+            0x7f | 0x08 => BACKSPACE,
+            0x1b => {
+                let (key, length) = escape_sequence(rest);
+                rest = &rest[length..];
+                key
+            }
+            0x20..=0x7e => byte as i16,
+            _ => continue,
+        };
+        keys.push(key);
+    }
+    keys
+}
+
+/// Decodes the escape sequence following an escape byte, returning the
+/// key and the length of the sequence.
+fn escape_sequence(bytes: &[u8]) -> (i16, usize) {
+    let (Some(&(b'[' | b'O')), Some(&last)) = (bytes.first(), bytes.get(1)) else {
+        return (ESCAPE, 0);
+    };
+    let simple = match last {
+        b'A' => Some(UP),
+        b'B' => Some(DOWN),
+        b'C' => Some(RIGHT),
+        b'D' => Some(LEFT),
+        b'H' => Some(HOME),
+        b'F' => Some(END),
+        // F1-F4 as ESC O P to ESC O S
+        b'P'..=b'S' => Some(F1 + (last - b'P') as i16),
+        _ => None,
+    };
+    if let Some(key) = simple {
+        return (key, 2);
+    }
+    // ESC [ <number> ~
+    let Some(end) = bytes.iter().position(|&byte| byte == b'~') else {
+        return (ESCAPE, 0);
+    };
+    let number: u32 = std::str::from_utf8(&bytes[1..end])
+        .ok()
+        .and_then(|number| number.parse().ok())
+        .unwrap_or(0);
+    let key = match number {
+        1 | 7 => HOME,
+        2 => INSERT,
+        3 => DELETE,
+        4 | 8 => END,
+        5 => PAGE_UP,
+        6 => PAGE_DOWN,
+        11..=15 => F1 + (number - 11) as i16,
+        17..=21 => F1 + 5 + (number - 17) as i16,
+        23 | 24 => F1 + 10 + (number - 23) as i16,
+        _ => ESCAPE,
+    };
+    (key, end + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(b"a\r\x7f"), [97, NEWLINE, BACKSPACE]);
+        assert_eq!(decode(b"\x1b[A\x1b[D\x1bOB\x1b[C"), [UP, LEFT, DOWN, RIGHT]);
+        assert_eq!(decode(b"\x1b"), [ESCAPE]);
+        assert_eq!(
+            decode(b"\x1b[3~\x1b[5~\x1bOP\x1b[24~"),
+            [DELETE, PAGE_UP, F1, F1 + 11]
+        );
+        assert_eq!(decode(b"\x03"), []);
+    }
+
+    #[test]
+    fn test_render() {
+        let mut screen = vec![0; 8192];
+        // Pixels (0, 0), (1, 3) and (2, 1)
+        screen[0] = 0b101;
+        screen[3 * 32] = 0b10;
+        screen[32] |= 0b100;
+        let lines = render(&screen, Render::Braille);
+        assert_eq!(lines.len(), 64);
+        assert!(lines[0].starts_with("\u{2881}\u{2803}\u{2800}"));
+        let lines = render(&screen, Render::Blocks);
+        assert_eq!(lines.len(), 128);
+        assert!(lines[0].starts_with("▀█ "));
+        assert!(lines[1].starts_with("▄  "));
+    }
+}
@@ -0,0 +1,145 @@
+//! Hack Assembler library.
+//! Translates Hack assembly language into Hack machine code.
+//!
+//! This assembler implements the assembly process in two passes:
+//! 1. First pass: Collects all labels and their corresponding ROM addresses
+//! 2. Second pass: Generates binary code while handling variables and symbols
+//!
+//! The assembler handles three types of instructions:
+//! - A-instructions: @value (translated to 0vvvvvvvvvvvvvvv where v is the 15-bit value)
+//! - C-instructions: dest=comp;jump (translated to 111accccccdddjjj)
+//! - L-instructions: (LABEL) (pseudo-command that defines a label)
+
+use code::Code;
+use parser::{CommandType, Parser};
+use symbol_table::SymbolTable;
+
+mod code;
+mod parser;
+mod symbol_table;
+
+/// The result of assembling a program.
+pub struct Assembly {
+    /// Machine code, one 16-bit word per instruction
+    pub instructions: Vec<u16>,
+    /// Labels and their ROM addresses, in program order
+    pub labels: Vec<(String, u16)>,
+    /// Variables and their RAM addresses, in allocation order
+    pub variables: Vec<(String, u16)>,
+}
+
+impl Assembly {
+    /// Returns the contents of a .hack file: one 16-bit binary word per line.
+    pub fn to_hack(&self) -> String {
+        self.instructions
+            .iter()
+            .map(|&instruction| format!("{}\n", decimal_to_binary(instruction)))
+            .collect()
+    }
+
+    /// Returns the symbol map: one `ROM <address> <label>` line per label
+    /// followed by one `RAM <address> <variable>` line per variable.
+    pub fn symbol_map(&self) -> String {
+        let labels = self
+            .labels
+            .iter()
+            .map(|(label, address)| format!("ROM {} {}\n", address, label));
+        let variables = self
+            .variables
+            .iter()
+            .map(|(variable, address)| format!("RAM {} {}\n", address, variable));
+        labels.chain(variables).collect()
+    }
+}
+
+/// Converts a decimal number to its 16-bit binary representation.
+///
+/// # Arguments
+///
+/// * `num` - A u16 number to convert to binary
+///
+/// # Returns
+///
+/// A String containing the 16-bit binary representation with leading zeros
+fn decimal_to_binary(num: u16) -> String {
+    format!("{:016b}", num)
+}
+
+/// Assembles Hack assembly source code.
+///
+/// # Arguments
+///
+/// * `source` - The assembly code as a string
+///
+/// # Returns
+///
+/// The machine code together with the labels and variables it defines
+pub fn assemble(source: &str) -> Assembly {
+    let mut symbol_table = SymbolTable::new();
+    let mut labels = Vec::new();
+    let mut parser = Parser::new(source);
+
+    // First pass: collect labels
+    // ROM address starts at 0 and increments for each actual instruction (A or C)
+    let mut rom_address = 0;
+    while parser.has_more_commands() {
+        match parser.command_type() {
+            Some(CommandType::LCommand) => {
+                let label = parser.symbol().expect("Failed to parse label");
+                symbol_table.add_entry(label.to_string(), rom_address);
+                labels.push((label.to_string(), rom_address));
+            }
+            Some(CommandType::ACommand) | Some(CommandType::CCommand) => {
+                rom_address += 1;
+            }
+            None => {}
+        }
+        parser.advance();
+    }
+
+    // Second pass: generate binary code
+    // RAM address starts at 16 for variables (0-15 are reserved)
+    let mut ram_address = 16;
+    let mut variables = Vec::new();
+    let mut parser = Parser::new(source);
+    let mut instructions = Vec::new();
+
+    while parser.has_more_commands() {
+        match parser.command_type() {
+            Some(CommandType::ACommand) => {
+                let symbol = parser.symbol().expect("Failed to parse symbol");
+                let address = if let Ok(num) = symbol.parse::<u16>() {
+                    // If symbol is a number, use it directly
+                    num
+                } else if symbol_table.contains(symbol) {
+                    // If symbol exists in table, use its address
+                    symbol_table.get_address(symbol).unwrap()
+                } else {
+                    // If symbol is new, allocate next available RAM address
+                    symbol_table.add_entry(symbol.to_string(), ram_address);
+                    variables.push((symbol.to_string(), ram_address));
+                    ram_address += 1;
+                    ram_address - 1
+                };
+                instructions.push(address);
+            }
+            Some(CommandType::CCommand) => {
+                // C-instructions always start with '111'
+                let mut binary = String::from("111");
+                binary.push_str(&Code::comp(parser.comp().unwrap()));
+                binary.push_str(&Code::dest(parser.dest().unwrap()));
+                binary.push_str(&Code::jump(parser.jump().unwrap()));
+                instructions.push(u16::from_str_radix(&binary, 2).unwrap());
+            }
+            Some(CommandType::LCommand) => {} // Labels are handled in first pass
+            None => {}
+        }
+        parser.advance();
+    }
+
+    Assembly {
+        instructions,
+        labels,
+        variables,
+    }
+}
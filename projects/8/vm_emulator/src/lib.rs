@@ -0,0 +1,14 @@
+//! VM emulator library: executes VM programs directly, as a reference for
+//! the code generated by the VM translator.
+//!
+//! Programs are parsed with the translator's parser and linked into a
+//! `Program`, which a `Vm` runs on 32K words of RAM using the standard
+//! mapping: SP, LCL, ARG, THIS and THAT in RAM[0-4], temp at RAM[5-12],
+//! statics from RAM[16] and the stack from RAM[256]. OS functions that the
+//! program does not define are provided natively by `os`.
+
+mod font;
+pub mod os;
+pub mod program;
+pub mod vm;
+pub mod vme_test;
@@ -0,0 +1,322 @@
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+use cpu_emulator::cpu::SCREEN_SIZE;
+use cpu_emulator::keys::{self, Player, Press};
+use cpu_emulator::screen::{self, Schedule};
+use cpu_emulator::terminal::{self, Render, Terminal};
+
+use vm_emulator::program::{self, Program};
+use vm_emulator::vm::{self, Outcome, Vm};
+use vm_emulator::{os, vme_test};
+
+const DEFAULT_MAX_STEPS: u64 = 100_000_000;
+/// Speed of interactive runs, in steps per second
+const DEFAULT_SPEED: u64 = 1_000_000;
+
+struct Options {
+    /// Entry function: `None` uses Sys.init if it is defined
+    entry: Option<String>,
+    max_steps: u64,
+    /// Initial RAM values, e.g. to mirror the projects/7 .tst setups
+    set: Vec<(usize, i16)>,
+    dump_ram: Vec<(usize, usize)>,
+    dump_stack: bool,
+    native_os: bool,
+    /// Image of the screen written at the end of the run
+    screenshot: Option<PathBuf>,
+    /// Further images during the run, named after their step
+    screenshots: Schedule,
+    /// Run interactively in the terminal
+    tty: bool,
+    render: Render,
+    /// Steps per second of interactive runs, `None` for full speed
+    speed: Option<u64>,
+    key_hold: Duration,
+    /// Keyboard input script driving the run
+    keys: Option<PathBuf>,
+    /// Where to write the keys pressed during a --tty run, as a script
+    record_keys: Option<PathBuf>,
+    input: String,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Option<Self> {
+        let mut options = Options {
+            entry: None,
+            max_steps: DEFAULT_MAX_STEPS,
+            set: Vec::new(),
+            dump_ram: Vec::new(),
+            dump_stack: false,
+            native_os: true,
+            screenshot: None,
+            screenshots: Schedule::default(),
+            tty: false,
+            render: Render::Braille,
+            speed: Some(DEFAULT_SPEED),
+            key_hold: Duration::from_millis(300),
+            keys: None,
+            record_keys: None,
+            input: String::new(),
+        };
+        let mut input = None;
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--entry" => options.entry = Some(args.next()?.clone()),
+                "--max-steps" => options.max_steps = args.next()?.parse().ok()?,
+                "--set" => {
+                    let (address, value) = args.next()?.split_once('=')?;
+                    options
+                        .set
+                        .push((address.parse().ok()?, value.parse().ok()?));
+                }
+                "--dump-ram" => {
+                    let range = args.next()?;
+                    let (first, last) = range.split_once('-').unwrap_or((range, range));
+                    options
+                        .dump_ram
+                        .push((first.parse().ok()?, last.parse().ok()?));
+                }
+                "--dump-stack" => options.dump_stack = true,
+                "--no-native-os" => options.native_os = false,
+                "--screenshot" => options.screenshot = Some(PathBuf::from(args.next()?)),
+                "--screenshot-at" => options.screenshots.at.push(args.next()?.parse().ok()?),
+                "--screenshot-every" => match args.next()?.parse().ok()? {
+                    0 => return None,
+                    every => options.screenshots.every = Some(every),
+                },
+                "--tty" => options.tty = true,
+                "--render" => {
+                    options.render = match args.next()?.as_str() {
+                        "braille" => Render::Braille,
+                        "blocks" => Render::Blocks,
+                        _ => return None,
+                    }
+                }
+                "--speed" => {
+                    options.speed = match args.next()?.as_str() {
+                        "max" => None,
+                        speed => Some(speed.parse().ok().filter(|&speed| speed > 0)?),
+                    }
+                }
+                "--key-hold" => {
+                    options.key_hold = Duration::from_millis(args.next()?.parse().ok()?)
+                }
+                "--keys" => options.keys = Some(PathBuf::from(args.next()?)),
+                "--record-keys" => options.record_keys = Some(PathBuf::from(args.next()?)),
+                _ if arg.starts_with("--") => return None,
+                _ if input.is_none() => input = Some(arg.clone()),
+                _ => return None,
+            }
+        }
+        options.input = input?;
+        // Captures during the run are named after the end-of-run one
+        if options.screenshot.is_none() && options.screenshots.next(0).is_some() {
+            return None;
+        }
+        // Only keys typed in the terminal can be recorded
+        if options.record_keys.is_some() && (!options.tty || options.keys.is_some()) {
+            return None;
+        }
+        Some(options)
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let Some(options) = Options::parse(&args) else {
+        eprintln!(
+            "Usage: {} [--entry <function>|none] [--max-steps <n>] [--set <address>=<value>] [--dump-ram <first>-<last>] [--dump-stack] [--no-native-os] [--screenshot <file.png|file.pbm> [--screenshot-at <step>] [--screenshot-every <n>]] [--tty [--render braille|blocks] [--speed <steps per second>|max] [--key-hold <ms>] [--record-keys <file>]] [--keys <file>] <file.vm, file.vmb, directory or script.tst>",
+            args[0]
+        );
+        process::exit(1);
+    };
+
+    let input = Path::new(&options.input);
+    if input
+        .extension()
+        .is_some_and(|extension| extension == "tst")
+    {
+        match vme_test::run(input) {
+            Ok(()) => println!("End of script - Comparison ended successfully"),
+            Err(error) => {
+                eprintln!("Error: {}", error);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let mut files = program::read_files(input).unwrap_or_else(|error| {
+        eprintln!("Error: {}", error);
+        process::exit(1);
+    });
+    if options.native_os {
+        files.extend(os::bootstrap(&files));
+    }
+    let program = Program::link(&files).unwrap_or_else(|errors| {
+        for error in errors {
+            eprintln!("Error: {}", error);
+        }
+        process::exit(1);
+    });
+
+    let entry = match options.entry.as_deref() {
+        Some("none") => None,
+        Some(entry) => Some(entry),
+        None => program.function("Sys.init").map(|_| "Sys.init"),
+    };
+    // Shared by the observer and the end of the run, so declared before the
+    // VM; opened once the arguments are checked
+    let terminal = RefCell::new(None);
+    let mut vm = Vm::new(&program);
+    vm.native_os = options.native_os;
+    for &(address, value) in &options.set {
+        if address >= vm::RAM_SIZE {
+            eprintln!("Error: RAM[{}] is out of range", address);
+            process::exit(1);
+        }
+        vm.ram[address] = value;
+    }
+    let mut player = options.keys.as_deref().map(|path| {
+        Player::new(keys::read(path).unwrap_or_else(|error| {
+            eprintln!("Error: {}", error);
+            process::exit(1);
+        }))
+    });
+    *terminal.borrow_mut() = options.tty.then(|| {
+        Terminal::open(terminal::Options {
+            render: options.render,
+            speed: options.speed,
+            key_hold: options.key_hold,
+            unit: "step",
+            keyboard: player.is_none(),
+        })
+        .unwrap_or_else(|error| {
+            eprintln!("Error: {}", error);
+            process::exit(1);
+        })
+    });
+    if let (true, Some(speed)) = (options.tty || player.is_some(), options.speed) {
+        // Sys.wait takes its time, to pace games such as Pong; scripts
+        // need it too to replay what was played in the terminal
+        vm.steps_per_ms = speed / 1000;
+    }
+    if let Some(player) = &mut player {
+        vm.ram[os::KBD] = player.key(0);
+    }
+    if options.screenshot.is_some() || options.tty || player.is_some() {
+        let path = options.screenshot.clone();
+        let schedule = options.screenshots.clone();
+        let record_keys = options.record_keys.clone();
+        let terminal = &terminal;
+        vm.observe(move |steps, ram| {
+            if let Some(player) = &mut player {
+                ram[os::KBD] = player.key(steps);
+            }
+            if let Some(path) = &path
+                && schedule.is_due(steps)
+            {
+                save_screenshot(&screen::path_at(path, steps), ram);
+            }
+            if let Some(terminal) = terminal.borrow_mut().as_mut()
+                && !terminal.update(steps, ram)
+            {
+                let presses = terminal.recording(steps);
+                terminal.restore();
+                if let Some(path) = &record_keys {
+                    save_keys(path, &presses);
+                }
+                println!("Quit after {} steps", steps);
+                process::exit(0);
+            }
+        });
+    }
+    if let Err(error) = vm.start(entry) {
+        drop(terminal.borrow_mut().take());
+        eprintln!("Error: {}", error);
+        process::exit(1);
+    }
+
+    let result = vm.run(options.max_steps);
+    let status = match &result {
+        Ok(Outcome::Halted) => format!("Halted after {} steps", vm.steps),
+        Ok(Outcome::Returned) => format!("{} returned after {} steps", entry.unwrap(), vm.steps),
+        Ok(Outcome::SysError(code)) => format!("Sys.error({}) after {} steps", code, vm.steps),
+        Ok(Outcome::StepLimit) => format!("Stopped at the step limit of {}", vm.steps),
+        Ok(Outcome::End) => format!("Ran past the last command after {} steps", vm.steps),
+        Err(error) => format!("Error: {}", error),
+    };
+    if let Some(mut terminal) = terminal.borrow_mut().take() {
+        let presses = terminal.recording(vm.steps);
+        terminal.finish(&vm.ram[os::SCREEN..os::SCREEN + SCREEN_SIZE], &status);
+        terminal.restore();
+        if let Some(path) = &options.record_keys {
+            save_keys(path, &presses);
+        }
+    }
+    if result.is_ok() {
+        println!("{}", status);
+    } else {
+        eprintln!("{}", status);
+    }
+    println!(
+        "SP={} LCL={} ARG={} THIS={} THAT={}",
+        vm.ram[vm::SP],
+        vm.ram[vm::LCL],
+        vm.ram[vm::ARG],
+        vm.ram[vm::THIS],
+        vm.ram[vm::THAT]
+    );
+    if let Some(path) = &options.screenshot {
+        save_screenshot(path, &vm.ram);
+    }
+    if options.dump_stack || result.is_err() {
+        dump_stack(&vm);
+    }
+    // `address value` lines, as read by vm-profile
+    for &(first, last) in &options.dump_ram {
+        for address in first..=last.min(vm::RAM_SIZE - 1) {
+            println!("{} {}", address, vm.ram[address]);
+        }
+    }
+    if result.is_err() {
+        process::exit(1);
+    }
+}
+
+/// Writes an image of the screen memory map of `ram`, exiting on errors.
+fn save_screenshot(path: &Path, ram: &[i16]) {
+    screen::write(path, &ram[os::SCREEN..os::SCREEN + SCREEN_SIZE]).unwrap_or_else(|error| {
+        eprintln!("Error: {}", error);
+        process::exit(1);
+    });
+}
+
+/// Writes the keys pressed in the terminal as a keyboard input script,
+/// exiting on errors.
+fn save_keys(path: &Path, presses: &[Press]) {
+    fs::write(path, keys::format(presses)).unwrap_or_else(|error| {
+        eprintln!("Error: cannot write {}: {}", path.display(), error);
+        process::exit(1);
+    });
+}
+
+/// Prints the active functions and the working stack above RAM[256].
+fn dump_stack(vm: &Vm) {
+    println!("Call stack:");
+    for function in vm.backtrace() {
+        println!("  {}", function);
+    }
+    let sp = (vm.ram[vm::SP] as u16 as usize).min(vm::RAM_SIZE);
+    let base = vm::STACK_BASE as usize;
+    println!("Stack (RAM[{}-{}]):", base, sp.max(base + 1) - 1);
+    for address in base..sp {
+        println!("  {} {}", address, vm.ram[address]);
+    }
+}
@@ -0,0 +1,780 @@
+//! Native implementations of the Jack OS classes.
+//!
+//! Calls to `Math`, `String`, `Array`, `Memory`, `Output`, `Screen`,
+//! `Keyboard` and `Sys` functions that the program does not define run in
+//! Rust, following the API and the object layouts of the classes in
+//! `projects/12`. A native function calls functions of other classes through
+//! `Vm::invoke`, so OS classes compiled to VM code can replace the native
+//! ones one class at a time.
+//!
+//! Errors call `Sys.error` with the standard OS error codes. The native
+//! `Sys.wait` measures time in steps, `Vm::steps_per_ms` per millisecond,
+//! and returns at once by default.
+
+use vm_translator::parser::VmCommand;
+use vm_translator::program::VmFile;
+
+use crate::font;
+use crate::vm::{Outcome, Trap, Vm};
+
+pub const SCREEN: usize = 16384;
+pub const KBD: usize = 24576;
+pub const SCREEN_WIDTH: i16 = 512;
+pub const SCREEN_HEIGHT: i16 = 256;
+
+const HEAP_BASE: i16 = 2048;
+const HEAP_END: i16 = 16383;
+const TEXT_ROWS: i16 = 23;
+const TEXT_COLUMNS: i16 = 64;
+const NEW_LINE: i16 = 128;
+const BACKSPACE: i16 = 129;
+
+/// `Sys.init` used when the program has a `Main.main` but no `Sys.init`.
+const SYS_INIT: &str = "function Sys.init 0
+call Memory.init 0
+pop temp 0
+call Math.init 0
+pop temp 0
+call Screen.init 0
+pop temp 0
+call Output.init 0
+pop temp 0
+call Keyboard.init 0
+pop temp 0
+call Main.main 0
+pop temp 0
+call Sys.halt 0
+pop temp 0";
+
+/// Returns a file defining `Sys.init` if `files` need one to start at
+/// `Main.main`.
+pub fn bootstrap(files: &[VmFile]) -> Option<VmFile> {
+    let defines = |name: &str| {
+        files.iter().any(|file| {
+            file.commands.iter().any(|command| {
+                matches!(&command.command, VmCommand::Function { name: function, .. } if function == name)
+            })
+        })
+    };
+    (defines("Main.main") && !defines("Sys.init")).then(|| VmFile::parse("OS", SYS_INIT))
+}
+
+/// State kept in the static variables of the Jack OS classes.
+pub(crate) struct OsState {
+    free_list: i16,
+    color: bool,
+    row: i16,
+    column: i16,
+}
+
+impl Default for OsState {
+    fn default() -> Self {
+        Self {
+            free_list: 0,
+            color: true,
+            row: 0,
+            column: 0,
+        }
+    }
+}
+
+type NativeFn = fn(&mut Vm, &[i16]) -> Result<i16, Trap>;
+
+pub(crate) struct Native {
+    pub n_args: usize,
+    pub run: NativeFn,
+}
+
+/// Returns the native implementation of OS function `name`.
+pub(crate) fn find(name: &str) -> Option<Native> {
+    let (n_args, run): (usize, NativeFn) = match name {
+        "Math.init" => (0, |_, _| Ok(0)),
+        "Math.abs" => (1, |_, args| Ok(args[0].wrapping_abs())),
+        "Math.multiply" => (2, |_, args| Ok(args[0].wrapping_mul(args[1]))),
+        "Math.divide" => (2, math_divide),
+        "Math.min" => (2, |_, args| Ok(args[0].min(args[1]))),
+        "Math.max" => (2, |_, args| Ok(args[0].max(args[1]))),
+        "Math.sqrt" => (1, math_sqrt),
+        "Memory.init" => (0, memory_init),
+        "Memory.peek" => (1, |vm, args| Ok(vm.read(address(args[0]))?)),
+        "Memory.poke" => (2, memory_poke),
+        "Memory.alloc" => (1, memory_alloc),
+        "Memory.deAlloc" => (1, memory_dealloc),
+        "Array.new" => (1, array_new),
+        "Array.dispose" => (1, |vm, args| vm.invoke("Memory.deAlloc", args)),
+        "String.new" => (1, string_new),
+        "String.dispose" => (1, string_dispose),
+        "String.length" => (1, |vm, args| field(vm, args[0], LENGTH)),
+        "String.charAt" => (2, string_char_at),
+        "String.setCharAt" => (3, string_set_char_at),
+        "String.appendChar" => (2, string_append_char),
+        "String.eraseLastChar" => (1, string_erase_last_char),
+        "String.intValue" => (1, string_int_value),
+        "String.setInt" => (2, string_set_int),
+        "String.newLine" => (0, |_, _| Ok(NEW_LINE)),
+        "String.backSpace" => (0, |_, _| Ok(BACKSPACE)),
+        "String.doubleQuote" => (0, |_, _| Ok(34)),
+        "Output.init" => (0, output_init),
+        "Output.moveCursor" => (2, output_move_cursor),
+        "Output.printChar" => (1, output_print_char),
+        "Output.printString" => (1, output_print_string),
+        "Output.printInt" => (1, output_print_int),
+        "Output.println" => (0, output_println),
+        "Output.backSpace" => (0, output_backspace),
+        "Screen.init" => (0, screen_init),
+        "Screen.clearScreen" => (0, screen_clear),
+        "Screen.setColor" => (1, screen_set_color),
+        "Screen.drawPixel" => (2, screen_draw_pixel),
+        "Screen.drawLine" => (4, screen_draw_line),
+        "Screen.drawRectangle" => (4, screen_draw_rectangle),
+        "Screen.drawCircle" => (3, screen_draw_circle),
+        "Keyboard.init" => (0, |_, _| Ok(0)),
+        "Keyboard.keyPressed" => (0, |vm, _| Ok(vm.ram[KBD])),
+        "Keyboard.readChar" => (0, keyboard_read_char),
+        "Keyboard.readLine" => (1, keyboard_read_line),
+        "Keyboard.readInt" => (1, keyboard_read_int),
+        "Sys.halt" => (0, |_, _| Err(Trap::Stop(Outcome::Halted))),
+        "Sys.error" => (1, sys_error),
+        "Sys.wait" => (1, sys_wait),
+        _ => return None,
+    };
+    Some(Native { n_args, run })
+}
+
+/// Calls `Sys.error`, which does not return.
+fn error(vm: &mut Vm, code: i16) -> Result<i16, Trap> {
+    vm.invoke("Sys.error", &[code])?;
+    Err(Trap::Stop(Outcome::Halted))
+}
+
+fn address(value: i16) -> usize {
+    value as u16 as usize
+}
+
+fn math_divide(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    if args[1] == 0 {
+        return error(vm, 3);
+    }
+    Ok(args[0].wrapping_div(args[1]))
+}
+
+fn math_sqrt(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    if args[0] < 0 {
+        return error(vm, 4);
+    }
+    Ok(isqrt(args[0] as i32) as i16)
+}
+
+fn isqrt(x: i32) -> i32 {
+    let mut root = 0;
+    while (root + 1) * (root + 1) <= x {
+        root += 1;
+    }
+    root
+}
+
+fn memory_init(vm: &mut Vm, _: &[i16]) -> Result<i16, Trap> {
+    vm.os.free_list = HEAP_BASE;
+    vm.ram[HEAP_BASE as usize] = HEAP_END - HEAP_BASE + 1;
+    vm.ram[HEAP_BASE as usize + 1] = 0;
+    Ok(0)
+}
+
+fn memory_poke(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    vm.write(address(args[0]), args[1])?;
+    Ok(0)
+}
+
+/// First fit over the free list of `Memory.jack`: each block starts with
+/// its size, header included, and the address of the next free block.
+fn memory_alloc(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    let size = args[0];
+    if size < 1 {
+        return error(vm, 5);
+    }
+    let mut previous = 0;
+    let mut current = vm.os.free_list;
+    while current != 0 {
+        let block_size = vm.read(address(current))?;
+        let next = vm.read(address(current) + 1)?;
+        let remaining = block_size as i32 - size as i32 - 2;
+        if remaining >= 0 {
+            let following = if remaining >= 2 {
+                // Split off the rest of the block as a new free block. A
+                // corrupted heap may wrap around, and then fault on the write
+                let split = current.wrapping_add(size).wrapping_add(2);
+                vm.write(address(split), remaining as i16)?;
+                vm.write(address(split) + 1, next)?;
+                vm.write(address(current), size + 2)?;
+                split
+            } else {
+                next
+            };
+            vm.write(address(current) + 1, 0)?;
+            if previous == 0 {
+                vm.os.free_list = following;
+            } else {
+                vm.write(address(previous) + 1, following)?;
+            }
+            return Ok(current + 2);
+        }
+        previous = current;
+        current = next;
+    }
+    error(vm, 6)
+}
+
+fn memory_dealloc(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    if args[0] != 0 {
+        let block = args[0].wrapping_sub(2);
+        vm.write(address(block) + 1, vm.os.free_list)?;
+        vm.os.free_list = block;
+    }
+    Ok(0)
+}
+
+fn array_new(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    if args[0] < 1 {
+        return error(vm, 2);
+    }
+    vm.invoke("Memory.alloc", args)
+}
+
+// Fields of a String object
+const CHARS: i16 = 0;
+const MAX_LENGTH: i16 = 1;
+const LENGTH: i16 = 2;
+
+fn field(vm: &Vm, this: i16, index: i16) -> Result<i16, Trap> {
+    Ok(vm.read(address(this.wrapping_add(index)))?)
+}
+
+fn set_field(vm: &mut Vm, this: i16, index: i16, value: i16) -> Result<(), Trap> {
+    Ok(vm.write(address(this.wrapping_add(index)), value)?)
+}
+
+fn string_new(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    let max_length = args[0];
+    if max_length < 0 {
+        return error(vm, 14);
+    }
+    let this = vm.invoke("Memory.alloc", &[3])?;
+    let chars = if max_length > 0 {
+        vm.invoke("Array.new", &[max_length])?
+    } else {
+        0
+    };
+    set_field(vm, this, CHARS, chars)?;
+    set_field(vm, this, MAX_LENGTH, max_length)?;
+    set_field(vm, this, LENGTH, 0)?;
+    Ok(this)
+}
+
+fn string_dispose(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    let chars = field(vm, args[0], CHARS)?;
+    if chars != 0 {
+        vm.invoke("Array.dispose", &[chars])?;
+    }
+    vm.invoke("Memory.deAlloc", args)
+}
+
+/// Returns the address of character `index` if it is within the length.
+fn char_address(vm: &Vm, this: i16, index: i16) -> Result<Option<usize>, Trap> {
+    if index < 0 || index >= field(vm, this, LENGTH)? {
+        return Ok(None);
+    }
+    Ok(Some(address(field(vm, this, CHARS)?.wrapping_add(index))))
+}
+
+fn string_char_at(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    match char_address(vm, args[0], args[1])? {
+        Some(address) => Ok(vm.read(address)?),
+        None => error(vm, 15),
+    }
+}
+
+fn string_set_char_at(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    match char_address(vm, args[0], args[1])? {
+        Some(address) => {
+            vm.write(address, args[2])?;
+            Ok(0)
+        }
+        None => error(vm, 16),
+    }
+}
+
+fn string_append_char(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    let this = args[0];
+    let length = field(vm, this, LENGTH)?;
+    if length >= field(vm, this, MAX_LENGTH)? {
+        return error(vm, 17);
+    }
+    let chars = field(vm, this, CHARS)?;
+    vm.write(address(chars.wrapping_add(length)), args[1])?;
+    set_field(vm, this, LENGTH, length + 1)?;
+    Ok(this)
+}
+
+fn string_erase_last_char(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    let length = field(vm, args[0], LENGTH)?;
+    if length == 0 {
+        return error(vm, 18);
+    }
+    set_field(vm, args[0], LENGTH, length - 1)?;
+    Ok(0)
+}
+
+/// Reads the characters of a native string.
+fn string_chars(vm: &Vm, this: i16) -> Result<Vec<i16>, Trap> {
+    let chars = field(vm, this, CHARS)?;
+    (0..field(vm, this, LENGTH)?)
+        .map(|index| Ok(vm.read(address(chars.wrapping_add(index)))?))
+        .collect()
+}
+
+fn string_int_value(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    let chars = string_chars(vm, args[0])?;
+    let negative = chars.first() == Some(&(b'-' as i16));
+    let value = chars
+        .iter()
+        .skip(negative as usize)
+        .take_while(|c| (b'0' as i16..=b'9' as i16).contains(c))
+        .fold(0i16, |value, c| {
+            value.wrapping_mul(10).wrapping_add(c - b'0' as i16)
+        });
+    Ok(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+fn string_set_int(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    let this = args[0];
+    let digits = args[1].to_string();
+    if digits.len() > field(vm, this, MAX_LENGTH)? as usize {
+        return error(vm, 19);
+    }
+    let chars = field(vm, this, CHARS)?;
+    for (index, digit) in digits.bytes().enumerate() {
+        vm.write(address(chars.wrapping_add(index as i16)), digit as i16)?;
+    }
+    set_field(vm, this, LENGTH, digits.len() as i16)?;
+    Ok(0)
+}
+
+fn output_init(vm: &mut Vm, _: &[i16]) -> Result<i16, Trap> {
+    vm.os.row = 0;
+    vm.os.column = 0;
+    Ok(0)
+}
+
+/// Draws character `c` in the cell under the cursor. Cells are 8 pixels
+/// wide, so each one is half of a screen word.
+fn draw_char(vm: &mut Vm, c: i16) {
+    let (row, column) = (vm.os.row as usize, vm.os.column as usize);
+    for (line, &bits) in font::glyph(c).iter().enumerate() {
+        let word = &mut vm.ram[SCREEN + (row * 11 + line) * 32 + column / 2];
+        *word = if column % 2 == 0 {
+            (*word & !0xff) | bits as i16
+        } else {
+            (*word & 0xff) | (bits as i16) << 8
+        };
+    }
+}
+
+fn output_move_cursor(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    let (row, column) = (args[0], args[1]);
+    if !(0..TEXT_ROWS).contains(&row) || !(0..TEXT_COLUMNS).contains(&column) {
+        return error(vm, 20);
+    }
+    vm.os.row = row;
+    vm.os.column = column;
+    draw_char(vm, b' ' as i16);
+    Ok(0)
+}
+
+fn output_print_char(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    match args[0] {
+        NEW_LINE => return output_println(vm, args),
+        BACKSPACE => return output_backspace(vm, args),
+        c => draw_char(vm, c),
+    }
+    vm.os.column += 1;
+    if vm.os.column == TEXT_COLUMNS {
+        output_println(vm, args)?;
+    }
+    Ok(0)
+}
+
+fn output_print_string(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    let length = vm.invoke("String.length", args)?;
+    for index in 0..length {
+        let c = vm.invoke("String.charAt", &[args[0], index])?;
+        output_print_char(vm, &[c])?;
+    }
+    Ok(0)
+}
+
+fn output_print_int(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    for digit in args[0].to_string().bytes() {
+        output_print_char(vm, &[digit as i16])?;
+    }
+    Ok(0)
+}
+
+fn output_println(vm: &mut Vm, _: &[i16]) -> Result<i16, Trap> {
+    vm.os.row = (vm.os.row + 1) % TEXT_ROWS;
+    vm.os.column = 0;
+    Ok(0)
+}
+
+/// Moves the cursor one column back, to the end of the previous line at
+/// the start of a line, and erases the character there.
+fn output_backspace(vm: &mut Vm, _: &[i16]) -> Result<i16, Trap> {
+    if vm.os.column > 0 {
+        vm.os.column -= 1;
+    } else if vm.os.row > 0 {
+        vm.os.row -= 1;
+        vm.os.column = TEXT_COLUMNS - 1;
+    }
+    draw_char(vm, b' ' as i16);
+    Ok(0)
+}
+
+fn screen_init(vm: &mut Vm, _: &[i16]) -> Result<i16, Trap> {
+    vm.os.color = true;
+    Ok(0)
+}
+
+fn screen_clear(vm: &mut Vm, _: &[i16]) -> Result<i16, Trap> {
+    vm.ram[SCREEN..KBD].fill(0);
+    Ok(0)
+}
+
+fn screen_set_color(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    vm.os.color = args[0] != 0;
+    Ok(0)
+}
+
+fn on_screen(x: i16, y: i16) -> bool {
+    (0..SCREEN_WIDTH).contains(&x) && (0..SCREEN_HEIGHT).contains(&y)
+}
+
+/// Sets a pixel known to be on screen to the current color.
+fn set_pixel(vm: &mut Vm, x: i16, y: i16) {
+    let word = &mut vm.ram[SCREEN + y as usize * 32 + x as usize / 16];
+    let mask = 1i16 << (x % 16);
+    if vm.os.color {
+        *word |= mask;
+    } else {
+        *word &= !mask;
+    }
+}
+
+fn screen_draw_pixel(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    let (x, y) = (args[0], args[1]);
+    if !on_screen(x, y) {
+        return error(vm, 7);
+    }
+    set_pixel(vm, x, y);
+    Ok(0)
+}
+
+fn screen_draw_line(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    let (x1, y1, x2, y2) = (args[0], args[1], args[2], args[3]);
+    if !on_screen(x1, y1) || !on_screen(x2, y2) {
+        return error(vm, 8);
+    }
+    // Bresenham's algorithm, stepping in x, y or both
+    let (dx, dy) = ((x2 - x1).abs(), -(y2 - y1).abs());
+    let (step_x, step_y) = ((x2 - x1).signum(), (y2 - y1).signum());
+    let (mut x, mut y, mut diff) = (x1, y1, dx + dy);
+    loop {
+        set_pixel(vm, x, y);
+        if x == x2 && y == y2 {
+            return Ok(0);
+        }
+        // Both tests use the error before this step
+        let double = 2 * diff;
+        if double >= dy {
+            diff += dy;
+            x += step_x;
+        }
+        if double <= dx {
+            diff += dx;
+            y += step_y;
+        }
+    }
+}
+
+fn screen_draw_rectangle(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    let (x1, y1, x2, y2) = (args[0], args[1], args[2], args[3]);
+    if !on_screen(x1, y1) || !on_screen(x2, y2) || x1 > x2 || y1 > y2 {
+        return error(vm, 9);
+    }
+    for y in y1..=y2 {
+        for x in x1..=x2 {
+            set_pixel(vm, x, y);
+        }
+    }
+    Ok(0)
+}
+
+/// Draws a filled circle, clipped to the screen.
+fn screen_draw_circle(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    let (cx, cy, r) = (args[0], args[1], args[2]);
+    if !on_screen(cx, cy) {
+        return error(vm, 12);
+    }
+    if !(0..=181).contains(&r) {
+        return error(vm, 13);
+    }
+    let r = r as i32;
+    for dy in -r..=r {
+        let dx = isqrt(r * r - dy * dy);
+        let y = cy as i32 + dy;
+        for x in (cx as i32 - dx)..=(cx as i32 + dx) {
+            if on_screen(x as i16, y as i16) {
+                set_pixel(vm, x as i16, y as i16);
+            }
+        }
+    }
+    Ok(0)
+}
+
+/// Waits until a key is pressed and released, and returns it. Each poll
+/// of the keyboard counts as a step.
+fn read_key(vm: &mut Vm) -> Result<i16, Trap> {
+    while vm.ram[KBD] == 0 {
+        vm.tick()?;
+    }
+    let key = vm.ram[KBD];
+    while vm.ram[KBD] != 0 {
+        vm.tick()?;
+    }
+    Ok(key)
+}
+
+fn keyboard_read_char(vm: &mut Vm, _: &[i16]) -> Result<i16, Trap> {
+    let key = read_key(vm)?;
+    vm.invoke("Output.printChar", &[key])?;
+    Ok(key)
+}
+
+fn keyboard_read_line(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    vm.invoke("Output.printString", args)?;
+    let line = vm.invoke("String.new", &[64])?;
+    loop {
+        match read_key(vm)? {
+            NEW_LINE => {
+                vm.invoke("Output.println", &[])?;
+                return Ok(line);
+            }
+            BACKSPACE => {
+                if vm.invoke("String.length", &[line])? > 0 {
+                    vm.invoke("String.eraseLastChar", &[line])?;
+                    vm.invoke("Output.backSpace", &[])?;
+                }
+            }
+            key => {
+                vm.invoke("String.appendChar", &[line, key])?;
+                vm.invoke("Output.printChar", &[key])?;
+            }
+        }
+    }
+}
+
+fn keyboard_read_int(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    let line = keyboard_read_line(vm, args)?;
+    let value = vm.invoke("String.intValue", &[line])?;
+    vm.invoke("String.dispose", &[line])?;
+    Ok(value)
+}
+
+/// Prints `ERR<code>` and stops the run.
+fn sys_error(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    for c in "ERR".bytes() {
+        vm.invoke("Output.printChar", &[c as i16])?;
+    }
+    vm.invoke("Output.printInt", args)?;
+    Err(Trap::Stop(Outcome::SysError(args[0])))
+}
+
+fn sys_wait(vm: &mut Vm, args: &[i16]) -> Result<i16, Trap> {
+    if args[0] < 0 {
+        return error(vm, 1);
+    }
+    for _ in 0..args[0] as u64 * vm.steps_per_ms {
+        vm.tick()?;
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::Program;
+
+    fn run(source: &str) -> (Vec<i16>, Outcome) {
+        let mut files = vec![VmFile::parse("Main", source)];
+        files.extend(bootstrap(&files));
+        let program = Program::link(&files).unwrap();
+        let mut vm = Vm::new(&program);
+        vm.start(Some("Sys.init")).unwrap();
+        let outcome = vm.run(100_000).unwrap();
+        (vm.ram, outcome)
+    }
+
+    #[test]
+    fn test_strings_and_output() {
+        let (ram, outcome) = run("function Main.main 1
+             push constant 2
+             call String.new 1
+             push constant 72
+             call String.appendChar 2
+             push constant 105
+             call String.appendChar 2
+             pop local 0
+             push local 0
+             call Output.printString 1
+             pop temp 0
+             push constant 1
+             neg
+             call Output.printInt 1
+             pop temp 0
+             push local 0
+             call String.length 1
+             pop static 0
+             push local 0
+             call String.dispose 1
+             pop temp 0
+             push constant 0
+             return");
+        assert_eq!(outcome, Outcome::Halted);
+        assert_eq!(ram[16], 2);
+        // "Hi" share the first screen word, "-1" the second
+        let top = |word: usize| -> Vec<i16> {
+            (0..11).map(|line| ram[SCREEN + line * 32 + word]).collect()
+        };
+        let expected = |left: u8, right: u8| -> Vec<i16> {
+            let (left, right) = (font::glyph(left as i16), font::glyph(right as i16));
+            (0..11)
+                .map(|line| left[line] as i16 | (right[line] as i16) << 8)
+                .collect()
+        };
+        assert_eq!(top(0), expected(b'H', b'i'));
+        assert_eq!(top(1), expected(b'-', b'1'));
+    }
+
+    #[test]
+    fn test_memory() {
+        let (ram, _) = run("function Main.main 0
+             push constant 5
+             call Array.new 1
+             pop static 0
+             push static 0
+             call Memory.deAlloc 1
+             pop temp 0
+             push constant 3
+             call Memory.alloc 1
+             pop static 1
+             push constant 14327
+             call Memory.alloc 1
+             pop static 2
+             push constant 0
+             return");
+        assert_eq!(ram[16], 2050);
+        // The freed block is reused, and the rest of the heap fits exactly
+        assert_eq!(ram[17], 2050);
+        assert_eq!(ram[18], 2057);
+    }
+
+    #[test]
+    fn test_corrupted_heap() {
+        // Faults instead of overflowing on bad addresses and block sizes
+        for source in [
+            "push constant 32767\nneg\ncall Memory.deAlloc 1",
+            "push constant 2048\npush constant 1\ncall Memory.poke 2\n\
+             push constant 2049\npush constant 32000\ncall Memory.poke 2\n\
+             push constant 32000\npush constant 32767\ncall Memory.poke 2\n\
+             push constant 1000\ncall Memory.alloc 1",
+        ] {
+            let mut files = vec![VmFile::parse(
+                "Main",
+                &format!("function Main.main 0\n{}\nreturn", source),
+            )];
+            files.extend(bootstrap(&files));
+            let program = Program::link(&files).unwrap();
+            let mut vm = Vm::new(&program);
+            vm.start(Some("Sys.init")).unwrap();
+            assert!(vm.run(100_000).is_err());
+        }
+    }
+
+    #[test]
+    fn test_draw_line() {
+        // Ran past (3, 2) and off the screen when the second test of a
+        // step used the error updated by the first
+        let (ram, outcome) = run("function Main.main 0
+             push constant 0
+             push constant 0
+             push constant 3
+             push constant 2
+             call Screen.drawLine 4
+             pop temp 0
+             push constant 0
+             return");
+        assert_eq!(outcome, Outcome::Halted);
+        let rows: Vec<i16> = (0..4).map(|row| ram[SCREEN + row * 32]).collect();
+        assert_eq!(rows, [0b1, 0b110, 0b1000, 0]);
+    }
+
+    #[test]
+    fn test_errors_and_screen() {
+        let (ram, outcome) = run("function Main.main 0
+             push constant 0
+             push constant 100
+             push constant 15
+             push constant 100
+             call Screen.drawLine 4
+             pop temp 0
+             push constant 1
+             push constant 0
+             call Math.divide 2
+             return");
+        assert_eq!(outcome, Outcome::SysError(3));
+        assert_eq!(ram[SCREEN + 3200], -1);
+        assert_eq!(ram[SCREEN + 3201], 0);
+    }
+
+    #[test]
+    fn test_vm_definitions_take_precedence() {
+        let (ram, _) = run("function Main.main 0
+             push constant 5
+             call Array.new 1
+             pop static 0
+             push constant 7
+             call Math.sqrt 1
+             pop static 1
+             push constant 0
+             return
+             function Memory.alloc 0
+             push constant 3000
+             return");
+        // The native Array.new allocates through the VM's Memory.alloc
+        assert_eq!(ram[16], 3000);
+        assert_eq!(ram[17], 2);
+
+        let mut files = vec![VmFile::parse(
+            "Main",
+            "function Main.main 0\ncall Keyboard.readChar 0\nreturn",
+        )];
+        files.extend(bootstrap(&files));
+        let program = Program::link(&files).unwrap();
+        let mut vm = Vm::new(&program);
+        vm.start(Some("Sys.init")).unwrap();
+        assert_eq!(vm.run(500).unwrap(), Outcome::StepLimit);
+        assert_eq!(vm.steps, 500);
+    }
+}
@@ -0,0 +1,337 @@
+//! Linking of parsed VM files into an executable program.
+//!
+//! Labels, function names and static variables are resolved once at load
+//! time, so that execution only deals with instruction indices and RAM
+//! addresses.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use vm_translator::parser::{Command, Op, Segment, VmCommand};
+use vm_translator::program::{self, VmFile};
+
+/// First RAM address of the static variables
+pub const STATIC_BASE: u16 = 16;
+/// Last RAM address available to static variables
+pub const STATIC_END: u16 = 255;
+/// First RAM address of the temp segment
+pub const TEMP_BASE: u16 = 5;
+
+/// Where a `push` reads or a `pop` writes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Constant(i16),
+    /// A fixed RAM address: temp, static and pointer
+    Address(u16),
+    /// RAM[RAM[register] + index]: local, argument, this and that
+    Indirect {
+        register: u16,
+        index: u16,
+    },
+}
+
+/// The function a `call` jumps to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Callee {
+    /// Index of the function's `function` instruction
+    Function(usize),
+    /// A function not defined by the program, an error when called
+    Undefined(String),
+}
+
+/// A linked VM command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    Arithmetic(Op),
+    Push(Operand),
+    Pop(Operand),
+    Label,
+    Goto(usize),
+    IfGoto(usize),
+    Function { n_locals: u16 },
+    Call { callee: Callee, n_args: u16 },
+    Return,
+}
+
+/// A problem found while linking, located at a VM command.
+#[derive(Debug, PartialEq)]
+pub struct LoadError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.vm:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+/// The instructions of all the files of a program, in file order.
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    /// The source command of each instruction
+    pub commands: Vec<Command>,
+    /// File name of each instruction
+    files: Vec<usize>,
+    file_names: Vec<String>,
+    /// Functions in program order, with the index of their `function`
+    functions: Vec<(String, usize)>,
+}
+
+impl Program {
+    /// Links the files of a program. Statics are allocated per file in
+    /// load order, each file taking as many words as its highest index;
+    /// those past `STATIC_END` are errors.
+    pub fn link(vm_files: &[VmFile]) -> Result<Self, Vec<LoadError>> {
+        let mut errors = Vec::new();
+        let mut functions = Vec::new();
+        let mut labels = HashMap::new();
+        let mut files = Vec::new();
+        let mut commands = Vec::new();
+
+        // First pass: function and label positions, static segment sizes
+        let mut static_bases: HashMap<&str, usize> = HashMap::new();
+        let mut next_static = STATIC_BASE as usize;
+        for (file_index, file) in vm_files.iter().enumerate() {
+            let statics = file
+                .commands
+                .iter()
+                .filter_map(|command| match command.command {
+                    VmCommand::Push {
+                        segment: Segment::Static,
+                        index,
+                    }
+                    | VmCommand::Pop {
+                        segment: Segment::Static,
+                        index,
+                    } => Some(index as usize + 1),
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(0);
+            static_bases.insert(&file.name, next_static);
+            next_static += statics;
+
+            let mut function = String::new();
+            for command in &file.commands {
+                let pc = commands.len();
+                match &command.command {
+                    VmCommand::Function { name, .. } => {
+                        function = name.clone();
+                        functions.push((name.clone(), pc));
+                    }
+                    VmCommand::Label(label) => {
+                        labels.insert((function.clone(), label.clone()), pc);
+                    }
+                    _ => {}
+                }
+                files.push(file_index);
+                commands.push(command.clone());
+            }
+        }
+        // Second pass: resolve every operand and jump target
+        let function_index: HashMap<&str, usize> = functions
+            .iter()
+            .map(|(name, pc)| (name.as_str(), *pc))
+            .collect();
+        let mut instructions = Vec::with_capacity(commands.len());
+        let mut function = "";
+        for (pc, command) in commands.iter().enumerate() {
+            let file = &vm_files[files[pc]];
+            let static_file = command.file.as_deref().unwrap_or(&file.name);
+            let error = |message: String| LoadError {
+                file: file.name.clone(),
+                line: command.line,
+                message,
+            };
+            let jump = |label: &str| {
+                labels
+                    .get(&(function.to_string(), label.to_string()))
+                    .copied()
+                    .ok_or_else(|| error(format!("label {} is not defined in {}", label, function)))
+            };
+            let operand = |segment, index| {
+                let static_base = static_bases.get(static_file).copied().unwrap_or(0);
+                operand(segment, index, static_base).map_err(error)
+            };
+
+            let instruction = match &command.command {
+                VmCommand::Arithmetic(op) => Ok(Instruction::Arithmetic(*op)),
+                VmCommand::Push { segment, index } => {
+                    operand(*segment, *index).map(Instruction::Push)
+                }
+                VmCommand::Pop { segment, index } => {
+                    operand(*segment, *index).map(Instruction::Pop)
+                }
+                VmCommand::Label(_) => Ok(Instruction::Label),
+                VmCommand::Goto(label) => jump(label).map(Instruction::Goto),
+                VmCommand::IfGoto(label) => jump(label).map(Instruction::IfGoto),
+                VmCommand::Function { name, n_locals } => {
+                    function = name;
+                    Ok(Instruction::Function {
+                        n_locals: *n_locals,
+                    })
+                }
+                VmCommand::Call { name, n_args } => {
+                    let callee = match function_index.get(name.as_str()) {
+                        Some(&pc) => Callee::Function(pc),
+                        None => Callee::Undefined(name.clone()),
+                    };
+                    Ok(Instruction::Call {
+                        callee,
+                        n_args: *n_args,
+                    })
+                }
+                VmCommand::Return => Ok(Instruction::Return),
+            };
+            match instruction {
+                Ok(instruction) => instructions.push(instruction),
+                Err(error) => errors.push(error),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Self {
+            instructions,
+            commands,
+            files,
+            file_names: vm_files.iter().map(|file| file.name.clone()).collect(),
+            functions,
+        })
+    }
+
+    /// Returns the index of the `function` instruction of `name`.
+    pub fn function(&self, name: &str) -> Option<usize> {
+        self.functions
+            .iter()
+            .find(|(function, _)| function == name)
+            .map(|(_, pc)| *pc)
+    }
+
+    /// Returns the name of the function containing instruction `pc`.
+    pub fn function_at(&self, pc: usize) -> Option<&str> {
+        let position = self.functions.partition_point(|(_, start)| *start <= pc);
+        let (name, start) = self.functions.get(position.checked_sub(1)?)?;
+        // Functions never span files
+        (self.files[*start] == self.files[pc]).then_some(name.as_str())
+    }
+
+    /// Returns the file name and line of instruction `pc`.
+    pub fn source(&self, pc: usize) -> (&str, usize) {
+        (&self.file_names[self.files[pc]], self.commands[pc].line)
+    }
+}
+
+/// Reads the files of a program: a single file, or the files of a
+/// directory chosen like the translator does, its .vm files or else its
+/// .vmb files.
+pub fn read_files(input: &Path) -> Result<Vec<VmFile>, String> {
+    let paths = if input.is_file() {
+        vec![input.to_path_buf()]
+    } else if input.is_dir() {
+        program::directory_files(input)?
+    } else {
+        return Err(format!(
+            "{} is neither a file nor a directory",
+            input.display()
+        ));
+    };
+    paths.iter().map(|path| VmFile::read(path)).collect()
+}
+
+/// Resolves a segment access to an operand.
+fn operand(segment: Segment, index: u16, static_base: usize) -> Result<Operand, String> {
+    let operand = match segment {
+        Segment::Constant => Operand::Constant(index as i16),
+        Segment::Local => Operand::Indirect { register: 1, index },
+        Segment::Argument => Operand::Indirect { register: 2, index },
+        Segment::This => Operand::Indirect { register: 3, index },
+        Segment::That => Operand::Indirect { register: 4, index },
+        Segment::Pointer if index < 2 => Operand::Address(3 + index),
+        Segment::Temp if index < 8 => Operand::Address(TEMP_BASE + index),
+        Segment::Static => {
+            let address = static_base + index as usize;
+            if address > STATIC_END as usize {
+                return Err(format!(
+                    "static {} at RAM[{}] is past the static segment RAM[{}-{}]",
+                    index, address, STATIC_BASE, STATIC_END
+                ));
+            }
+            Operand::Address(address as u16)
+        }
+        Segment::Pointer | Segment::Temp => {
+            return Err(format!("{} index {} is out of range", segment, index));
+        }
+    };
+    Ok(operand)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link() {
+        let files = vec![
+            VmFile::parse(
+                "Main",
+                "function Main.main 0\npush static 2\nlabel L\ngoto L\ncall Main.f 0\ncall Output.print 1",
+            ),
+            VmFile::parse("Other", "function Main.f 0\npop static 0\nreturn"),
+        ];
+        let program = Program::link(&files).unwrap();
+        assert_eq!(
+            program.instructions[1],
+            Instruction::Push(Operand::Address(18))
+        );
+        assert_eq!(program.instructions[3], Instruction::Goto(2));
+        assert_eq!(
+            program.instructions[4],
+            Instruction::Call {
+                callee: Callee::Function(6),
+                n_args: 0
+            }
+        );
+        assert_eq!(
+            program.instructions[5],
+            Instruction::Call {
+                callee: Callee::Undefined("Output.print".to_string()),
+                n_args: 1
+            }
+        );
+        // Main uses static 0-2, so Other's statics start at 19
+        assert_eq!(
+            program.instructions[7],
+            Instruction::Pop(Operand::Address(19))
+        );
+        assert_eq!(program.function_at(3), Some("Main.main"));
+        assert_eq!(program.source(7), ("Other", 2));
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let files = vec![VmFile::parse(
+            "Main",
+            "function Main.main 0\ngoto MISSING\npush temp 8\npop pointer 2\npush static 65530",
+        )];
+        let messages: Vec<String> = Program::link(&files)
+            .err()
+            .unwrap()
+            .iter()
+            .map(|error| error.to_string())
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "Main.vm:2: label MISSING is not defined in Main.main",
+                "Main.vm:3: temp index 8 is out of range",
+                "Main.vm:4: pointer index 2 is out of range",
+                "Main.vm:5: static 65530 at RAM[65546] is past the static segment RAM[16-255]",
+            ]
+        );
+    }
+}
@@ -0,0 +1,555 @@
+//! Execution of linked VM programs.
+//!
+//! The stack, the frames and the segments all live in RAM with the same
+//! layout as translated code, so a run can be compared word for word with
+//! the CPU emulator. The VM also keeps the list of active functions on the
+//! side, which is only used to report where an error happened.
+//!
+//! Calls to functions the program does not define go to the native OS in
+//! `os`. A native function may in turn call back into VM code, which runs
+//! nested inside the `call` command that started it.
+
+use std::fmt;
+
+use crate::os::{self, OsState};
+use crate::program::{Callee, Instruction, Operand, Program};
+
+pub const RAM_SIZE: usize = 32768;
+pub const SP: usize = 0;
+pub const LCL: usize = 1;
+pub const ARG: usize = 2;
+pub const THIS: usize = 3;
+pub const THAT: usize = 4;
+/// Initial stack pointer set before calling the entry function
+pub const STACK_BASE: i16 = 256;
+
+/// Return address pushed by the call to the entry function. Returning to
+/// it ends the run.
+const ENTRY_RETURN: i16 = -1;
+/// Return address pushed when a native function calls VM code
+const NATIVE_RETURN: i16 = -2;
+
+/// Why a run stopped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// A `goto` jumped back onto itself, the usual end of `Sys.halt`, or
+    /// the native `Sys.halt` was called
+    Halted,
+    /// The entry function returned
+    Returned,
+    /// The native `Sys.error` was called with this error code
+    SysError(i16),
+    /// The step limit was reached
+    StepLimit,
+    /// Execution ran past the last command
+    End,
+}
+
+/// A runtime error, located at the command that caused it.
+#[derive(Debug)]
+pub struct VmError {
+    pub file: String,
+    pub line: usize,
+    pub function: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.vm:{}", self.file, self.line)?;
+        if let Some(function) = &self.function {
+            write!(f, " ({})", function)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Interrupts the execution of a command.
+pub(crate) enum Trap {
+    /// An error not yet located at a command
+    Fault(String),
+    /// An error raised by a nested command, already located
+    Error(VmError),
+    Stop(Outcome),
+}
+
+impl From<String> for Trap {
+    fn from(message: String) -> Self {
+        Trap::Fault(message)
+    }
+}
+
+/// A function called after each step, see `Vm::observe`.
+type Observer<'a> = dyn FnMut(u64, &mut [i16]) + 'a;
+
+pub struct Vm<'a> {
+    program: &'a Program,
+    pub ram: Vec<i16>,
+    /// Index of the next instruction
+    pub pc: usize,
+    /// Number of commands executed so far
+    pub steps: u64,
+    /// Whether undefined OS functions are provided natively
+    pub native_os: bool,
+    /// Steps the native `Sys.wait` spends per millisecond
+    pub steps_per_ms: u64,
+    pub(crate) os: OsState,
+    /// Step limit of the current run, also checked by nested calls
+    max_steps: u64,
+    /// `function` instruction of each active call, innermost last
+    frames: Vec<usize>,
+    /// Set once a native `Sys.halt` or `Sys.error` has stopped the program
+    stopped: Option<Outcome>,
+    /// Called with the step count and the RAM after each step
+    observer: Option<Box<Observer<'a>>>,
+    /// Last step passed to the observer: a native call counts its own
+    /// steps before the step of its `call` command ends
+    observed: u64,
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(program: &'a Program) -> Self {
+        Self {
+            program,
+            ram: vec![0; RAM_SIZE],
+            pc: 0,
+            steps: 0,
+            native_os: true,
+            steps_per_ms: 0,
+            os: OsState::default(),
+            max_steps: u64::MAX,
+            frames: Vec::new(),
+            stopped: None,
+            observer: None,
+            observed: 0,
+        }
+    }
+
+    /// Sets a function to call with the step count and the RAM after each
+    /// step, including the steps counted while native functions wait, e.g.
+    /// to capture the screen or to press keys.
+    pub fn observe(&mut self, observer: impl FnMut(u64, &mut [i16]) + 'a) {
+        self.observer = Some(Box::new(observer));
+    }
+
+    fn notify(&mut self) {
+        if self.observed == self.steps {
+            return;
+        }
+        self.observed = self.steps;
+        if let Some(observer) = &mut self.observer {
+            observer(self.steps, &mut self.ram);
+        }
+    }
+
+    /// Prepares a run. With an entry function, sets SP to 256 and calls it
+    /// like the bootstrap code does; without one, starts at the first
+    /// command with the registers as they are.
+    pub fn start(&mut self, entry: Option<&str>) -> Result<(), String> {
+        let Some(entry) = entry else {
+            self.pc = 0;
+            return Ok(());
+        };
+        let target = self
+            .program
+            .function(entry)
+            .ok_or_else(|| format!("entry function {} is not defined", entry))?;
+        self.ram[SP] = STACK_BASE;
+        self.call(target, 0, ENTRY_RETURN)
+            .map_err(|message| format!("cannot call {}: {}", entry, message))
+    }
+
+    /// Prepares a run that starts at the first command of function `name`
+    /// without calling it, with the registers as they are: the official VM
+    /// emulator's behavior when a script loads a program with `Sys.init`.
+    pub fn start_at(&mut self, name: &str) -> Result<(), String> {
+        let target = self
+            .program
+            .function(name)
+            .ok_or_else(|| format!("function {} is not defined", name))?;
+        self.frames = vec![target];
+        self.pc = target;
+        Ok(())
+    }
+
+    /// Runs until the program stops or `max_steps` commands have executed.
+    pub fn run(&mut self, max_steps: u64) -> Result<Outcome, VmError> {
+        self.max_steps = max_steps;
+        while self.steps < max_steps {
+            if let Some(outcome) = self.step()? {
+                return Ok(outcome);
+            }
+        }
+        Ok(Outcome::StepLimit)
+    }
+
+    /// Executes one command. Returns the outcome if the program stopped.
+    pub fn step(&mut self) -> Result<Option<Outcome>, VmError> {
+        if let Some(outcome) = self.stopped {
+            return Ok(Some(outcome));
+        }
+        match self.advance() {
+            Ok(()) => Ok(None),
+            Err(Trap::Stop(outcome)) => {
+                // Unlike a halting loop, the native functions do not keep
+                // the program in place, so later steps must not go on
+                if let Outcome::Halted | Outcome::SysError(_) = outcome {
+                    self.stopped = Some(outcome);
+                }
+                Ok(Some(outcome))
+            }
+            Err(Trap::Error(error)) => Err(error),
+            Err(Trap::Fault(_)) => unreachable!("faults are located by advance"),
+        }
+    }
+
+    fn advance(&mut self) -> Result<(), Trap> {
+        // Labels are not commands of their own, as in the official emulator
+        while self.program.instructions.get(self.pc) == Some(&Instruction::Label) {
+            self.pc += 1;
+        }
+        let pc = self.pc;
+        let Some(instruction) = self.program.instructions.get(pc) else {
+            return Err(Trap::Stop(Outcome::End));
+        };
+        self.steps += 1;
+        self.pc += 1;
+        let result = self.execute(instruction);
+        self.notify();
+        result.map_err(|trap| match trap {
+            Trap::Fault(message) => Trap::Error(self.error(pc, message)),
+            trap => trap,
+        })
+    }
+
+    /// Counts a step spent waiting inside a native function, stopping at
+    /// the step limit.
+    pub(crate) fn tick(&mut self) -> Result<(), Trap> {
+        if self.steps >= self.max_steps {
+            return Err(Trap::Stop(Outcome::StepLimit));
+        }
+        self.steps += 1;
+        self.notify();
+        Ok(())
+    }
+
+    fn execute(&mut self, instruction: &Instruction) -> Result<(), Trap> {
+        match instruction {
+            Instruction::Arithmetic(op) => {
+                let y = self.pop()?;
+                let result = if op.is_unary() {
+                    op.eval(0, y)
+                } else {
+                    let x = self.pop()?;
+                    op.eval(x, y)
+                };
+                self.push(result)?;
+            }
+            Instruction::Push(operand) => {
+                let value = match *operand {
+                    Operand::Constant(value) => value,
+                    _ => self.read(self.address(operand)?)?,
+                };
+                self.push(value)?;
+            }
+            Instruction::Pop(operand) => {
+                let address = self.address(operand)?;
+                let value = self.pop()?;
+                self.write(address, value)?;
+            }
+            Instruction::Label => {}
+            Instruction::Goto(target) => self.jump(*target)?,
+            Instruction::IfGoto(target) => {
+                if self.pop()? != 0 {
+                    self.jump(*target)?;
+                }
+            }
+            Instruction::Function { n_locals } => {
+                for _ in 0..*n_locals {
+                    self.push(0)?;
+                }
+            }
+            Instruction::Call { callee, n_args } => match callee {
+                Callee::Function(target) => {
+                    let return_address = i16::try_from(self.pc)
+                        .map_err(|_| "return address does not fit in a word".to_string())?;
+                    self.call(*target, *n_args, return_address)?;
+                }
+                Callee::Undefined(name) => {
+                    let mut args = vec![0; *n_args as usize];
+                    for arg in args.iter_mut().rev() {
+                        *arg = self.pop()?;
+                    }
+                    let result = self.call_native(name, &args)?;
+                    self.push(result)?;
+                }
+            },
+            Instruction::Return => self.ret()?,
+        }
+        Ok(())
+    }
+
+    /// Calls function `name` from native code and returns its result. VM
+    /// definitions take precedence over native ones.
+    pub(crate) fn invoke(&mut self, name: &str, args: &[i16]) -> Result<i16, Trap> {
+        let Some(target) = self.program.function(name) else {
+            return self.call_native(name, args);
+        };
+        for &arg in args {
+            self.push(arg)?;
+        }
+        let resume = self.pc;
+        let depth = self.frames.len();
+        self.call(target, args.len() as u16, NATIVE_RETURN)?;
+        while self.frames.len() > depth {
+            if self.steps >= self.max_steps {
+                return Err(Trap::Stop(Outcome::StepLimit));
+            }
+            self.advance()?;
+        }
+        self.pc = resume;
+        Ok(self.pop()?)
+    }
+
+    fn call_native(&mut self, name: &str, args: &[i16]) -> Result<i16, Trap> {
+        let native = os::find(name)
+            .filter(|_| self.native_os)
+            .ok_or_else(|| format!("function {} is not defined", name))?;
+        if args.len() != native.n_args {
+            return Err(Trap::Fault(format!(
+                "{} expects {} argument(s), found {}",
+                name,
+                native.n_args,
+                args.len()
+            )));
+        }
+        (native.run)(self, args).map_err(|trap| match trap {
+            Trap::Fault(message) => Trap::Fault(format!("{}: {}", name, message)),
+            trap => trap,
+        })
+    }
+
+    /// Jumps to `target`, detecting a jump back onto the same `goto`.
+    fn jump(&mut self, target: usize) -> Result<(), Trap> {
+        let current = self.pc - 1;
+        self.pc = target;
+        let instructions = &self.program.instructions;
+        let is_loop = target <= current
+            && instructions[target..current]
+                .iter()
+                .all(|instruction| *instruction == Instruction::Label);
+        if is_loop {
+            return Err(Trap::Stop(Outcome::Halted));
+        }
+        Ok(())
+    }
+
+    /// Saves the caller's frame and jumps to the `function` at `target`.
+    fn call(&mut self, target: usize, n_args: u16, return_address: i16) -> Result<(), String> {
+        self.push(return_address)?;
+        for register in [LCL, ARG, THIS, THAT] {
+            self.push(self.ram[register])?;
+        }
+        let sp = self.ram[SP];
+        self.ram[ARG] = sp.wrapping_sub(5).wrapping_sub(n_args as i16);
+        self.ram[LCL] = sp;
+        self.frames.push(target);
+        self.pc = target;
+        Ok(())
+    }
+
+    /// Restores the caller's frame, leaving the return value on its stack.
+    fn ret(&mut self) -> Result<(), Trap> {
+        let frame = self.ram[LCL] as u16 as usize;
+        let saved = |offset: usize| {
+            frame
+                .checked_sub(offset)
+                .ok_or_else(|| format!("invalid frame at LCL = {}", frame))
+        };
+        let return_address = self.read(saved(5)?)?;
+        let value = self.pop()?;
+        let arg = self.ram[ARG];
+        self.write(arg as u16 as usize, value)?;
+        self.ram[SP] = arg.wrapping_add(1);
+        for (register, offset) in [(THAT, 1), (THIS, 2), (ARG, 3), (LCL, 4)] {
+            self.ram[register] = self.read(saved(offset)?)?;
+        }
+        self.frames.pop();
+
+        match return_address {
+            ENTRY_RETURN => Err(Trap::Stop(Outcome::Returned)),
+            // The native caller resumes once its call is popped
+            NATIVE_RETURN => Ok(()),
+            _ => {
+                self.pc = return_address as u16 as usize;
+                Ok(())
+            }
+        }
+    }
+
+    fn address(&self, operand: &Operand) -> Result<usize, String> {
+        match *operand {
+            Operand::Constant(_) => Err("cannot pop to constant".to_string()),
+            Operand::Address(address) => Ok(address as usize),
+            Operand::Indirect { register, index } => {
+                Ok(self.ram[register as usize] as u16 as usize + index as usize)
+            }
+        }
+    }
+
+    pub(crate) fn read(&self, address: usize) -> Result<i16, String> {
+        self.ram
+            .get(address)
+            .copied()
+            .ok_or_else(|| format!("read from RAM[{}] is out of range", address))
+    }
+
+    pub(crate) fn write(&mut self, address: usize, value: i16) -> Result<(), String> {
+        let word = self
+            .ram
+            .get_mut(address)
+            .ok_or_else(|| format!("write to RAM[{}] is out of range", address))?;
+        *word = value;
+        Ok(())
+    }
+    fn push(&mut self, value: i16) -> Result<(), String> {
+        let sp = self.ram[SP] as u16 as usize;
+        // SP itself must stay a valid address
+        if sp + 1 >= RAM_SIZE {
+            return Err(format!("stack overflow (SP = {})", sp));
+        }
+        self.ram[sp] = value;
+        self.ram[SP] += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i16, String> {
+        let sp = self.ram[SP] as u16 as usize;
+        if sp == 0 || sp > RAM_SIZE {
+            return Err(format!("stack underflow (SP = {})", sp));
+        }
+        self.ram[SP] -= 1;
+        Ok(self.ram[sp - 1])
+    }
+
+    /// Returns the active functions, innermost first.
+    pub fn backtrace(&self) -> Vec<&str> {
+        self.frames
+            .iter()
+            .rev()
+            .filter_map(|&target| self.program.function_at(target))
+            .collect()
+    }
+
+    fn error(&self, pc: usize, message: String) -> VmError {
+        let (file, line) = self.program.source(pc);
+        VmError {
+            file: file.to_string(),
+            line,
+            function: self.program.function_at(pc).map(str::to_string),
+            message,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_translator::program::VmFile;
+
+    fn program(source: &str) -> Program {
+        Program::link(&[VmFile::parse("Main", source)]).unwrap()
+    }
+
+    #[test]
+    fn test_call_and_return() {
+        let program = program(
+            "function Main.main 1
+             push constant 7
+             push constant 5
+             call Main.sub 2
+             pop local 0
+             push local 0
+             return
+             function Main.sub 0
+             push argument 0
+             push argument 1
+             sub
+             return",
+        );
+        let mut vm = Vm::new(&program);
+        vm.start(Some("Main.main")).unwrap();
+        assert_eq!(vm.run(1000).unwrap(), Outcome::Returned);
+        // The entry frame is popped like any other, leaving its result
+        assert_eq!(vm.ram[SP], 257);
+        assert_eq!(vm.ram[256], 2);
+        assert_eq!(vm.steps, 12);
+    }
+
+    #[test]
+    fn test_halt_and_step_limit() {
+        let program = program(
+            "function Main.main 0
+             push constant 3
+             pop static 0
+             label LOOP
+             push static 0
+             push constant 1
+             sub
+             pop static 0
+             push static 0
+             if-goto LOOP
+             label END
+             goto END",
+        );
+        let mut vm = Vm::new(&program);
+        vm.start(Some("Main.main")).unwrap();
+        assert_eq!(vm.run(10).unwrap(), Outcome::StepLimit);
+        assert_eq!(vm.run(1000).unwrap(), Outcome::Halted);
+        assert_eq!(vm.ram[16], 0);
+    }
+
+    #[test]
+    fn test_observe() {
+        let program = program(
+            "function Main.main 0
+             push constant 1
+             push static 0
+             add
+             return",
+        );
+        let mut steps = Vec::new();
+        let mut vm = Vm::new(&program);
+        vm.observe(|step, ram| {
+            steps.push(step);
+            // Changes to the RAM are seen by the next command
+            ram[16] = 41;
+        });
+        vm.start(Some("Main.main")).unwrap();
+        assert_eq!(vm.run(100).unwrap(), Outcome::Returned);
+        assert_eq!(vm.ram[256], 42);
+        drop(vm);
+        assert_eq!(steps, [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let program = program("function Main.main 0\npop local 0\nreturn");
+        let mut vm = Vm::new(&program);
+        vm.start(Some("Main.main")).unwrap();
+        vm.ram[SP] = 0;
+        let error = vm.run(10).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Main.vm:2 (Main.main): stack underflow (SP = 0)"
+        );
+        assert_eq!(vm.backtrace(), ["Main.main"]);
+
+        let program = self::program("function Main.main 0\ncall Main.missing 0");
+        let mut vm = Vm::new(&program);
+        assert!(vm.start(Some("Sys.init")).is_err());
+        vm.start(Some("Main.main")).unwrap();
+        let error = vm.run(10).unwrap_err();
+        assert_eq!(error.message, "function Main.missing is not defined");
+    }
+}
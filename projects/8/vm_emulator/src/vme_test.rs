@@ -0,0 +1,184 @@
+//! Runs the VM emulator test scripts (`*VME.tst`) of projects 7 and 8.
+//!
+//! Supported commands are `load`, `output-file`, `compare-to`,
+//! `output-list`, `output`, `set`, `vmstep`, `echo` and `clear-echo`,
+//! with `repeat n { ... }` blocks. Scripts are checked against their
+//! `.cmp` file as each row is output, stopping at the first difference.
+
+use std::path::Path;
+
+use cpu_emulator::script::{self, Statement, Target};
+
+use crate::os;
+use crate::program::{self, Program, TEMP_BASE};
+use crate::vm::{self, Vm};
+
+/// Runs a test script. Files named by the script are relative to its
+/// directory, and the output table is written to its `output-file`.
+pub fn run(path: &Path) -> Result<(), String> {
+    let statements = script::read(path)?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let located = |line: usize, message: String| format!("{}:{}: {}", name, line, message);
+    // A bare file name has an empty parent
+    let directory = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+
+    // The program is loaded up front since the VM borrows it
+    let (load, line) = first_load(&statements)
+        .ok_or_else(|| format!("{}: the script does not load a program", name))?;
+    let input = match load.get(1) {
+        Some(file) => directory.join(file),
+        None => directory.to_path_buf(),
+    };
+    let mut files = program::read_files(&input).map_err(|message| located(line, message))?;
+    files.extend(os::bootstrap(&files));
+    let program = Program::link(&files).map_err(|errors| {
+        let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        located(line, messages.join("\n"))
+    })?;
+
+    let mut vm = Vm::new(&program);
+    if program.function("Sys.init").is_some() {
+        // The stack starts at 256 unless the script sets SP, as the
+        // projects/8 scripts do to mimic the bootstrap's call
+        vm.ram[vm::SP] = vm::STACK_BASE;
+        vm.start_at("Sys.init")
+            .map_err(|message| located(line, message))?;
+    }
+    script::run(path, &statements, &mut Emulator { load, vm })
+}
+
+/// Returns the words and line of the script's first `load` command.
+fn first_load(statements: &[Statement]) -> Option<(&[String], usize)> {
+    statements.iter().find_map(|statement| match statement {
+        Statement::Command { words, line } if words[0] == "load" => Some((&words[..], *line)),
+        Statement::Command { .. } => None,
+        Statement::Repeat { body, .. } => first_load(body),
+    })
+}
+
+struct Emulator<'a, 'p> {
+    load: &'a [String],
+    vm: Vm<'p>,
+}
+
+impl Target for Emulator<'_, '_> {
+    fn command(&mut self, words: &[String], _directory: &Path) -> Result<bool, String> {
+        match words[0].as_str() {
+            "load" if words == self.load => {}
+            "load" => return Err("only one program can be loaded".to_string()),
+            "vmstep" => {
+                // Halting and running past the end leave the program in place
+                self.vm.step().map_err(|error| error.to_string())?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn get(&self, variable: &str) -> Result<i16, String> {
+        Ok(self.vm.ram[self.address(variable)?])
+    }
+
+    fn set(&mut self, variable: &str, value: i16) -> Result<(), String> {
+        let address = self.address(variable)?;
+        self.vm.ram[address] = value;
+        Ok(())
+    }
+}
+
+impl Emulator<'_, '_> {
+    /// Returns the RAM address of a script variable: a register, `RAM[i]`,
+    /// or an entry of the local, argument, this, that or temp segment.
+    fn address(&self, variable: &str) -> Result<usize, String> {
+        let unknown = || format!("unknown variable: {}", variable);
+        let registers = [
+            ("sp", vm::SP),
+            ("local", vm::LCL),
+            ("argument", vm::ARG),
+            ("this", vm::THIS),
+            ("that", vm::THAT),
+        ];
+        let Some((name, index)) = script::indexed(variable) else {
+            let (_, register) = registers
+                .iter()
+                .find(|(name, _)| *name == variable)
+                .ok_or_else(unknown)?;
+            return Ok(*register);
+        };
+        let address = match name {
+            "RAM" => index,
+            "temp" if index < 8 => TEMP_BASE as usize + index,
+            _ => {
+                let (_, register) = registers
+                    .iter()
+                    .find(|(register, _)| *register == name && name != "sp")
+                    .ok_or_else(unknown)?;
+                self.vm.ram[*register] as u16 as usize + index
+            }
+        };
+        if address >= vm::RAM_SIZE {
+            return Err(format!("{} is out of range", variable));
+        }
+        Ok(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_run() {
+        let directory = std::env::temp_dir().join(format!("vme_test_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(
+            directory.join("Add.vm"),
+            "push constant 7\npush constant 8\nadd\npush argument 1\npop local 0",
+        )
+        .unwrap();
+        fs::write(
+            directory.join("Add.cmp"),
+            "|  RAM[0]  | RAM[256] |local[0]|\n|     257  |      15  |     42 |\n",
+        )
+        .unwrap();
+        let script = "load Add.vm, output-file Add.out, compare-to Add.cmp,
+                      set sp 256, set local 300, set argument 400, set argument[1] 42,
+                      repeat 3 { vmstep; }
+                      output-list RAM[0]%D2.6.2 RAM[256]%D2.6.2 local[0];";
+        fs::write(
+            directory.join("Add.tst"),
+            format!("{} vmstep, vmstep, output;", script),
+        )
+        .unwrap();
+        assert_eq!(run(&directory.join("Add.tst")), Ok(()));
+        assert_eq!(
+            fs::read_to_string(directory.join("Add.out")).unwrap(),
+            fs::read_to_string(directory.join("Add.cmp")).unwrap()
+        );
+
+        fs::write(directory.join("Add.tst"), format!("{} output;", script)).unwrap();
+        assert_eq!(
+            run(&directory.join("Add.tst")),
+            Err("Add.tst:4: comparison failure at line 2, column 3 (local[0]): expected \"42\", found \"0\"".to_string())
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_run_os_test() {
+        // A projects/12 script, run on the compiled test and the native OS
+        let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../12/MemoryTest");
+        let directory = std::env::temp_dir().join(format!("vme_os_test_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        for file in ["MemoryTest.tst", "MemoryTest.cmp", "output/Main.vm"] {
+            let name = Path::new(file).file_name().unwrap();
+            fs::copy(source.join(file), directory.join(name)).unwrap();
+        }
+        assert_eq!(run(&directory.join("MemoryTest.tst")), Ok(()));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,498 @@
+//! Static call-graph and stack-depth analysis of a VM program.
+//!
+//! The maximum operand-stack depth of each function is computed by abstract
+//! interpretation over its control flow. Combined with the call graph, this
+//! gives the worst-case stack usage of every function, including the frames
+//! of everything it calls, unless recursion makes it unbounded.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+
+use crate::code_writer::STACK_BASE;
+use crate::parser::{Command, VmCommand};
+use crate::program::{ControlFlow, VmFile};
+
+/// Base address of the heap, right after the stack
+pub const HEAP_BASE: usize = 2048;
+/// Words pushed by `call`: return address, LCL, ARG, THIS and THAT
+const FRAME_SIZE: usize = 5;
+
+/// A `call` command and the operand-stack depth just before it,
+/// arguments included.
+pub struct CallSite {
+    pub callee: String,
+    pub depth: usize,
+}
+
+pub struct FunctionInfo {
+    pub file: String,
+    pub num_locals: usize,
+    /// Maximum operand-stack depth, or `None` if a loop keeps growing it
+    pub max_depth: Option<usize>,
+    pub calls: Vec<CallSite>,
+}
+
+/// Worst-case stack usage of a function, in words, counting its locals,
+/// its operand stack and the frames of all functions it may call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackUsage {
+    Bounded(usize),
+    /// The function is recursive or may call a recursive function
+    Recursive,
+    /// The function's operand stack grows inside a loop
+    UnboundedDepth,
+}
+
+pub struct Analysis {
+    pub functions: BTreeMap<String, FunctionInfo>,
+    /// Functions that are part of a recursion cycle
+    pub recursive: BTreeSet<String>,
+    usage: HashMap<String, StackUsage>,
+}
+
+pub fn analyze(files: &[VmFile]) -> Analysis {
+    let mut functions = BTreeMap::new();
+    for file in files {
+        for function in file.functions() {
+            let VmCommand::Function { n_locals, .. } = file.commands[function.start].command else {
+                unreachable!("function span without a function command")
+            };
+            let body = &file.commands[function.start + 1..function.end];
+            let (max_depth, calls) = operand_depth(body);
+            functions.insert(
+                function.name,
+                FunctionInfo {
+                    file: file.name.clone(),
+                    num_locals: n_locals as usize,
+                    max_depth,
+                    calls,
+                },
+            );
+        }
+    }
+
+    let recursive = recursion_cycles(&functions);
+    let mut analysis = Analysis {
+        functions,
+        recursive,
+        usage: HashMap::new(),
+    };
+    let names: Vec<String> = analysis.functions.keys().cloned().collect();
+    for name in names {
+        analysis.compute_usage(&name);
+    }
+    analysis
+}
+
+/// Computes the maximum operand-stack depth of a function body and the
+/// depth at each call site. Depths at labels are joined by taking the
+/// maximum, so the result is an upper bound even for unbalanced code.
+/// The calls are found even when the depth is unbounded.
+fn operand_depth(body: &[Command]) -> (Option<usize>, Vec<CallSite>) {
+    let flow = ControlFlow::new(body);
+    let mut depths: Vec<Option<usize>> = vec![None; body.len()];
+    let mut worklist = Vec::new();
+    if !body.is_empty() {
+        depths[0] = Some(0);
+        worklist.push(0);
+    }
+
+    // Without loops the depth can't exceed the number of commands, so a
+    // depth past it is unbounded. Capping depths there still reaches every
+    // command, and ends the search.
+    let unbounded = body.len() + 1;
+    while let Some(i) = worklist.pop() {
+        let depth = depths[i].unwrap();
+        let (pops, pushes) = body[i].stack_effect();
+        let after = (depth.saturating_sub(pops) + pushes).min(unbounded);
+        for next in flow.successors(body, i) {
+            if depths[next].is_none_or(|d| d < after) {
+                depths[next] = Some(after);
+                worklist.push(next);
+            }
+        }
+    }
+
+    let mut max_depth = 0;
+    let mut calls = Vec::new();
+    for (command, depth) in body.iter().zip(&depths) {
+        let Some(depth) = *depth else { continue };
+        let (pops, pushes) = command.stack_effect();
+        max_depth = max_depth
+            .max(depth)
+            .max(depth.saturating_sub(pops) + pushes);
+        if let VmCommand::Call { name, .. } = &command.command {
+            calls.push(CallSite {
+                callee: name.clone(),
+                depth,
+            });
+        }
+    }
+    ((max_depth < unbounded).then_some(max_depth), calls)
+}
+
+/// Finds the functions that belong to a recursion cycle, using Tarjan's
+/// strongly connected components algorithm.
+fn recursion_cycles(functions: &BTreeMap<String, FunctionInfo>) -> BTreeSet<String> {
+    struct Tarjan<'a> {
+        functions: &'a BTreeMap<String, FunctionInfo>,
+        index: HashMap<&'a str, usize>,
+        low_link: HashMap<&'a str, usize>,
+        stack: Vec<&'a str>,
+        on_stack: BTreeSet<&'a str>,
+        recursive: BTreeSet<String>,
+    }
+
+    impl<'a> Tarjan<'a> {
+        fn visit(&mut self, name: &'a str) {
+            let index = self.index.len();
+            self.index.insert(name, index);
+            self.low_link.insert(name, index);
+            self.stack.push(name);
+            self.on_stack.insert(name);
+
+            let calls = &self.functions[name].calls;
+            for call in calls {
+                let callee = call.callee.as_str();
+                if !self.functions.contains_key(callee) {
+                    continue;
+                }
+                if !self.index.contains_key(callee) {
+                    self.visit(callee);
+                    let low = self.low_link[name].min(self.low_link[callee]);
+                    self.low_link.insert(name, low);
+                } else if self.on_stack.contains(callee) {
+                    let low = self.low_link[name].min(self.index[callee]);
+                    self.low_link.insert(name, low);
+                }
+            }
+
+            if self.low_link[name] == self.index[name] {
+                let mut component = Vec::new();
+                while let Some(member) = self.stack.pop() {
+                    self.on_stack.remove(member);
+                    component.push(member);
+                    if member == name {
+                        break;
+                    }
+                }
+                let calls_itself = calls.iter().any(|call| call.callee == name);
+                if component.len() > 1 || calls_itself {
+                    self.recursive
+                        .extend(component.into_iter().map(str::to_string));
+                }
+            }
+        }
+    }
+
+    let mut tarjan = Tarjan {
+        functions,
+        index: HashMap::new(),
+        low_link: HashMap::new(),
+        stack: Vec::new(),
+        on_stack: BTreeSet::new(),
+        recursive: BTreeSet::new(),
+    };
+    for name in functions.keys() {
+        if !tarjan.index.contains_key(name.as_str()) {
+            tarjan.visit(name);
+        }
+    }
+    tarjan.recursive
+}
+
+impl Analysis {
+    /// Returns the worst-case stack usage of `name`. Calls to functions that
+    /// are not part of the program are counted as using no stack.
+    pub fn stack_usage(&self, name: &str) -> StackUsage {
+        self.usage
+            .get(name)
+            .copied()
+            .unwrap_or(StackUsage::Bounded(0))
+    }
+
+    fn compute_usage(&mut self, name: &str) -> StackUsage {
+        if let Some(usage) = self.usage.get(name) {
+            return *usage;
+        }
+        let Some(function) = self.functions.get(name) else {
+            return StackUsage::Bounded(0);
+        };
+        if self.recursive.contains(name) {
+            self.usage.insert(name.to_string(), StackUsage::Recursive);
+            return StackUsage::Recursive;
+        }
+        let Some(max_depth) = function.max_depth else {
+            self.usage
+                .insert(name.to_string(), StackUsage::UnboundedDepth);
+            return StackUsage::UnboundedDepth;
+        };
+
+        let num_locals = function.num_locals;
+        let calls: Vec<(String, usize)> = function
+            .calls
+            .iter()
+            .map(|call| (call.callee.clone(), call.depth))
+            .collect();
+
+        // Non-recursive, so the callees can't lead back here
+        let mut usage = StackUsage::Bounded(num_locals + max_depth);
+        for (callee, depth) in calls {
+            usage = match (usage, self.compute_usage(&callee)) {
+                (StackUsage::Bounded(current), StackUsage::Bounded(callee_usage)) => {
+                    StackUsage::Bounded(current.max(num_locals + depth + FRAME_SIZE + callee_usage))
+                }
+                (StackUsage::Bounded(_), unbounded) => unbounded,
+                (usage, _) => usage,
+            };
+        }
+        self.usage.insert(name.to_string(), usage);
+        usage
+    }
+
+    /// Returns the call chain from `entry` that uses the most stack.
+    fn deepest_chain(&self, entry: &str) -> Vec<String> {
+        let mut chain = vec![entry.to_string()];
+        let mut current = entry;
+        while let Some(function) = self.functions.get(current) {
+            let deepest = function
+                .calls
+                .iter()
+                .filter_map(|call| match self.stack_usage(&call.callee) {
+                    StackUsage::Bounded(usage) => Some((call.depth + usage, &call.callee)),
+                    _ => None,
+                })
+                .max_by_key(|(cost, _)| *cost);
+            let Some((_, callee)) = deepest else { break };
+            chain.push(callee.clone());
+            current = callee;
+        }
+        chain
+    }
+
+    /// Formats the analysis as a table of functions, followed by the
+    /// worst-case stack usage of the whole program if `entry` is defined.
+    pub fn report(&self, entry: &str) -> String {
+        let mut report = String::new();
+        let width = self
+            .functions
+            .keys()
+            .map(String::len)
+            .max()
+            .unwrap_or(8)
+            .max(8);
+
+        writeln!(
+            report,
+            "{:<width$}  {:>6}  {:>7}  {:>11}",
+            "Function", "Locals", "Operand", "Stack usage"
+        )
+        .unwrap();
+        for (name, function) in &self.functions {
+            let depth = match function.max_depth {
+                Some(depth) => depth.to_string(),
+                None => "growing".to_string(),
+            };
+            writeln!(
+                report,
+                "{:<width$}  {:>6}  {:>7}  {:>11}",
+                name,
+                function.num_locals,
+                depth,
+                format_usage(self.stack_usage(name))
+            )
+            .unwrap();
+        }
+
+        let undefined: BTreeSet<&str> = self
+            .functions
+            .values()
+            .flat_map(|function| &function.calls)
+            .map(|call| call.callee.as_str())
+            .filter(|callee| !self.functions.contains_key(*callee))
+            .collect();
+        if !undefined.is_empty() {
+            writeln!(report).unwrap();
+            writeln!(
+                report,
+                "Not defined, counted as using no stack: {}",
+                undefined.into_iter().collect::<Vec<_>>().join(", ")
+            )
+            .unwrap();
+        }
+
+        if !self.recursive.is_empty() {
+            writeln!(report).unwrap();
+            writeln!(
+                report,
+                "Recursive functions: {}",
+                self.recursive
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+            .unwrap();
+        }
+
+        if !self.functions.contains_key(entry) {
+            return report;
+        }
+
+        writeln!(report).unwrap();
+        match self.stack_usage(entry) {
+            StackUsage::Bounded(usage) => {
+                // The bootstrap code calls the entry function with SP = 256
+                let peak = STACK_BASE + FRAME_SIZE + usage;
+                writeln!(
+                    report,
+                    "Worst-case stack from {}: {} words, SP reaches {} (heap starts at {})",
+                    entry, usage, peak, HEAP_BASE
+                )
+                .unwrap();
+                writeln!(
+                    report,
+                    "Deepest call chain: {}",
+                    self.deepest_chain(entry).join(" -> ")
+                )
+                .unwrap();
+                if peak > HEAP_BASE {
+                    writeln!(report, "WARNING: the stack overflows into the heap").unwrap();
+                }
+            }
+            usage => {
+                writeln!(
+                    report,
+                    "WARNING: worst-case stack from {} is {}",
+                    entry,
+                    format_usage(usage)
+                )
+                .unwrap();
+            }
+        }
+        report
+    }
+
+    /// Formats the call graph in Graphviz DOT format, grouping functions by
+    /// file. Recursive functions are drawn in red.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph calls {\n    node [shape=box];\n");
+
+        let mut files: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (name, function) in &self.functions {
+            files.entry(&function.file).or_default().push(name);
+        }
+        for (file, names) in files {
+            writeln!(dot, "    subgraph \"cluster_{}\" {{", file).unwrap();
+            writeln!(dot, "        label=\"{}.vm\";", file).unwrap();
+            for name in names {
+                let color = if self.recursive.contains(name) {
+                    ", color=red"
+                } else {
+                    ""
+                };
+                writeln!(
+                    dot,
+                    "        \"{}\" [label=\"{}\\n{}\"{}];",
+                    name,
+                    name,
+                    format_usage(self.stack_usage(name)),
+                    color
+                )
+                .unwrap();
+            }
+            dot.push_str("    }\n");
+        }
+
+        for (name, function) in &self.functions {
+            let callees: BTreeSet<&str> = function
+                .calls
+                .iter()
+                .map(|call| call.callee.as_str())
+                .collect();
+            for callee in callees {
+                writeln!(dot, "    \"{}\" -> \"{}\";", name, callee).unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+fn format_usage(usage: StackUsage) -> String {
+    match usage {
+        StackUsage::Bounded(words) => format!("{} words", words),
+        StackUsage::Recursive => "unbounded (recursion)".to_string(),
+        StackUsage::UnboundedDepth => "unbounded (growing loop)".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stack_usage() {
+        let files = vec![VmFile::parse(
+            "Main",
+            "function Sys.init 0\npush constant 1\npush constant 2\ncall Main.add 2\npop temp 0\nlabel END\ngoto END\n\
+             function Main.add 1\npush argument 0\npush argument 1\nadd\nreturn",
+        )];
+
+        let analysis = analyze(&files);
+        assert_eq!(analysis.functions["Main.add"].max_depth, Some(2));
+        assert_eq!(analysis.stack_usage("Main.add"), StackUsage::Bounded(3));
+        // 2 arguments + 5 frame words + Main.add
+        assert_eq!(analysis.stack_usage("Sys.init"), StackUsage::Bounded(10));
+    }
+
+    #[test]
+    fn test_recursion() {
+        let files = vec![VmFile::parse(
+            "Main",
+            "function Sys.init 0\ncall Main.even 0\nreturn\n\
+             function Main.even 0\ncall Main.odd 0\nreturn\n\
+             function Main.odd 0\ncall Main.even 0\nreturn\n\
+             function Main.leaf 0\npush constant 0\nreturn",
+        )];
+
+        let analysis = analyze(&files);
+        let recursive: Vec<&str> = analysis.recursive.iter().map(String::as_str).collect();
+        assert_eq!(recursive, ["Main.even", "Main.odd"]);
+        assert_eq!(analysis.stack_usage("Sys.init"), StackUsage::Recursive);
+        assert_eq!(analysis.stack_usage("Main.leaf"), StackUsage::Bounded(1));
+    }
+
+    #[test]
+    fn test_growing_loop() {
+        let files = vec![VmFile::parse(
+            "Main",
+            "function Main.grow 0\nlabel LOOP\npush constant 1\ngoto LOOP",
+        )];
+
+        let analysis = analyze(&files);
+        assert_eq!(
+            analysis.stack_usage("Main.grow"),
+            StackUsage::UnboundedDepth
+        );
+    }
+
+    #[test]
+    fn test_growing_loop_calls() {
+        let files = vec![VmFile::parse(
+            "Main",
+            "function Main.grow 0\nlabel LOOP\npush constant 1\ncall Main.leaf 0\n\
+             if-goto LOOP\ncall Main.after 0\nreturn\n\
+             function Main.leaf 0\ncall Main.grow 0\nreturn\n\
+             function Main.after 0\npush constant 0\nreturn",
+        )];
+
+        let analysis = analyze(&files);
+        let grow = &analysis.functions["Main.grow"];
+        assert_eq!(grow.max_depth, None);
+        let callees: Vec<&str> = grow.calls.iter().map(|call| call.callee.as_str()).collect();
+        assert_eq!(callees, ["Main.leaf", "Main.after"]);
+        let recursive: Vec<&str> = analysis.recursive.iter().map(String::as_str).collect();
+        assert_eq!(recursive, ["Main.grow", "Main.leaf"]);
+    }
+}
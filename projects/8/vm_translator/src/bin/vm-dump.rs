@@ -0,0 +1,35 @@
+//! Prints a `.vmb` bytecode file back as VM text.
+
+use std::{env, fs, process};
+
+use vm_translator::bytecode;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let (show_lines, input) = match args.as_slice() {
+        [_, input] => (false, input),
+        [_, flag, input] if flag == "--lines" => (true, input),
+        _ => {
+            eprintln!("Usage: {} [--lines] <file.vmb>", args[0]);
+            process::exit(1);
+        }
+    };
+
+    let bytes = fs::read(input).expect("Failed to read input file");
+    let commands = bytecode::decode(&bytes).unwrap_or_else(|error| {
+        eprintln!("Error: {}: {}", input, error);
+        process::exit(1);
+    });
+
+    for command in &commands {
+        let mut line = command.to_string();
+        if let Some(file) = &command.file {
+            // Inlined static references can't be expressed in VM code
+            line = format!("{} // from {}.vm", line, file);
+        }
+        if show_lines {
+            line = format!("{:>5}  {}", command.line, line);
+        }
+        println!("{}", line);
+    }
+}
@@ -0,0 +1,25 @@
+//! Prints the profile of a run of a program translated with `--profile`,
+//! from its `.profile` map and a dump of the RAM taken after the run.
+
+use std::{env, fs, process};
+
+use vm_translator::profile::{self, ProfileLayout};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let [_, map_file, dump_file] = args.as_slice() else {
+        eprintln!("Usage: {} <program.profile> <ram dump>", args[0]);
+        process::exit(1);
+    };
+
+    let fail = |file: &str, error: String| -> ! {
+        eprintln!("Error: {}: {}", file, error);
+        process::exit(1);
+    };
+    let map = fs::read_to_string(map_file).expect("Failed to read profile map");
+    let layout = ProfileLayout::parse_map(&map).unwrap_or_else(|error| fail(map_file, error));
+    let dump = fs::read_to_string(dump_file).expect("Failed to read RAM dump");
+    let ram = profile::parse_ram_dump(&dump).unwrap_or_else(|error| fail(dump_file, error));
+
+    print!("{}", profile::report(&layout, &ram));
+}
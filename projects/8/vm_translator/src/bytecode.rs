@@ -0,0 +1,381 @@
+//! Binary encoding of VM programs (`.vmb` files).
+//!
+//! A `.vmb` file holds the commands of one `.vm` file and loads much faster
+//! than re-parsing the text. All numbers are little-endian:
+//!
+//! ```text
+//! magic     "VMB", then the format version 0x02
+//! names     u16 count, then per name: u16 length, UTF-8 bytes
+//! commands  u32 count, then per command:
+//!           opcode    u8, bit 7 set when a u16 file name index follows
+//!                     (inlined `static` references, see `Command::file`)
+//!           operands  push/pop: segment u8, index u16
+//!                     label/goto/if-goto: name u16
+//!                     function/call: name u16, count u16
+//!           line      zigzag varint, difference from the previous line
+//! ```
+//!
+//! Function, label and file names are interned in the name table.
+//! Opcodes are fixed by `ARITHMETIC` and the constants below, never by
+//! declaration order, so that a file keeps its meaning; a change to them
+//! needs a new version.
+
+use std::collections::HashMap;
+
+use crate::labels;
+use crate::parser::{Command, Op, Segment, VmCommand};
+
+const MAGIC: &[u8; 3] = b"VMB";
+const VERSION: u8 = 2;
+
+/// Opcodes of the arithmetic commands. Version 1 had `abs` at 0x0e.
+const ARITHMETIC: [(Op, u8); 17] = [
+    (Op::Add, 0x00),
+    (Op::Sub, 0x01),
+    (Op::Neg, 0x02),
+    (Op::Eq, 0x03),
+    (Op::Gt, 0x04),
+    (Op::Lt, 0x05),
+    (Op::And, 0x06),
+    (Op::Or, 0x07),
+    (Op::Not, 0x08),
+    (Op::Mul, 0x09),
+    (Op::Div, 0x0a),
+    (Op::Mod, 0x0b),
+    (Op::Shl, 0x0c),
+    (Op::Shr, 0x0d),
+    (Op::Nmul, 0x0f),
+    (Op::Ndiv, 0x10),
+    (Op::Nmod, 0x11),
+];
+
+const PUSH: u8 = 0x20;
+const POP: u8 = 0x21;
+const LABEL: u8 = 0x22;
+const GOTO: u8 = 0x23;
+const IF: u8 = 0x24;
+const FUNCTION: u8 = 0x25;
+const CALL: u8 = 0x26;
+const RETURN: u8 = 0x27;
+
+/// Set on the opcode of a command whose `file` is set.
+const FILE_FLAG: u8 = 0x80;
+
+/// Encodes commands as the contents of a `.vmb` file.
+pub fn encode(commands: &[Command]) -> Vec<u8> {
+    let mut names: Vec<&str> = Vec::new();
+    let mut name_indices: HashMap<&str, u16> = HashMap::new();
+    let mut body = Vec::new();
+    let mut previous_line = 0;
+
+    for command in commands {
+        let mut intern = |name| {
+            *name_indices.entry(name).or_insert_with(|| {
+                names.push(name);
+                u16::try_from(names.len() - 1).expect("Too many names for bytecode")
+            })
+        };
+
+        let opcode = opcode(&command.command);
+        let file = command.file.as_deref().map(&mut intern);
+        if let Some(file) = file {
+            body.push(opcode | FILE_FLAG);
+            push_u16(&mut body, file);
+        } else {
+            body.push(opcode);
+        }
+
+        match &command.command {
+            VmCommand::Push { segment, index } | VmCommand::Pop { segment, index } => {
+                let segment = Segment::ALL.iter().position(|s| s == segment).unwrap();
+                body.push(segment as u8);
+                push_u16(&mut body, *index);
+            }
+            VmCommand::Label(label) | VmCommand::Goto(label) | VmCommand::IfGoto(label) => {
+                push_u16(&mut body, intern(label));
+            }
+            VmCommand::Function {
+                name,
+                n_locals: count,
+            }
+            | VmCommand::Call {
+                name,
+                n_args: count,
+            } => {
+                push_u16(&mut body, intern(name));
+                push_u16(&mut body, *count);
+            }
+            VmCommand::Arithmetic(_) | VmCommand::Return => {}
+        }
+
+        push_varint(&mut body, command.line as i64 - previous_line as i64);
+        previous_line = command.line;
+    }
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    push_u16(&mut bytes, names.len() as u16);
+    for name in &names {
+        push_u16(&mut bytes, to_u16(name.len()));
+        bytes.extend_from_slice(name.as_bytes());
+    }
+    bytes.extend_from_slice(&(commands.len() as u32).to_le_bytes());
+    bytes.extend(body);
+    bytes
+}
+
+/// Decodes the contents of a `.vmb` file.
+pub fn decode(bytes: &[u8]) -> Result<Vec<Command>, String> {
+    let mut reader = Reader { bytes, position: 0 };
+    if reader.take(3)? != MAGIC {
+        return Err("not a VM bytecode file".to_string());
+    }
+    let version = reader.u8()?;
+    if version != VERSION {
+        return Err(format!(
+            "unsupported bytecode version {}, rebuild it with --emit vmb",
+            version
+        ));
+    }
+
+    let mut names = Vec::new();
+    for _ in 0..reader.u16()? {
+        let length = reader.u16()? as usize;
+        let name = std::str::from_utf8(reader.take(length)?)
+            .ok()
+            .filter(|name| labels::is_identifier(name))
+            .ok_or_else(|| format!("invalid name at byte {}", reader.position))?;
+        names.push(name.to_string());
+    }
+    let name = |index: u16| {
+        names
+            .get(index as usize)
+            .cloned()
+            .ok_or_else(|| format!("invalid name index {}", index))
+    };
+
+    let count = u32::from_le_bytes(reader.take(4)?.try_into().unwrap());
+    let mut commands = Vec::new();
+    let mut line: i64 = 0;
+    for _ in 0..count {
+        let start = reader.position;
+        let mut opcode = reader.u8()?;
+        let file = if opcode & FILE_FLAG != 0 {
+            opcode &= !FILE_FLAG;
+            Some(name(reader.u16()?)?)
+        } else {
+            None
+        };
+
+        let command = match opcode {
+            PUSH | POP => {
+                let segment = reader.u8()?;
+                let segment = Segment::ALL
+                    .get(segment as usize)
+                    .filter(|&&s| opcode == PUSH || s != Segment::Constant)
+                    .copied()
+                    .ok_or_else(|| format!("invalid segment {} at byte {}", segment, start))?;
+                let index = reader.u16()?;
+                if segment == Segment::Constant && index > i16::MAX as u16 {
+                    return Err(format!(
+                        "constant out of range: {} (0-32767) at byte {}",
+                        index, start
+                    ));
+                }
+                if opcode == PUSH {
+                    VmCommand::Push { segment, index }
+                } else {
+                    VmCommand::Pop { segment, index }
+                }
+            }
+            LABEL => VmCommand::Label(name(reader.u16()?)?),
+            GOTO => VmCommand::Goto(name(reader.u16()?)?),
+            IF => VmCommand::IfGoto(name(reader.u16()?)?),
+            FUNCTION => VmCommand::Function {
+                name: name(reader.u16()?)?,
+                n_locals: reader.u16()?,
+            },
+            CALL => VmCommand::Call {
+                name: name(reader.u16()?)?,
+                n_args: reader.u16()?,
+            },
+            RETURN => VmCommand::Return,
+            _ => {
+                let &(op, _) = ARITHMETIC
+                    .iter()
+                    .find(|&&(_, code)| code == opcode)
+                    .ok_or_else(|| format!("unknown opcode {:#04x} at byte {}", opcode, start))?;
+                VmCommand::Arithmetic(op)
+            }
+        };
+
+        line += reader.varint()?;
+        let mut command = Command::new(command, line.max(0) as usize);
+        command.file = file;
+        commands.push(command);
+    }
+
+    if reader.position != bytes.len() {
+        return Err(format!("unexpected data at byte {}", reader.position));
+    }
+    Ok(commands)
+}
+
+fn opcode(command: &VmCommand) -> u8 {
+    match command {
+        VmCommand::Arithmetic(op) => {
+            ARITHMETIC
+                .iter()
+                .find(|(o, _)| o == op)
+                .expect("arithmetic command without an opcode")
+                .1
+        }
+        VmCommand::Push { .. } => PUSH,
+        VmCommand::Pop { .. } => POP,
+        VmCommand::Label(_) => LABEL,
+        VmCommand::Goto(_) => GOTO,
+        VmCommand::IfGoto(_) => IF,
+        VmCommand::Function { .. } => FUNCTION,
+        VmCommand::Call { .. } => CALL,
+        VmCommand::Return => RETURN,
+    }
+}
+
+fn to_u16(value: usize) -> u16 {
+    u16::try_from(value).unwrap_or_else(|_| panic!("Value too large for bytecode: {}", value))
+}
+
+fn push_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Appends a signed LEB128-style varint, zigzag encoded so that small
+/// negative differences stay small.
+fn push_varint(bytes: &mut Vec<u8>, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let end = self.position + count;
+        let slice = self
+            .bytes
+            .get(self.position..end)
+            .ok_or_else(|| "unexpected end of file".to_string())?;
+        self.position = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn varint(&mut self) -> Result<i64, String> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok((value >> 1) as i64 ^ -((value & 1) as i64));
+            }
+        }
+        Err(format!("invalid line number at byte {}", self.position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::VmFile;
+
+    #[test]
+    fn test_round_trip() {
+        let source = "function Main.main 2\n\n// comment\npush constant 7\npop local 1\n\
+                      label LOOP\npush local 1\nif-goto LOOP\ngoto LOOP\n\
+                      call Main.main 0\nmul\nnot\nreturn";
+        let commands = VmFile::parse("Main", source).commands;
+        let bytes = encode(&commands);
+        assert_eq!(decode(&bytes).unwrap(), commands);
+        // "Main.main" and "LOOP" are stored once
+        assert_eq!(u16::from_le_bytes([bytes[4], bytes[5]]), 2);
+    }
+
+    #[test]
+    fn test_opcodes() {
+        // Every command has its own opcode, below the other commands'
+        for op in Op::ALL {
+            let commands = [Command::new(VmCommand::Arithmetic(op), 1)];
+            assert_eq!(decode(&encode(&commands)).unwrap(), commands);
+        }
+        let mut codes: Vec<u8> = ARITHMETIC.iter().map(|&(_, code)| code).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), Op::ALL.len());
+        assert!(codes.iter().all(|&code| code < PUSH));
+        assert_eq!(opcode(&VmCommand::Arithmetic(Op::Mul)), 0x09);
+    }
+
+    #[test]
+    fn test_inlined_static_and_lines_going_back() {
+        let mut commands = vec![
+            Command::new(
+                VmCommand::Push {
+                    segment: Segment::Static,
+                    index: 3,
+                },
+                10,
+            ),
+            Command::new(
+                VmCommand::Pop {
+                    segment: Segment::Temp,
+                    index: 0,
+                },
+                2,
+            ),
+        ];
+        commands[0].file = Some("Square".to_string());
+        assert_eq!(decode(&encode(&commands)).unwrap(), commands);
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        assert_eq!(decode(b"VM"), Err("unexpected end of file".to_string()));
+        assert_eq!(decode(b"XXXX"), Err("not a VM bytecode file".to_string()));
+        assert_eq!(
+            decode(b"VMB\x01\x00\x00\x00\x00\x00\x00"),
+            Err("unsupported bytecode version 1, rebuild it with --emit vmb".to_string())
+        );
+        let mut bytes = encode(&[Command::new(VmCommand::Return, 1)]);
+        bytes[10] = 0x7f;
+        assert_eq!(
+            decode(&bytes),
+            Err("unknown opcode 0x7f at byte 10".to_string())
+        );
+        let push = VmCommand::Push {
+            segment: Segment::Constant,
+            index: 40000,
+        };
+        assert_eq!(
+            decode(&encode(&[Command::new(push, 1)])),
+            Err("constant out of range: 40000 (0-32767) at byte 10".to_string())
+        );
+    }
+}
@@ -1,23 +1,98 @@
-use crate::parser::CommandType;
-use std::fs::File;
+use crate::extended;
+use crate::labels;
+use crate::parser::{Op, Segment};
+use crate::profile::ProfileLayout;
+use std::collections::BTreeSet;
 use std::io::{BufWriter, Write};
 
-pub struct CodeWriter {
-    output: BufWriter<File>,
+/// Default stack limit for `--stack-guard`: the heap starts at 2048.
+pub const DEFAULT_STACK_LIMIT: usize = 2048;
+
+/// Default initial SP.
+pub const STACK_BASE: usize = 256;
+
+/// RAM register set by the stack guard handlers before halting:
+/// 1 for an overflow, 2 for an underflow.
+const STACK_ERROR_REGISTER: &str = "R15";
+
+/// Initial register values and entry point written by `write_bootstrap`.
+pub struct Bootstrap {
+    pub sp: usize,
+    pub lcl: Option<usize>,
+    pub arg: Option<usize>,
+    pub this: Option<usize>,
+    pub that: Option<usize>,
+    /// Function called with no arguments once the registers are set
+    pub entry: Option<String>,
+}
+
+impl Default for Bootstrap {
+    /// SP = 256, then `call Sys.init 0`
+    fn default() -> Self {
+        Self {
+            sp: STACK_BASE,
+            lcl: None,
+            arg: None,
+            this: None,
+            that: None,
+            entry: Some("Sys.init".to_string()),
+        }
+    }
+}
+
+/// The VM command a block of generated assembly comes from.
+pub struct SourceMapEntry {
+    /// 1-based line of the first assembly line of the block
+    pub asm_line: usize,
+    /// ROM address of the first instruction of the block
+    pub rom_address: usize,
+    pub file: String,
+    pub line: usize,
+    pub function: String,
+}
+
+pub struct CodeWriter<W: Write> {
+    output: BufWriter<W>,
     filename: String,
     label_counter: usize,
     function_name: String,
-    call_counter: usize,
+    /// Highest valid SP when stack guards are enabled
+    stack_limit: Option<usize>,
+    /// Number of locals of the current function
+    num_locals: Option<usize>,
+    /// Initial SP, the frame base of code outside functions
+    stack_base: usize,
+    /// Write a `// File.vm:line function` comment before each command
+    annotate: bool,
+    /// Assembly lines and instructions written so far
+    asm_lines: usize,
+    rom_address: usize,
+    source_map: Vec<SourceMapEntry>,
+    /// Routines of the extended commands to write after the program
+    routines: BTreeSet<&'static str>,
+    /// Whether the halt loop ending the program was written
+    halt_written: bool,
+    /// Counters updated at runtime when profiling
+    profile: Option<ProfileLayout>,
 }
 
-impl CodeWriter {
-    pub fn new(output_file: File, filename: String) -> Self {
+impl<W: Write> CodeWriter<W> {
+    pub fn new(output: W, filename: String) -> Self {
         Self {
-            output: BufWriter::new(output_file),
+            output: BufWriter::new(output),
             filename,
             label_counter: 0,
             function_name: String::new(),
-            call_counter: 0,
+            stack_limit: None,
+            num_locals: None,
+            stack_base: STACK_BASE,
+            annotate: false,
+            asm_lines: 0,
+            rom_address: 0,
+            source_map: Vec::new(),
+            routines: BTreeSet::new(),
+            halt_written: false,
+            profile: None,
         }
     }
 
@@ -25,18 +100,110 @@ impl CodeWriter {
         self.filename = filename;
     }
 
-    pub fn write_bootstrap(&mut self) {
+    /// Enables `// File.vm:line function` comments before each command.
+    pub fn set_annotate(&mut self, annotate: bool) {
+        self.annotate = annotate;
+    }
+
+    /// Records that the following code comes from `file`:`line` in
+    /// `function`, annotating it if enabled.
+    pub fn set_source(&mut self, file: &str, line: usize, function: &str) {
+        self.source_map.push(SourceMapEntry {
+            asm_line: self.asm_lines + 1,
+            rom_address: self.rom_address,
+            file: file.to_string(),
+            line,
+            function: function.to_string(),
+        });
+        if self.annotate {
+            self.write_line(&format!("// {}.vm:{} {}", file, line, function));
+        }
+    }
+
+    /// Writes the shared routines of the extended commands used so far,
+    /// behind a halt loop like the stack guard handlers.
+    pub fn write_extended_routines(&mut self) {
+        if !self.routines.is_empty() {
+            self.write_halt();
+        }
+        for routine in std::mem::take(&mut self.routines) {
+            self.write_line(&format!("// Extended command routine {}", routine));
+            let code = match routine {
+                "$$MUL" => extended::MUL,
+                "$$DIVMOD" => extended::DIVMOD,
+                "$$SHL" => extended::SHL,
+                _ => extended::SHR,
+            };
+            for line in code.lines().filter(|line| !line.is_empty()) {
+                self.write_line(line);
+            }
+        }
+    }
+
+    /// Returns the number of instructions written so far.
+    pub fn rom_address(&self) -> usize {
+        self.rom_address
+    }
+
+    /// Returns where each block of the code written so far comes from.
+    pub fn source_map(&self) -> &[SourceMapEntry] {
+        &self.source_map
+    }
+
+    /// Enables runtime stack guards: pushes check that SP stays at or below
+    /// `limit`, pops check that SP stays above the locals of the current
+    /// frame. A failed check jumps to `$$STACK_OVERFLOW` or
+    /// `$$STACK_UNDERFLOW`, which store an error code in R15 and halt; they
+    /// are written by `write_stack_handlers` after the program.
+    pub fn set_stack_guard(&mut self, limit: usize) {
+        self.stack_limit = Some(limit);
+    }
+
+    /// Enables profiling: each function entry increments the function's
+    /// call counter, and `write_profile_cycles` adds to its cycle counter.
+    /// The bootstrap clears the counters.
+    pub fn set_profile(&mut self, layout: ProfileLayout) {
+        self.profile = Some(layout);
+    }
+
+    pub fn write_bootstrap(&mut self, bootstrap: &Bootstrap) {
         self.write_line("// Bootstrap code");
-        self.write_line("@256");
-        self.write_line("D=A");
-        self.write_line("@SP");
-        self.write_line("M=D");
-        self.write_call("Sys.init", 0);
+        let registers = [
+            ("SP", Some(bootstrap.sp)),
+            ("LCL", bootstrap.lcl),
+            ("ARG", bootstrap.arg),
+            ("THIS", bootstrap.this),
+            ("THAT", bootstrap.that),
+        ];
+        for (register, value) in registers {
+            if let Some(value) = value {
+                self.write_line(&format!("@{}", value));
+                self.write_line("D=A");
+                self.write_line(&format!("@{}", register));
+                self.write_line("M=D");
+            }
+        }
+        self.stack_base = bootstrap.sp;
+        if let Some(layout) = &self.profile {
+            let (base, size) = (layout.base, layout.size());
+            self.write_clear_loop(base, base + size);
+        }
+
+        // Sys.init never returns, but another entry such as Main.main
+        // would otherwise fall through into the first function
+        if let Some(entry) = &bootstrap.entry {
+            self.write_call(entry, 0);
+            self.write_line("($$HALT)");
+            self.write_line("@$$HALT");
+            self.write_line("0;JMP");
+        }
     }
 
-    pub fn write_arithmetic(&mut self, command: &str) {
-        match command {
-            "add" => {
+    pub fn write_arithmetic(&mut self, op: Op) {
+        self.check_underflow(if op.is_unary() { 1 } else { 2 });
+
+        match op {
+            Op::Add => {
                 self.write_line("// add");
                 self.pop_to_d();
                 self.decrement_sp();
@@ -44,7 +211,7 @@ impl CodeWriter {
                 self.write_line("M=D+M");
                 self.increment_sp();
             }
-            "sub" => {
+            Op::Sub => {
                 self.write_line("// sub");
                 self.pop_to_d();
                 self.decrement_sp();
@@ -52,17 +219,17 @@ impl CodeWriter {
                 self.write_line("M=M-D");
                 self.increment_sp();
             }
-            "neg" => {
+            Op::Neg => {
                 self.write_line("// neg");
                 self.decrement_sp();
                 self.write_line("A=M");
                 self.write_line("M=-M");
                 self.increment_sp();
             }
-            "eq" => self.write_comparison("JEQ"),
-            "gt" => self.write_comparison("JGT"),
-            "lt" => self.write_comparison("JLT"),
-            "and" => {
+            Op::Eq => self.write_comparison("JEQ"),
+            Op::Gt => self.write_comparison("JGT"),
+            Op::Lt => self.write_comparison("JLT"),
+            Op::And => {
                 self.write_line("// and");
                 self.pop_to_d();
                 self.decrement_sp();
@@ -70,7 +237,7 @@ impl CodeWriter {
                 self.write_line("M=D&M");
                 self.increment_sp();
             }
-            "or" => {
+            Op::Or => {
                 self.write_line("// or");
                 self.pop_to_d();
                 self.decrement_sp();
@@ -78,47 +245,78 @@ impl CodeWriter {
                 self.write_line("M=D|M");
                 self.increment_sp();
             }
-            "not" => {
+            Op::Not => {
                 self.write_line("// not");
                 self.decrement_sp();
                 self.write_line("A=M");
                 self.write_line("M=!M");
                 self.increment_sp();
             }
-            _ => panic!("Unknown arithmetic command: {}", command),
-        }
-    }
-
-    pub fn write_push_pop(&mut self, command_type: CommandType, segment: &str, index: usize) {
-        match command_type {
-            CommandType::Push => self.write_push(segment, index),
-            CommandType::Pop => self.write_pop(segment, index),
-            _ => panic!("Invalid command type for push/pop"),
+            Op::Mul => self.write_routine_call("mul", "$$MUL"),
+            Op::Div => self.write_routine_call("div", "$$DIVMOD"),
+            Op::Mod => {
+                // The remainder is left in R13
+                self.write_routine_call("mod", "$$DIVMOD");
+                self.write_line("@R13");
+                self.write_line("D=M");
+                self.write_line("@SP");
+                self.write_line("A=M-1");
+                self.write_line("M=D");
+            }
+            Op::Nmul | Op::Ndiv => {
+                let routine = if op == Op::Nmul { "$$MUL" } else { "$$DIVMOD" };
+                self.write_routine_call(op.name(), routine);
+                self.write_line("@SP");
+                self.write_line("A=M-1");
+                self.write_line("M=-M");
+            }
+            Op::Nmod => {
+                self.write_routine_call("nmod", "$$DIVMOD");
+                self.write_line("@R13");
+                self.write_line("D=-M");
+                self.write_line("@SP");
+                self.write_line("A=M-1");
+                self.write_line("M=D");
+            }
+            Op::Shl => self.write_routine_call("shl", "$$SHL"),
+            Op::Shr => self.write_routine_call("shr", "$$SHR"),
         }
     }
 
     pub fn write_label(&mut self, label: &str) {
         self.write_line(&format!("// label {}", label));
-        self.write_line(&format!("({}${})", self.function_name, label));
+        self.write_line(&format!(
+            "({})",
+            labels::user_label(&self.function_name, label)
+        ));
     }
 
     pub fn write_goto(&mut self, label: &str) {
         self.write_line(&format!("// goto {}", label));
-        self.write_line(&format!("@{}${}", self.function_name, label));
+        self.write_line(&format!(
+            "@{}",
+            labels::user_label(&self.function_name, label)
+        ));
         self.write_line("0;JMP");
     }
 
     pub fn write_if(&mut self, label: &str) {
         self.write_line(&format!("// if-goto {}", label));
+        self.check_underflow(1);
         self.pop_to_d();
-        self.write_line(&format!("@{}${}", self.function_name, label));
+        self.write_line(&format!(
+            "@{}",
+            labels::user_label(&self.function_name, label)
+        ));
         self.write_line("D;JNE");
     }
 
     pub fn write_function(&mut self, function_name: &str, num_locals: usize) {
         self.function_name = function_name.to_string();
+        self.num_locals = Some(num_locals);
         self.write_line(&format!("// function {} {}", function_name, num_locals));
         self.write_line(&format!("({})", function_name));
+        self.check_overflow(num_locals);
 
         // Initialize local variables to 0
         for _ in 0..num_locals {
@@ -127,38 +325,113 @@ impl CodeWriter {
             self.write_line("M=0");
             self.increment_sp();
         }
+
+        let calls = self.profile.as_ref().and_then(|layout| {
+            let index = layout.index(function_name)?;
+            Some(layout.calls_address(index))
+        });
+        if let Some(address) = calls {
+            self.write_line("// profile: count call");
+            self.write_counter_add(address, 1);
+        }
+    }
+
+    /// Adds `instructions` to the cycle counter of the current function,
+    /// at the start of a basic block of that many instructions.
+    pub fn write_profile_cycles(&mut self, instructions: usize) {
+        let cycles = self.profile.as_ref().and_then(|layout| {
+            let index = layout.index(&self.function_name)?;
+            layout.cycles_address(index)
+        });
+        if let Some(address) = cycles
+            && instructions > 0
+        {
+            self.write_line(&format!("// profile: {} cycles", instructions));
+            self.write_counter_add(address, instructions);
+        }
+    }
+
+    /// Adds `amount` (below 32768) to the two-word counter at `address`,
+    /// carrying bit 15 of the low word into the high word.
+    fn write_counter_add(&mut self, address: usize, amount: usize) {
+        let done = self.generated_label("PROFILE");
+        if amount == 1 {
+            self.write_line(&format!("@{}", address));
+            self.write_line("MD=M+1");
+        } else {
+            self.write_line(&format!("@{}", amount));
+            self.write_line("D=A");
+            self.write_line(&format!("@{}", address));
+            self.write_line("MD=D+M");
+        }
+        self.write_line(&format!("@{}", done));
+        self.write_line("D;JGE");
+        self.write_line("@32767");
+        self.write_line("D=A");
+        self.write_line(&format!("@{}", address));
+        self.write_line("M=D&M");
+        self.write_line(&format!("@{}", address + 1));
+        self.write_line("M=M+1");
+        self.write_line(&format!("({})", done));
+    }
+
+    /// Sets RAM[start..end] to 0.
+    fn write_clear_loop(&mut self, start: usize, end: usize) {
+        let (clear, cleared) = (
+            self.generated_label("CLEAR"),
+            self.generated_label("CLEARED"),
+        );
+        self.write_line(&format!("@{}", start));
+        self.write_line("D=A");
+        self.write_line("@R13");
+        self.write_line("M=D");
+        self.write_line(&format!("({})", clear));
+        self.write_line("@R13");
+        self.write_line("D=M");
+        self.write_line(&format!("@{}", end));
+        self.write_line("D=D-A");
+        self.write_line(&format!("@{}", cleared));
+        self.write_line("D;JGE");
+        self.write_line("@R13");
+        self.write_line("A=M");
+        self.write_line("M=0");
+        self.write_line("@R13");
+        self.write_line("M=M+1");
+        self.write_line(&format!("@{}", clear));
+        self.write_line("0;JMP");
+        self.write_line(&format!("({})", cleared));
     }
 
     pub fn write_call(&mut self, function_name: &str, num_args: usize) {
-        let return_label = format!("{}$ret.{}", function_name, self.call_counter);
-        self.call_counter += 1;
+        let return_label = self.generated_label(&format!("RET.{}", function_name));
 
         self.write_line(&format!("// call {} {}", function_name, num_args));
+        self.check_overflow(5);
 
         // Push return address
         self.write_line(&format!("@{}", return_label));
         self.write_line("D=A");
-        self.push_d();
+        self.store_d_and_increment_sp();
 
         // Push LCL
         self.write_line("@LCL");
         self.write_line("D=M");
-        self.push_d();
+        self.store_d_and_increment_sp();
 
         // Push ARG
         self.write_line("@ARG");
         self.write_line("D=M");
-        self.push_d();
+        self.store_d_and_increment_sp();
 
         // Push THIS
         self.write_line("@THIS");
         self.write_line("D=M");
-        self.push_d();
+        self.store_d_and_increment_sp();
 
         // Push THAT
         self.write_line("@THAT");
         self.write_line("D=M");
-        self.push_d();
+        self.store_d_and_increment_sp();
 
         // ARG = SP - 5 - num_args
         self.write_line("@SP");
@@ -182,6 +455,85 @@ impl CodeWriter {
         self.write_line(&format!("({})", return_label));
     }
 
+    /// Writes `call function_name num_args` immediately followed by `return`,
+    /// reusing the current frame instead of pushing a new one: the arguments
+    /// replace the current function's arguments and the saved caller frame
+    /// is moved up behind them, so the callee returns directly to our caller.
+    pub fn write_tail_call(&mut self, function_name: &str, num_args: usize) {
+        self.write_line(&format!(
+            "// call {} {} (tail call)",
+            function_name, num_args
+        ));
+        self.check_overflow(5);
+
+        // Save the caller frame (LCL-5..LCL-1) above the stack top, where
+        // the moves below cannot overwrite it
+        for i in 0..5 {
+            self.write_line("@LCL");
+            self.write_line("D=M");
+            self.write_line(&format!("@{}", 5 - i));
+            self.write_line("A=D-A");
+            self.write_line("D=M");
+            self.write_line("@SP");
+            self.write_line("A=M");
+            for _ in 0..i {
+                self.write_line("A=A+1");
+            }
+            self.write_line("M=D");
+        }
+
+        // Move the new arguments down to ARG. The destination is always
+        // below the source, so copying upwards is safe
+        for i in 0..num_args {
+            self.write_line(&format!("@{}", i));
+            self.write_line("D=A");
+            self.write_line("@ARG");
+            self.write_line("D=D+M");
+            self.write_line("@R13"); // R13 = ARG + i
+            self.write_line("M=D");
+            self.write_line("@SP");
+            self.write_line("D=M");
+            self.write_line(&format!("@{}", num_args - i));
+            self.write_line("A=D-A");
+            self.write_line("D=M");
+            self.write_line("@R13");
+            self.write_line("A=M");
+            self.write_line("M=D"); // *(ARG + i) = *(SP - num_args + i)
+        }
+
+        // Restore the saved frame right after the arguments
+        self.write_line(&format!("@{}", num_args));
+        self.write_line("D=A");
+        self.write_line("@ARG");
+        self.write_line("D=D+M");
+        self.write_line("@R13"); // R13 = ARG + num_args
+        self.write_line("M=D");
+        for i in 0..5 {
+            self.write_line("@SP");
+            self.write_line("A=M");
+            for _ in 0..i {
+                self.write_line("A=A+1");
+            }
+            self.write_line("D=M");
+            self.write_line("@R13");
+            self.write_line("M=M+1");
+            self.write_line("A=M-1");
+            self.write_line("M=D");
+        }
+
+        // LCL = SP = ARG + num_args + 5, ARG is unchanged
+        self.write_line("@R13");
+        self.write_line("D=M");
+        self.write_line("@LCL");
+        self.write_line("M=D");
+        self.write_line("@SP");
+        self.write_line("M=D");
+
+        // goto function
+        self.write_line(&format!("@{}", function_name));
+        self.write_line("0;JMP");
+    }
+
     pub fn write_return(&mut self) {
         self.write_line("// return");
 
@@ -199,6 +551,7 @@ impl CodeWriter {
         self.write_line("M=D");
 
         // Reposition return value for caller
+        self.check_underflow(1);
         self.pop_to_d();
         self.write_line("@ARG");
         self.write_line("A=M");
@@ -251,62 +604,65 @@ impl CodeWriter {
         self.write_line("0;JMP");
     }
 
-    fn write_push(&mut self, segment: &str, index: usize) {
+    pub fn write_push(&mut self, segment: Segment, index: u16) {
         self.write_line(&format!("// push {} {}", segment, index));
+        self.check_overflow(1);
+        let index = index as usize;
         match segment {
-            "constant" => {
+            Segment::Constant => {
                 self.write_line(&format!("@{}", index));
                 self.write_line("D=A");
-                self.push_d();
+                self.store_d_and_increment_sp();
             }
-            "local" => self.push_from_segment("LCL", index),
-            "argument" => self.push_from_segment("ARG", index),
-            "this" => self.push_from_segment("THIS", index),
-            "that" => self.push_from_segment("THAT", index),
-            "temp" => {
+            Segment::Local => self.push_from_segment("LCL", index),
+            Segment::Argument => self.push_from_segment("ARG", index),
+            Segment::This => self.push_from_segment("THIS", index),
+            Segment::That => self.push_from_segment("THAT", index),
+            Segment::Temp => {
                 self.write_line(&format!("@{}", 5 + index));
                 self.write_line("D=M");
-                self.push_d();
+                self.store_d_and_increment_sp();
             }
-            "static" => {
+            Segment::Static => {
                 self.write_line(&format!("@{}.{}", self.filename, index));
                 self.write_line("D=M");
-                self.push_d();
+                self.store_d_and_increment_sp();
             }
-            "pointer" => {
+            Segment::Pointer => {
                 let addr = if index == 0 { "THIS" } else { "THAT" };
                 self.write_line(&format!("@{}", addr));
                 self.write_line("D=M");
-                self.push_d();
+                self.store_d_and_increment_sp();
             }
-            _ => panic!("Unknown segment: {}", segment),
         }
     }
 
-    fn write_pop(&mut self, segment: &str, index: usize) {
+    pub fn write_pop(&mut self, segment: Segment, index: u16) {
         self.write_line(&format!("// pop {} {}", segment, index));
+        self.check_underflow(1);
+        let index = index as usize;
         match segment {
-            "local" => self.pop_to_segment("LCL", index),
-            "argument" => self.pop_to_segment("ARG", index),
-            "this" => self.pop_to_segment("THIS", index),
-            "that" => self.pop_to_segment("THAT", index),
-            "temp" => {
+            Segment::Local => self.pop_to_segment("LCL", index),
+            Segment::Argument => self.pop_to_segment("ARG", index),
+            Segment::This => self.pop_to_segment("THIS", index),
+            Segment::That => self.pop_to_segment("THAT", index),
+            Segment::Temp => {
                 self.pop_to_d();
                 self.write_line(&format!("@{}", 5 + index));
                 self.write_line("M=D");
             }
-            "static" => {
+            Segment::Static => {
                 self.pop_to_d();
                 self.write_line(&format!("@{}.{}", self.filename, index));
                 self.write_line("M=D");
             }
-            "pointer" => {
+            Segment::Pointer => {
                 let addr = if index == 0 { "THIS" } else { "THAT" };
                 self.pop_to_d();
                 self.write_line(&format!("@{}", addr));
                 self.write_line("M=D");
             }
-            _ => panic!("Unknown segment: {}", segment),
+            Segment::Constant => panic!("Cannot pop to constant"),
         }
     }
 
@@ -316,7 +672,7 @@ impl CodeWriter {
         self.write_line(&format!("@{}", segment));
         self.write_line("A=D+M");
         self.write_line("D=M");
-        self.push_d();
+        self.store_d_and_increment_sp();
     }
 
     fn pop_to_segment(&mut self, segment: &str, index: usize) {
@@ -337,10 +693,22 @@ impl CodeWriter {
         self.write_line("M=D");
     }
 
-    fn write_comparison(&mut self, jump_type: &str) {
-        let true_label = format!("TRUE_{}", self.label_counter);
-        let end_label = format!("END_{}", self.label_counter);
+    /// Returns a new label for code generated by the translator, which
+    /// cannot collide with VM functions and labels.
+    fn generated_label(&mut self, name: &str) -> String {
+        let label = format!(
+            "{}{}.{}",
+            labels::GENERATED_PREFIX,
+            name,
+            self.label_counter
+        );
         self.label_counter += 1;
+        label
+    }
+
+    fn write_comparison(&mut self, jump_type: &str) {
+        let true_label = self.generated_label("TRUE");
+        let end_label = self.generated_label("FALSE_END");
 
         self.write_line(&format!("// {}", jump_type.to_lowercase()));
         self.pop_to_d();
@@ -368,7 +736,25 @@ impl CodeWriter {
         self.increment_sp();
     }
 
-    fn push_d(&mut self) {
+    /// Calls a shared routine of the extended commands, which pops its
+    /// operands and pushes the result itself.
+    fn write_routine_call(&mut self, command: &str, routine: &'static str) {
+        // The routine names already carry the generated prefix
+        let return_label = format!("{}.RET.{}", routine, self.label_counter);
+        self.label_counter += 1;
+        self.routines.insert(routine);
+
+        self.write_line(&format!("// {}", command));
+        self.write_line(&format!("@{}", return_label));
+        self.write_line("D=A");
+        self.write_line("@R14");
+        self.write_line("M=D");
+        self.write_line(&format!("@{}", routine));
+        self.write_line("0;JMP");
+        self.write_line(&format!("({})", return_label));
+    }
+
+    fn store_d_and_increment_sp(&mut self) {
         self.write_line("@SP");
         self.write_line("A=M");
         self.write_line("M=D");
@@ -391,11 +777,138 @@ impl CodeWriter {
         self.write_line("M=M-1");
     }
 
+    /// Jumps to the overflow handler if pushing `count` more values would
+    /// take SP past the stack limit. Commands check before their first
+    /// store, so that nothing is written at or above the limit.
+    fn check_overflow(&mut self, count: usize) {
+        let Some(limit) = self.stack_limit else {
+            return;
+        };
+        self.write_line("@SP");
+        self.write_line("D=M");
+        self.write_line(&format!("@{}", limit.saturating_sub(count)));
+        self.write_line("D=D-A");
+        self.write_line("@$$STACK_OVERFLOW");
+        self.write_line("D;JGT");
+    }
+
+    /// Jumps to the underflow handler if popping `count` values would take SP
+    /// below the frame base: LCL plus the locals of the current function, or
+    /// the stack base outside functions. Commands check once before their
+    /// first `decrement_sp`, as D is live between the pops of binary
+    /// operations.
+    fn check_underflow(&mut self, count: usize) {
+        if self.stack_limit.is_none() {
+            return;
+        }
+        self.write_line("@SP");
+        self.write_line("D=M");
+        match self.num_locals {
+            Some(num_locals) => {
+                self.write_line("@LCL");
+                self.write_line("D=D-M");
+                self.write_line(&format!("@{}", num_locals + count));
+            }
+            None => self.write_line(&format!("@{}", self.stack_base + count)),
+        }
+        self.write_line("D=D-A");
+        self.write_line("@$$STACK_UNDERFLOW");
+        self.write_line("D;JLT");
+    }
+
+    /// Writes the `$$END` loop that keeps code running off the end of the
+    /// program out of the code written after it, once.
+    fn write_halt(&mut self) {
+        if self.halt_written {
+            return;
+        }
+        self.halt_written = true;
+        self.write_line("// Halt");
+        self.write_line("($$END)");
+        self.write_line("@$$END");
+        self.write_line("0;JMP");
+    }
+
+    /// Writes the handlers the stack guards jump to, behind a halt loop so
+    /// that code running off the end of the program does not reach them.
+    /// Does nothing unless stack guards are enabled.
+    pub fn write_stack_handlers(&mut self) {
+        if self.stack_limit.is_none() {
+            return;
+        }
+        self.write_halt();
+        self.write_line("// Stack guard handlers");
+        for (label, code) in [("$$STACK_OVERFLOW", 1), ("$$STACK_UNDERFLOW", 2)] {
+            self.write_line(&format!("({})", label));
+            self.write_line(&format!("@{}", code));
+            self.write_line("D=A");
+            self.write_line(&format!("@{}", STACK_ERROR_REGISTER));
+            self.write_line("M=D");
+            self.write_line("@$$STACK_HALT");
+            self.write_line("0;JMP");
+        }
+        self.write_line("($$STACK_HALT)");
+        self.write_line("@$$STACK_HALT");
+        self.write_line("0;JMP");
+    }
+
     fn write_line(&mut self, line: &str) {
         writeln!(self.output, "{}", line).expect("Failed to write line");
+        self.asm_lines += 1;
+        if !line.starts_with("//") && !line.starts_with('(') {
+            self.rom_address += 1;
+        }
     }
 
     pub fn close(&mut self) {
         self.output.flush().expect("Failed to flush output");
     }
+
+    /// Closes the writer and returns the underlying output.
+    pub fn into_inner(mut self) -> W {
+        self.close();
+        self.output
+            .into_inner()
+            .unwrap_or_else(|_| panic!("Failed to flush output"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_map_and_annotations() {
+        let mut code_writer = CodeWriter::new(Vec::new(), "Main".to_string());
+        code_writer.set_annotate(true);
+        code_writer.set_source("Main", 1, "Main.f");
+        code_writer.write_function("Main.f", 0);
+        code_writer.set_source("Main", 2, "Main.f");
+        code_writer.write_push(Segment::Constant, 7);
+
+        let entries = code_writer.source_map();
+        assert_eq!((entries[0].asm_line, entries[0].rom_address), (1, 0));
+        // Comments and labels take no ROM space
+        assert_eq!((entries[1].asm_line, entries[1].rom_address), (4, 0));
+
+        let asm = String::from_utf8(code_writer.into_inner()).unwrap();
+        let lines: Vec<&str> = asm.lines().collect();
+        assert_eq!(lines[0], "// Main.vm:1 Main.f");
+        assert_eq!(lines[3], "// Main.vm:2 Main.f");
+    }
+
+    #[test]
+    fn test_halt_before_runtime() {
+        let mut code_writer = CodeWriter::new(Vec::new(), "Main".to_string());
+        code_writer.set_stack_guard(DEFAULT_STACK_LIMIT);
+        code_writer.write_arithmetic(Op::Mul);
+        code_writer.write_extended_routines();
+        code_writer.write_stack_handlers();
+
+        let asm = String::from_utf8(code_writer.into_inner()).unwrap();
+        let lines: Vec<&str> = asm.lines().collect();
+        let position = |line| lines.iter().position(|&l| l == line).unwrap();
+        assert!(position("($$END)") < position("($$MUL)"));
+        assert_eq!(lines.iter().filter(|&&l| l == "($$END)").count(), 1);
+    }
 }
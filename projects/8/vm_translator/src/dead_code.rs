@@ -0,0 +1,104 @@
+//! Dead function elimination: drops every function that cannot be reached
+//! through `call` commands from the program's entry function.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::parser::VmCommand;
+use crate::program::VmFile;
+
+/// A function removed by `strip_unused`.
+pub struct RemovedFunction {
+    pub name: String,
+    pub file: String,
+    /// Number of VM commands in the function, including its `function` command
+    pub commands: usize,
+}
+
+/// Removes functions unreachable from `entry`.
+///
+/// Returns `None` without touching the program if `entry` is not defined,
+/// since everything would be considered dead. Code appearing before the
+/// first `function` of a file is always kept.
+pub fn strip_unused(files: &mut [VmFile], entry: &str) -> Option<Vec<RemovedFunction>> {
+    // Map every function to the functions it calls
+    let mut calls: HashMap<String, Vec<String>> = HashMap::new();
+    for file in files.iter() {
+        for function in file.functions() {
+            let callees = file.commands[function.start..function.end]
+                .iter()
+                .filter_map(|command| match &command.command {
+                    VmCommand::Call { name, .. } => Some(name.clone()),
+                    _ => None,
+                })
+                .collect();
+            calls.insert(function.name, callees);
+        }
+    }
+
+    if !calls.contains_key(entry) {
+        return None;
+    }
+
+    let mut reachable: HashSet<&str> = HashSet::new();
+    let mut worklist = vec![entry];
+    while let Some(name) = worklist.pop() {
+        if !reachable.insert(name) {
+            continue;
+        }
+        if let Some(callees) = calls.get(name) {
+            worklist.extend(callees.iter().map(String::as_str));
+        }
+    }
+
+    let mut removed = Vec::new();
+    for file in files.iter_mut() {
+        let first_removed = removed.len();
+        // Walk backwards so earlier spans stay valid while draining
+        for function in file.functions().into_iter().rev() {
+            if !reachable.contains(function.name.as_str()) {
+                file.commands.drain(function.start..function.end);
+                removed.push(RemovedFunction {
+                    name: function.name,
+                    file: file.name.clone(),
+                    commands: function.end - function.start,
+                });
+            }
+        }
+        removed[first_removed..].reverse();
+    }
+
+    Some(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_unused() {
+        let mut files = vec![
+            VmFile::parse(
+                "Sys",
+                "function Sys.init 0\ncall Main.main 0\nreturn\nfunction Sys.halt 0\nreturn",
+            ),
+            VmFile::parse(
+                "Main",
+                "function Main.main 0\ncall Main.helper 0\nreturn\nfunction Main.helper 0\nreturn\nfunction Main.unused 0\ncall Sys.halt 0\nreturn",
+            ),
+        ];
+
+        let removed = strip_unused(&mut files, "Sys.init").unwrap();
+        let names: Vec<&str> = removed.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["Sys.halt", "Main.unused"]);
+        assert_eq!(removed[1].commands, 3);
+        assert_eq!(files[0].commands.len(), 3);
+        assert_eq!(files[1].commands.len(), 5);
+    }
+
+    #[test]
+    fn test_missing_entry_keeps_everything() {
+        let mut files = vec![VmFile::parse("Main", "function Main.main 0\nreturn")];
+        assert!(strip_unused(&mut files, "Sys.init").is_none());
+        assert_eq!(files[0].commands.len(), 2);
+    }
+}
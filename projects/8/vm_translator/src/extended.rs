@@ -0,0 +1,398 @@
+//! Extended VM commands: `mul`, `div`, `mod`, `shl`, `shr`, and the `neg`
+//! variants `nmul`, `ndiv` and `nmod`.
+//!
+//! They are only accepted with `--ext`. They call shared Hack routines,
+//! emitted once after the program behind a halt loop. A routine pops
+//! its operands, leaves the result on the stack and jumps back to the
+//! address in R14. Routine variables, such as `$$B` for the top operand,
+//! are allocated by the assembler like statics, after those of the
+//! program since the routines come last. R15 is left to the stack guards.
+//!
+//! Semantics, on 16-bit two's complement values:
+//! - `mul` wraps around like `add`
+//! - `div` truncates toward zero, `mod` has the sign of the dividend;
+//!   `x / 0` is 0 and `x mod 0` is x
+//! - `shl`/`shr` shift left/right (logical) by 0 to 15 bits; other shift
+//!   amounts give 0
+//! - `nmul`, `ndiv` and `nmod` negate the result of `mul`, `div` and `mod`,
+//!   like the same command followed by `neg`, which `--opt-vm` fuses
+
+use crate::parser::{Op, VmCommand};
+use crate::program::VmFile;
+
+/// Multiplication by shift-and-add, stopping when no bits of b are left.
+pub const MUL: &str = "
+($$MUL)
+@SP
+AM=M-1
+D=M
+@$$B
+M=D
+@SP
+A=M-1
+D=M
+@R13
+M=D
+@$$MUL.SUM
+M=0
+@$$MUL.BIT
+M=1
+($$MUL.LOOP)
+@$$B
+D=M
+@$$MUL.END
+D;JEQ
+@$$MUL.BIT
+D=M
+@$$B
+D=D&M
+@$$MUL.SKIP
+D;JEQ
+@R13
+D=M
+@$$MUL.SUM
+M=D+M
+@$$MUL.BIT
+D=!M
+@$$B
+M=D&M
+($$MUL.SKIP)
+@R13
+D=M
+M=D+M
+@$$MUL.BIT
+D=M
+M=D+M
+@$$MUL.LOOP
+0;JMP
+($$MUL.END)
+@$$MUL.SUM
+D=M
+@SP
+A=M-1
+M=D
+@R14
+A=M
+0;JMP
+";
+
+/// Long division of the absolute values, one bit of the dividend at a time;
+/// the signs are applied at the end. Leaves the quotient on the stack and
+/// the remainder in R13.
+pub const DIVMOD: &str = "
+($$DIVMOD)
+@SP
+AM=M-1
+D=M
+@$$B
+M=D
+@SP
+A=M-1
+D=M
+@R13
+M=D
+@$$DIV.QNEG
+M=0
+@$$DIV.RNEG
+M=0
+@R13
+D=M
+@$$DIV.APOS
+D;JGE
+@R13
+M=-M
+@$$DIV.QNEG
+M=!M
+@$$DIV.RNEG
+M=-1
+($$DIV.APOS)
+@$$B
+D=M
+@$$DIV.BPOS
+D;JGE
+@$$B
+M=-M
+@$$DIV.QNEG
+M=!M
+($$DIV.BPOS)
+@R13
+D=M
+@$$DIV.R
+M=D
+@$$DIV.Q
+M=0
+@$$B
+D=M
+@$$DIV.SIGNS
+D;JEQ
+@$$DIV.BMIN
+D;JLT
+@$$DIV.R
+M=0
+@$$DIV.BIT
+M=1
+($$DIV.LOOP)
+@$$DIV.R
+D=M
+M=D+M
+@R13
+D=M
+M=D+M
+@$$DIV.NOBIT
+D;JGE
+@$$DIV.R
+M=M+1
+($$DIV.NOBIT)
+@$$DIV.Q
+D=M
+M=D+M
+@$$DIV.R
+D=M
+@$$DIV.SUB
+D;JLT
+@$$B
+D=D-M
+@$$DIV.NEXT
+D;JLT
+($$DIV.SUB)
+@$$B
+D=M
+@$$DIV.R
+M=M-D
+@$$DIV.Q
+M=M+1
+($$DIV.NEXT)
+@$$DIV.BIT
+D=M
+MD=D+M
+@$$DIV.LOOP
+D;JNE
+@$$DIV.SIGNS
+0;JMP
+($$DIV.BMIN)
+@R13
+D=M
+@$$DIV.SIGNS
+D;JGE
+@$$DIV.Q
+M=1
+@$$DIV.R
+M=0
+($$DIV.SIGNS)
+@$$DIV.QNEG
+D=M
+@$$DIV.QPOS
+D;JEQ
+@$$DIV.Q
+M=-M
+($$DIV.QPOS)
+@$$DIV.RNEG
+D=M
+@$$DIV.RPOS
+D;JEQ
+@$$DIV.R
+M=-M
+($$DIV.RPOS)
+@$$DIV.R
+D=M
+@R13
+M=D
+@$$DIV.Q
+D=M
+@SP
+A=M-1
+M=D
+@R14
+A=M
+0;JMP
+";
+
+/// Left shift by repeated doubling.
+pub const SHL: &str = "
+($$SHL)
+@SP
+AM=M-1
+D=M
+@$$B
+M=D
+@SP
+A=M-1
+D=M
+@R13
+M=D
+@$$B
+D=M
+@$$SHL.ZERO
+D;JLT
+@16
+D=D-A
+@$$SHL.ZERO
+D;JGE
+($$SHL.LOOP)
+@$$B
+D=M
+@$$SHL.END
+D;JEQ
+@$$B
+M=D-1
+@R13
+D=M
+M=D+M
+@$$SHL.LOOP
+0;JMP
+($$SHL.ZERO)
+@R13
+M=0
+($$SHL.END)
+@R13
+D=M
+@SP
+A=M-1
+M=D
+@R14
+A=M
+0;JMP
+";
+
+/// Logical right shift: copies bit i+b of a to bit i of the result.
+pub const SHR: &str = "
+($$SHR)
+@SP
+AM=M-1
+D=M
+@$$B
+M=D
+@SP
+A=M-1
+D=M
+@R13
+M=D
+@$$SHR.RESULT
+M=0
+@$$B
+D=M
+@$$SHR.END
+D;JLT
+@16
+D=D-A
+@$$SHR.END
+D;JGE
+@$$SHR.BIT
+M=1
+@$$SHR.SRC
+M=1
+($$SHR.FIND)
+@$$B
+D=M
+@$$SHR.LOOP
+D;JEQ
+@$$B
+M=D-1
+@$$SHR.SRC
+D=M
+M=D+M
+@$$SHR.FIND
+0;JMP
+($$SHR.LOOP)
+@$$SHR.SRC
+D=M
+@$$SHR.END
+D;JEQ
+@R13
+D=D&M
+@$$SHR.SKIP
+D;JEQ
+@$$SHR.BIT
+D=M
+@$$SHR.RESULT
+M=D|M
+($$SHR.SKIP)
+@$$SHR.SRC
+D=M
+M=D+M
+@$$SHR.BIT
+D=M
+M=D+M
+@$$SHR.LOOP
+0;JMP
+($$SHR.END)
+@$$SHR.RESULT
+D=M
+@SP
+A=M-1
+M=D
+@R14
+A=M
+0;JMP
+";
+
+/// OS functions with an extended command equivalent.
+const MATH_CALLS: [(&str, Op); 2] = [("Math.multiply", Op::Mul), ("Math.divide", Op::Div)];
+
+/// Returns the OS functions with an extended command equivalent that the
+/// program defines itself, whose code `rewrite_math_calls` bypasses.
+pub fn defined_math_functions(files: &[VmFile]) -> Vec<&'static str> {
+    MATH_CALLS
+        .iter()
+        .map(|&(function, _)| function)
+        .filter(|&function| {
+            files.iter().any(|file| {
+                file.commands.iter().any(|command| {
+                    matches!(&command.command, VmCommand::Function { name, .. } if name == function)
+                })
+            })
+        })
+        .collect()
+}
+
+/// Replaces `call Math.multiply 2` and `call Math.divide 2` with `mul` and
+/// `div`. Division by zero then gives 0 instead of calling `Sys.error`.
+/// Returns the number of calls replaced.
+pub fn rewrite_math_calls(files: &mut [VmFile]) -> usize {
+    let mut rewritten = 0;
+    for command in files.iter_mut().flat_map(|file| &mut file.commands) {
+        let VmCommand::Call { name, n_args: 2 } = &command.command else {
+            continue;
+        };
+        if let Some(&(_, op)) = MATH_CALLS.iter().find(|(function, _)| name == function) {
+            command.command = VmCommand::Arithmetic(op);
+            rewritten += 1;
+        }
+    }
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_math_calls() {
+        let mut files = vec![VmFile::parse(
+            "Main",
+            "push constant 6\npush constant 7\ncall Math.multiply 2\ncall Math.max 2\ncall Math.divide 2",
+        )];
+        assert_eq!(rewrite_math_calls(&mut files), 2);
+        let source: Vec<String> = files[0].commands.iter().map(|c| c.to_string()).collect();
+        assert_eq!(source[2..], ["mul", "call Math.max 2", "div"]);
+    }
+
+    #[test]
+    fn test_defined_math_functions() {
+        let files = vec![
+            VmFile::parse(
+                "Main",
+                "function Main.main 0
+call Math.divide 2
+return",
+            ),
+            VmFile::parse(
+                "Math",
+                "function Math.divide 0
+push constant 0
+return",
+            ),
+        ];
+        assert_eq!(defined_math_functions(&files), ["Math.divide"]);
+    }
+}
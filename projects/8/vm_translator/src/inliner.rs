@@ -0,0 +1,421 @@
+//! Inlining of small, non-recursive VM functions.
+//!
+//! A `call f n` is replaced by the body of `f`. The callee's arguments and
+//! locals live in extra local slots appended to the caller's frame, its
+//! labels are renamed with the generated `$$` prefix to stay apart from the
+//! caller's, and every `return` becomes a jump to the end of the inlined
+//! block, leaving the return value on the stack exactly as a real call would.
+//!
+//! The caller's frame grows by those slots. Nothing is inlined into the
+//! entry function, whose frame stays on the stack when the program ends and
+//! is checked by test scripts such as projects/8 StaticsTest.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::labels;
+use crate::parser::{Command, Segment, VmCommand};
+use crate::program::VmFile;
+
+/// Default maximum body size, in VM commands, of an inlined function.
+/// Large enough for accessors like `Square.getX` and trivial setters.
+pub const DEFAULT_THRESHOLD: usize = 8;
+
+/// A function that may be substituted at its call sites.
+struct Callee {
+    file: String,
+    num_locals: u16,
+    /// Commands after the `function` command
+    body: Vec<Command>,
+    /// Number of argument slots the body reads
+    num_args: u16,
+    /// `pointer` indices written by the body, restored after the inlined code
+    pointers: Vec<u16>,
+}
+
+/// Inlines calls to functions of at most `threshold` commands, except in
+/// `entry`. Returns the number of call sites replaced.
+pub fn inline_small_functions(
+    files: &mut [VmFile],
+    threshold: usize,
+    entry: Option<&str>,
+) -> usize {
+    let callees = find_callees(files, threshold);
+    let mut label_counter = 0;
+    let mut inlined = 0;
+
+    for file in files.iter_mut() {
+        // Walk backwards so earlier spans stay valid while splicing
+        for function in file.functions().into_iter().rev() {
+            if entry == Some(function.name.as_str()) {
+                continue;
+            }
+            let mut header = file.commands[function.start].clone();
+            let VmCommand::Function { n_locals, .. } = &mut header.command else {
+                unreachable!("function span without a function command")
+            };
+            let num_locals = *n_locals;
+            let mut body = Vec::new();
+            let mut extra_locals = 0;
+            let mut changed = false;
+
+            for command in &file.commands[function.start + 1..function.end] {
+                let callee = match &command.command {
+                    VmCommand::Call { name, n_args } if *name != function.name => {
+                        callees.get(name.as_str()).map(|callee| (callee, *n_args))
+                    }
+                    _ => None,
+                };
+                let Some((callee, num_args)) = callee else {
+                    body.push(command.clone());
+                    continue;
+                };
+
+                let used = expand_call(
+                    callee,
+                    num_args,
+                    num_locals,
+                    &file.name,
+                    command.line,
+                    label_counter,
+                    &mut body,
+                );
+                extra_locals = extra_locals.max(used);
+                label_counter += 1;
+                inlined += 1;
+                changed = true;
+            }
+
+            if changed {
+                *n_locals = num_locals + extra_locals;
+                body.insert(0, header);
+                file.commands.splice(function.start..function.end, body);
+            }
+        }
+    }
+
+    inlined
+}
+
+/// Collects the functions that can be inlined: small, balanced and not
+/// part of a recursive cycle.
+fn find_callees(files: &[VmFile], threshold: usize) -> HashMap<String, Callee> {
+    let mut calls: HashMap<String, Vec<String>> = HashMap::new();
+    let mut candidates = HashMap::new();
+
+    for file in files {
+        for function in file.functions() {
+            let header = &file.commands[function.start];
+            let body = &file.commands[function.start + 1..function.end];
+
+            calls.insert(
+                function.name.clone(),
+                body.iter()
+                    .filter_map(|command| match &command.command {
+                        VmCommand::Call { name, .. } => Some(name.clone()),
+                        _ => None,
+                    })
+                    .collect(),
+            );
+
+            if body.len() > threshold || !is_balanced(body) {
+                continue;
+            }
+
+            let num_args = body
+                .iter()
+                .filter_map(|command| match command.command {
+                    VmCommand::Push {
+                        segment: Segment::Argument,
+                        index,
+                    }
+                    | VmCommand::Pop {
+                        segment: Segment::Argument,
+                        index,
+                    } => Some(index + 1),
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(0);
+            let mut pointers: Vec<u16> = body
+                .iter()
+                .filter_map(|command| match command.command {
+                    VmCommand::Pop {
+                        segment: Segment::Pointer,
+                        index,
+                    } => Some(index),
+                    _ => None,
+                })
+                .collect();
+            pointers.sort();
+            pointers.dedup();
+
+            let VmCommand::Function { n_locals, .. } = header.command else {
+                unreachable!("function span without a function command")
+            };
+            candidates.insert(
+                function.name,
+                Callee {
+                    file: file.name.clone(),
+                    num_locals: n_locals,
+                    body: body.to_vec(),
+                    num_args,
+                    pointers,
+                },
+            );
+        }
+    }
+
+    candidates.retain(|name, _| !is_recursive(name, &calls));
+    candidates
+}
+
+/// Returns true if `name` can reach itself through calls.
+fn is_recursive(name: &str, calls: &HashMap<String, Vec<String>>) -> bool {
+    let mut visited: HashSet<&str> = HashSet::new();
+    let mut worklist: Vec<&str> = calls
+        .get(name)
+        .map(|callees| callees.iter().map(String::as_str).collect())
+        .unwrap_or_default();
+
+    while let Some(current) = worklist.pop() {
+        if current == name {
+            return true;
+        }
+        if visited.insert(current)
+            && let Some(callees) = calls.get(current)
+        {
+            worklist.extend(callees.iter().map(String::as_str));
+        }
+    }
+    false
+}
+
+/// Checks that the body never underflows the operand stack, reaches every
+/// label with the same depth and returns with exactly one value on the stack.
+/// Only then is replacing `return` with a jump equivalent to a real return.
+fn is_balanced(body: &[Command]) -> bool {
+    let mut label_depths: HashMap<&str, usize> = HashMap::new();
+    let mut depth = Some(0);
+
+    for command in body {
+        match &command.command {
+            VmCommand::Label(label) => {
+                let expected = label_depths.get(label.as_str()).copied();
+                depth = match (depth, expected) {
+                    (Some(d), Some(e)) if d != e => return false,
+                    (Some(d), _) => Some(d),
+                    (None, e) => e,
+                };
+                // A label only reached by a later backward jump
+                let Some(d) = depth else { return false };
+                label_depths.insert(label, d);
+                continue;
+            }
+            VmCommand::Function { .. } => return false,
+            _ => {}
+        }
+
+        // Unreachable code after goto/return
+        let Some(d) = depth else { continue };
+        let (pops, pushes) = command.stack_effect();
+        if d < pops {
+            return false;
+        }
+        let d = d - pops + pushes;
+        depth = Some(d);
+
+        match &command.command {
+            VmCommand::Goto(label) | VmCommand::IfGoto(label) => {
+                if *label_depths.entry(label).or_insert(d) != d {
+                    return false;
+                }
+                if matches!(command.command, VmCommand::Goto(_)) {
+                    depth = None;
+                }
+            }
+            VmCommand::Return => {
+                if d != 0 {
+                    return false;
+                }
+                depth = None;
+            }
+            _ => {}
+        }
+    }
+
+    // Falling off the end of a function is not a return
+    depth.is_none()
+}
+
+/// Appends the inlined body of `callee` to `output`, given that the caller
+/// has `base` locals of its own. Returns the number of extra local slots used.
+fn expand_call(
+    callee: &Callee,
+    num_args: u16,
+    base: u16,
+    caller_file: &str,
+    line: usize,
+    id: usize,
+    output: &mut Vec<Command>,
+) -> u16 {
+    let num_args_slots = callee.num_args.max(num_args);
+    let locals_base = base + num_args_slots;
+    let saves_base = locals_base + callee.num_locals;
+    let end_label = format!("{}INLINE{}.END", labels::GENERATED_PREFIX, id);
+    let file = (callee.file != caller_file).then(|| callee.file.clone());
+    let push = |segment, index| Command::new(VmCommand::Push { segment, index }, line);
+    let pop = |segment, index| Command::new(VmCommand::Pop { segment, index }, line);
+
+    // Pop the arguments into the callee's slots, last argument first
+    for i in (0..num_args).rev() {
+        output.push(pop(Segment::Local, base + i));
+    }
+    // Locals start out as 0, even when the inlined code runs in a loop
+    for j in 0..callee.num_locals {
+        output.push(push(Segment::Constant, 0));
+        output.push(pop(Segment::Local, locals_base + j));
+    }
+    // THIS/THAT are part of the frame a real call would save
+    for (slot, &pointer) in (saves_base..).zip(&callee.pointers) {
+        output.push(push(Segment::Pointer, pointer));
+        output.push(pop(Segment::Local, slot));
+    }
+
+    let mut needs_end_label = false;
+    for (i, command) in callee.body.iter().enumerate() {
+        let mut command = command.clone();
+        match &mut command.command {
+            VmCommand::Push { segment, index } | VmCommand::Pop { segment, index } => match segment
+            {
+                Segment::Argument => {
+                    *segment = Segment::Local;
+                    *index += base;
+                }
+                Segment::Local => *index += locals_base,
+                Segment::Static => command.file = command.file.or_else(|| file.clone()),
+                _ => {}
+            },
+            VmCommand::Label(label) | VmCommand::Goto(label) | VmCommand::IfGoto(label) => {
+                *label = format!("{}INLINE{}.{}", labels::GENERATED_PREFIX, id, label);
+            }
+            VmCommand::Return => {
+                if i + 1 == callee.body.len() {
+                    continue;
+                }
+                needs_end_label = true;
+                command.command = VmCommand::Goto(end_label.clone());
+            }
+            _ => {}
+        }
+        output.push(command);
+    }
+
+    if needs_end_label {
+        output.push(Command::new(VmCommand::Label(end_label), line));
+    }
+    for (slot, &pointer) in (saves_base..).zip(&callee.pointers) {
+        output.push(push(Segment::Local, slot));
+        output.push(pop(Segment::Pointer, pointer));
+    }
+
+    num_args_slots + callee.num_locals + callee.pointers.len() as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(file: &VmFile) -> String {
+        file.commands
+            .iter()
+            .map(|command| command.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_inline_accessor() {
+        let mut files = vec![
+            VmFile::parse(
+                "Main",
+                "function Main.main 1\npush local 0\ncall Square.getX 1\nreturn",
+            ),
+            VmFile::parse(
+                "Square",
+                "function Square.getX 0\npush argument 0\npop pointer 0\npush this 0\npush static 2\nadd\nreturn",
+            ),
+        ];
+
+        assert_eq!(
+            inline_small_functions(&mut files, DEFAULT_THRESHOLD, None),
+            1
+        );
+        assert_eq!(
+            source(&files[0]),
+            "function Main.main 3\npush local 0\npop local 1\n\
+             push pointer 0\npop local 2\n\
+             push local 1\npop pointer 0\npush this 0\npush static 2\nadd\n\
+             push local 2\npop pointer 0\nreturn"
+        );
+        // The static still belongs to Square.vm
+        let static_push = files[0]
+            .commands
+            .iter()
+            .find(|c| c.to_string() == "push static 2");
+        assert_eq!(static_push.unwrap().file.as_deref(), Some("Square"));
+    }
+
+    #[test]
+    fn test_labels_and_returns() {
+        let mut files = vec![VmFile::parse(
+            "Main",
+            "function Main.main 0\npush constant 1\ncall Main.abs 1\nlabel INLINE0.END\nreturn\n\
+             function Main.abs 0\npush argument 0\npush constant 0\nlt\nif-goto NEG\n\
+             push argument 0\nreturn\nlabel NEG\npush argument 0\nneg\nreturn",
+        )];
+
+        assert_eq!(inline_small_functions(&mut files, 16, None), 1);
+        let main: Vec<String> = files[0].commands[..14]
+            .iter()
+            .map(|command| command.to_string())
+            .collect();
+        assert_eq!(main[0], "function Main.main 1");
+        assert!(main.contains(&"if-goto $$INLINE0.NEG".to_string()));
+        assert!(main.contains(&"goto $$INLINE0.END".to_string()));
+        assert_eq!(main[12], "label $$INLINE0.END");
+        // The caller's own label of the same name stays distinct
+        assert_eq!(main[13], "label INLINE0.END");
+    }
+
+    #[test]
+    fn test_recursive_functions_are_kept() {
+        let mut files = vec![VmFile::parse(
+            "Main",
+            "function Main.main 0\npush constant 1\ncall Main.f 1\nreturn\n\
+             function Main.f 0\npush argument 0\ncall Main.f 1\nreturn",
+        )];
+
+        assert_eq!(
+            inline_small_functions(&mut files, DEFAULT_THRESHOLD, None),
+            0
+        );
+    }
+
+    #[test]
+    fn test_entry_function_is_kept() {
+        let mut files = vec![VmFile::parse(
+            "Sys",
+            "function Sys.init 0\npush constant 1\ncall Sys.id 1\ncall Sys.main 0\nreturn\n\
+             function Sys.main 0\npush constant 2\ncall Sys.id 1\nreturn\n\
+             function Sys.id 0\npush argument 0\nreturn",
+        )];
+
+        assert_eq!(
+            inline_small_functions(&mut files, DEFAULT_THRESHOLD, Some("Sys.init")),
+            1
+        );
+        let init = &files[0].commands[..5];
+        assert_eq!(init[0].to_string(), "function Sys.init 0");
+        assert_eq!(init[2].to_string(), "call Sys.id 1");
+    }
+}
@@ -0,0 +1,167 @@
+//! Naming of assembly labels.
+//!
+//! VM identifiers may only contain letters, digits, `_`, `.` and `:`, so
+//! the translator can keep its labels apart from the program's:
+//! - functions keep their VM name: `Main.main`
+//! - a label `L` inside function `f` becomes `f$L`
+//! - labels generated by the translator start with `$$`, which no VM
+//!   identifier and no `f$L` label can contain
+//!
+//! `check_assembly` verifies the result before it is written, as the
+//! assembler silently keeps the last of two definitions of a label.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::parser::{Command, VmCommand};
+
+/// Prefix reserved for labels generated by the translator.
+pub const GENERATED_PREFIX: &str = "$$";
+
+/// Symbols predefined by the Hack assembler, which a label cannot redefine.
+const PREDEFINED: [&str; 7] = ["SP", "LCL", "ARG", "THIS", "THAT", "SCREEN", "KBD"];
+
+/// Returns true if `name` is a valid VM identifier: a sequence of letters,
+/// digits, `_`, `.` and `:` not starting with a digit.
+pub fn is_identifier(name: &str) -> bool {
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | ':');
+    name.chars().next().is_some_and(|c| !c.is_ascii_digit()) && name.chars().all(valid)
+}
+
+/// Returns the assembly label of VM label `label` inside `function`.
+pub fn user_label(function: &str, label: &str) -> String {
+    format!("{}${}", function, label)
+}
+
+fn is_predefined(label: &str) -> bool {
+    PREDEFINED.contains(&label) || (0..16).any(|register| label == format!("R{}", register))
+}
+
+/// Checks that every label of `asm` is defined once and does not redefine a
+/// predefined symbol. Returns one message per problem.
+pub fn check_assembly(asm: &str) -> Vec<String> {
+    let mut errors = Vec::new();
+    let mut defined: HashMap<&str, usize> = HashMap::new();
+
+    for (number, line) in asm.lines().enumerate() {
+        let Some(label) = line
+            .trim()
+            .strip_prefix('(')
+            .and_then(|rest| rest.strip_suffix(')'))
+        else {
+            continue;
+        };
+        let line = number + 1;
+        if is_predefined(label) {
+            errors.push(format!(
+                "label {} at assembly line {} redefines a predefined symbol",
+                label, line
+            ));
+        }
+        if let Some(first) = defined.insert(label, line) {
+            errors.push(format!(
+                "label {} at assembly line {} is already defined at line {}",
+                label, line, first
+            ));
+            defined.insert(label, first);
+        }
+    }
+    errors
+}
+
+/// Renames the labels generated by passes such as inlining, which VM code
+/// cannot contain, so that written-out VM code parses again: `$$L` becomes
+/// `L`, or `L_1`, `L_2`... if the function already has a label `L`.
+pub fn vm_labels(commands: &[Command]) -> Vec<Command> {
+    let mut output = commands.to_vec();
+    let starts: Vec<usize> = (0..output.len())
+        .filter(|&i| i == 0 || matches!(output[i].command, VmCommand::Function { .. }))
+        .chain([output.len()])
+        .collect();
+    for span in starts.windows(2) {
+        let function = &mut output[span[0]..span[1]];
+        let mut used: HashSet<String> = function
+            .iter()
+            .filter_map(|command| label_of(&command.command))
+            .filter(|label| !label.starts_with(GENERATED_PREFIX))
+            .cloned()
+            .collect();
+        let mut renamed: HashMap<String, String> = HashMap::new();
+        for command in function.iter_mut() {
+            let (VmCommand::Label(label) | VmCommand::Goto(label) | VmCommand::IfGoto(label)) =
+                &mut command.command
+            else {
+                continue;
+            };
+            let Some(name) = label.strip_prefix(GENERATED_PREFIX) else {
+                continue;
+            };
+            let new_label = renamed.entry(label.clone()).or_insert_with(|| {
+                let new_label = (0..)
+                    .map(|n| match n {
+                        0 => name.to_string(),
+                        n => format!("{}_{}", name, n),
+                    })
+                    .find(|candidate| !used.contains(candidate))
+                    .unwrap();
+                used.insert(new_label.clone());
+                new_label
+            });
+            *label = new_label.clone();
+        }
+    }
+    output
+}
+
+fn label_of(command: &VmCommand) -> Option<&String> {
+    match command {
+        VmCommand::Label(label) | VmCommand::Goto(label) | VmCommand::IfGoto(label) => Some(label),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identifiers() {
+        assert!(is_identifier("Main.main"));
+        assert!(is_identifier("IF_TRUE0"));
+        assert!(is_identifier("a:b"));
+        assert!(!is_identifier(""));
+        assert!(!is_identifier("0LOOP"));
+        assert!(!is_identifier("f$ret.0"));
+        assert!(!is_identifier("LOOP-1"));
+    }
+
+    #[test]
+    fn test_vm_labels() {
+        let source = "function Main.f 0\nlabel END\nlabel X\ngoto X\nreturn\n\
+                      function Main.g 0\nlabel X\ngoto X\nreturn";
+        let mut commands = crate::program::VmFile::parse("Main", source).commands;
+        for i in [2, 3, 6, 7] {
+            let (VmCommand::Label(label) | VmCommand::Goto(label)) = &mut commands[i].command
+            else {
+                unreachable!()
+            };
+            *label = "$$END".to_string();
+        }
+        let labels: Vec<String> = vm_labels(&commands)
+            .iter()
+            .filter_map(|command| label_of(&command.command).cloned())
+            .collect();
+        assert_eq!(labels, ["END", "END_1", "END_1", "END", "END"]);
+    }
+
+    #[test]
+    fn test_check_assembly() {
+        let asm = "(Main.main)\n@SP\n(Main.main$LOOP)\n(R15)\n(R16)\n(Main.main)\n";
+        assert_eq!(
+            check_assembly(asm),
+            [
+                "label R15 at assembly line 4 redefines a predefined symbol",
+                "label Main.main at assembly line 6 is already defined at line 1",
+            ]
+        );
+    }
+}
@@ -0,0 +1,18 @@
+//! VM translator library: the VM program model, the passes that run over
+//! it and the Hack code generator, shared by the `vm_translator` and
+//! `vm-dump` binaries.
+
+pub mod analysis;
+pub mod bytecode;
+pub mod code_writer;
+pub mod dead_code;
+pub mod extended;
+pub mod inliner;
+pub mod labels;
+pub mod linker;
+pub mod optimizer;
+pub mod parser;
+pub mod profile;
+pub mod program;
+pub mod size_report;
+pub mod verifier;
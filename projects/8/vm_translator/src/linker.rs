@@ -0,0 +1,190 @@
+//! Link-time checks across all the .vm files of a program.
+//!
+//! The Hack assembler silently turns an unknown jump target into a RAM
+//! variable, so a misspelled function or label would make the program jump
+//! into garbage. These checks catch such mistakes before translation.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::parser::VmCommand;
+use crate::program::VmFile;
+
+/// A problem found while linking, located at a VM command.
+#[derive(Debug)]
+pub struct LinkError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.vm:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+/// Checks that every called function is defined exactly once and always
+/// called with the same number of arguments, and that every `goto`/`if-goto`
+/// targets a label defined once in the enclosing function.
+pub fn check(files: &[VmFile]) -> Vec<LinkError> {
+    let mut errors = Vec::new();
+    let error = |file: &VmFile, line, message| LinkError {
+        file: file.name.clone(),
+        line,
+        message,
+    };
+
+    // Function definitions
+    let mut defined: HashMap<&str, (&str, usize)> = HashMap::new();
+    for file in files {
+        for command in &file.commands {
+            let VmCommand::Function { name, .. } = &command.command else {
+                continue;
+            };
+            if let Some((other_file, other_line)) = defined.get(name.as_str()) {
+                errors.push(error(
+                    file,
+                    command.line,
+                    format!(
+                        "function {} is already defined at {}.vm:{}",
+                        name, other_file, other_line
+                    ),
+                ));
+            } else {
+                defined.insert(name, (&file.name, command.line));
+            }
+        }
+    }
+
+    // Calls: the first call site of each function sets its expected arity
+    let mut arities: HashMap<&str, (u16, &str, usize)> = HashMap::new();
+    for file in files {
+        for command in &file.commands {
+            let VmCommand::Call { name, n_args } = &command.command else {
+                continue;
+            };
+            let name = name.as_str();
+            let num_args = *n_args;
+            if !defined.contains_key(name) {
+                errors.push(error(
+                    file,
+                    command.line,
+                    format!("call to undefined function {}", name),
+                ));
+            }
+            match arities.get(name) {
+                Some((expected, other_file, other_line)) if *expected != num_args => {
+                    errors.push(error(
+                        file,
+                        command.line,
+                        format!(
+                            "{} called with {} arguments, but with {} at {}.vm:{}",
+                            name, num_args, expected, other_file, other_line
+                        ),
+                    ));
+                }
+                Some(_) => {}
+                None => {
+                    arities.insert(name, (num_args, &file.name, command.line));
+                }
+            }
+        }
+    }
+
+    // Labels are scoped to their function; code before the first function
+    // of a file forms its own scope
+    for file in files {
+        let mut starts: Vec<usize> = file.functions().iter().map(|f| f.start).collect();
+        if starts.first() != Some(&0) {
+            starts.insert(0, 0);
+        }
+        starts.push(file.commands.len());
+
+        for scope in starts.windows(2) {
+            let commands = &file.commands[scope[0]..scope[1]];
+            let function = match commands.first().map(|command| &command.command) {
+                Some(VmCommand::Function { name, .. }) => name.as_str(),
+                _ => "top-level code",
+            };
+
+            let mut labels = HashSet::new();
+            for command in commands {
+                if let VmCommand::Label(label) = &command.command
+                    && !labels.insert(label)
+                {
+                    errors.push(error(
+                        file,
+                        command.line,
+                        format!("label {} is defined twice in {}", label, function),
+                    ));
+                }
+            }
+            for command in commands {
+                if let VmCommand::Goto(label) | VmCommand::IfGoto(label) = &command.command
+                    && !labels.contains(label)
+                {
+                    errors.push(error(
+                        file,
+                        command.line,
+                        format!("label {} is not defined in {}", label, function),
+                    ));
+                }
+            }
+        }
+    }
+
+    errors.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(files: &[VmFile]) -> Vec<String> {
+        check(files).iter().map(|error| error.to_string()).collect()
+    }
+
+    #[test]
+    fn test_valid_program() {
+        let files = vec![
+            VmFile::parse(
+                "Sys",
+                "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END",
+            ),
+            VmFile::parse("Main", "function Main.main 0\npush constant 0\nreturn"),
+        ];
+        assert!(check(&files).is_empty());
+    }
+
+    #[test]
+    fn test_undefined_function_and_arity() {
+        let files = vec![VmFile::parse(
+            "Main",
+            "function Main.main 0\ncall Main.hepler 0\ncall Main.f 1\ncall Main.f 2\nreturn\nfunction Main.f 0\nreturn",
+        )];
+        assert_eq!(
+            messages(&files),
+            [
+                "Main.vm:2: call to undefined function Main.hepler",
+                "Main.vm:4: Main.f called with 2 arguments, but with 1 at Main.vm:3",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels_are_scoped_to_functions() {
+        let files = vec![VmFile::parse(
+            "Main",
+            "function Main.a 0\nlabel LOOP\ngoto LOOP\nfunction Main.b 0\ngoto LOOP\nlabel X\nlabel X",
+        )];
+        assert_eq!(
+            messages(&files),
+            [
+                "Main.vm:5: label LOOP is not defined in Main.b",
+                "Main.vm:7: label X is defined twice in Main.b",
+            ]
+        );
+    }
+}
@@ -1,140 +1,787 @@
+use std::io::{self, Write};
 use std::{env, fs, path::Path, process};
 
-use code_writer::CodeWriter;
-use parser::{CommandType, Parser};
+use vm_translator::code_writer::{self, Bootstrap, CodeWriter, SourceMapEntry};
+use vm_translator::parser::{Command, VmCommand};
+use vm_translator::profile::ProfileLayout;
+use vm_translator::program::{self, VmFile};
+use vm_translator::size_report::{self, SizeReport};
+use vm_translator::{
+    analysis, bytecode, dead_code, extended, inliner, labels, linker, optimizer, verifier,
+};
 
-mod code_writer;
-mod parser;
+/// Number of instructions the Hack ROM can hold
+const ROM_SIZE: usize = 32768;
+
+/// Output format of the translator
+#[derive(Clone, Copy, PartialEq)]
+enum Emit {
+    /// Hack assembly (.asm)
+    Asm,
+    /// Hack machine code (.hack) and its symbol map (.sym)
+    Hack,
+    /// VM bytecode (.vmb), one file per .vm file
+    Vmb,
+}
+
+/// Command line options
+struct Options {
+    input: String,
+    /// Run the VM-to-VM optimizer and write the optimized .vm files to output/
+    optimize_vm: bool,
+    /// Drop functions unreachable from the entry function (directory mode)
+    strip_unused: bool,
+    /// Reuse the caller's frame for `call` immediately followed by `return`
+    tail_calls: bool,
+    /// Maximum body size of inlined functions, if inlining is enabled
+    inline_threshold: Option<usize>,
+    /// Report call graph and stack usage instead of translating
+    analyze: bool,
+    /// Check calls, labels and arities across files before translating:
+    /// forced on or off, or by default in directory mode only
+    link_check: Option<bool>,
+    /// Verify the stack discipline of every function before translating
+    verify: bool,
+    /// Stack limit checked at runtime, if stack guards are enabled
+    stack_limit: Option<usize>,
+    /// Output format
+    emit: Emit,
+    /// Write bootstrap code: forced on or off, or by default in directory mode only
+    bootstrap: Option<bool>,
+    /// Initial registers and entry function set by the bootstrap code
+    startup: Bootstrap,
+    /// Precede each command's code with a `// File.vm:line function` comment
+    annotate: bool,
+    /// Write a map from assembly lines and ROM addresses to VM source lines
+    source_map: bool,
+    /// Print the instructions emitted per function and per command kind
+    size_report: bool,
+    /// Accept the extended commands
+    extended: bool,
+    /// Replace calls to Math.multiply/divide with extended commands
+    math_calls: bool,
+    /// Count function calls at runtime and write a .profile map
+    profile: bool,
+    /// Also estimate the cycles spent in each function
+    profile_cycles: bool,
+    /// First RAM address of the profile counters
+    profile_base: Option<usize>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Option<Self> {
+        let mut input = None;
+        let mut optimize_vm = false;
+        let mut strip_unused = false;
+        let mut tail_calls = false;
+        let mut inline_threshold = None;
+        let mut analyze = false;
+        let mut link_check = None;
+        let mut verify = false;
+        let mut stack_limit = None;
+        let mut emit = Emit::Asm;
+        let mut bootstrap = None;
+        let mut startup = Bootstrap::default();
+        let mut customized = false;
+        let mut annotate = false;
+        let mut source_map = false;
+        let mut size_report = false;
+        let mut extended = false;
+        let mut math_calls = false;
+        let mut profile = false;
+        let mut profile_cycles = false;
+        let mut profile_base = None;
+
+        let mut args = args[1..].iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--opt-vm" => optimize_vm = true,
+                "--strip-unused" => strip_unused = true,
+                "--tail-calls" => tail_calls = true,
+                "--inline" => {
+                    inline_threshold = inline_threshold.or(Some(inliner::DEFAULT_THRESHOLD))
+                }
+                "--inline-threshold" => inline_threshold = Some(args.next()?.parse().ok()?),
+                "--analyze" => analyze = true,
+                "--link-check" => link_check = Some(true),
+                "--no-link-check" => link_check = Some(false),
+                "--verify" => verify = true,
+                "--stack-guard" => {
+                    stack_limit = stack_limit.or(Some(code_writer::DEFAULT_STACK_LIMIT))
+                }
+                "--stack-limit" => stack_limit = Some(args.next()?.parse().ok()?),
+                "--emit" => {
+                    emit = match args.next()?.as_str() {
+                        "asm" => Emit::Asm,
+                        "hack" => Emit::Hack,
+                        "vmb" => Emit::Vmb,
+                        _ => return None,
+                    }
+                }
+                "--annotate" => annotate = true,
+                "--source-map" => source_map = true,
+                "--size-report" => size_report = true,
+                "--ext" => extended = true,
+                "--ext-math" => {
+                    extended = true;
+                    math_calls = true;
+                }
+                "--profile" => profile = true,
+                "--profile-cycles" => profile_cycles = true,
+                "--profile-base" => profile_base = Some(args.next()?.parse().ok()?),
+                "--bootstrap" => bootstrap = Some(true),
+                "--no-bootstrap" => bootstrap = Some(false),
+                "--entry" => {
+                    let name = args.next()?;
+                    startup.entry = (name != "none").then(|| name.clone());
+                    customized = true;
+                }
+                "--sp" | "--lcl" | "--arg" | "--this" | "--that" => {
+                    let value = args.next()?.parse().ok()?;
+                    match arg.as_str() {
+                        "--sp" => startup.sp = value,
+                        "--lcl" => startup.lcl = Some(value),
+                        "--arg" => startup.arg = Some(value),
+                        "--this" => startup.this = Some(value),
+                        _ => startup.that = Some(value),
+                    }
+                    customized = true;
+                }
+                _ if arg.starts_with("--") => return None,
+                _ if input.is_none() => input = Some(arg.clone()),
+                _ => return None,
+            }
+        }
+
+        Some(Self {
+            input: input?,
+            optimize_vm,
+            strip_unused,
+            tail_calls,
+            inline_threshold,
+            analyze,
+            link_check,
+            verify,
+            stack_limit,
+            emit,
+            // Choosing an entry or registers asks for a bootstrap in single-file mode too
+            bootstrap: bootstrap.or(customized.then_some(true)),
+            startup,
+            annotate,
+            source_map,
+            size_report,
+            extended,
+            math_calls,
+            profile: profile || profile_cycles || profile_base.is_some(),
+            profile_cycles,
+            profile_base,
+        })
+    }
+
+    /// Returns true if the links between files should be checked.
+    fn link_check(&self, directory_mode: bool) -> bool {
+        self.link_check.unwrap_or(directory_mode)
+    }
+
+    /// Returns the bootstrap to write, if any.
+    fn bootstrap(&self, directory_mode: bool) -> Option<&Bootstrap> {
+        self.bootstrap
+            .unwrap_or(directory_mode)
+            .then_some(&self.startup)
+    }
+
+    /// Returns the function the program starts from, for dead code
+    /// removal and stack analysis.
+    fn entry(&self) -> Option<&str> {
+        self.startup.entry.as_deref()
+    }
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <file.vm or directory>", args[0]);
+    let Some(options) = Options::parse(&args) else {
+        eprintln!(
+            "Usage: {} [--opt-vm] [--strip-unused] [--tail-calls] [--inline] [--inline-threshold <n>] [--analyze] [--link-check] [--no-link-check] [--verify] [--stack-guard] [--stack-limit <n>] [--emit asm|hack|vmb] [--bootstrap] [--no-bootstrap] [--entry <function>|none] [--sp|--lcl|--arg|--this|--that <n>] [--annotate] [--source-map] [--size-report] [--ext] [--ext-math] [--profile] [--profile-cycles] [--profile-base <address>] <file.vm, file.vmb or directory>",
+            args[0]
+        );
         process::exit(1);
-    }
+    };
 
-    let input_path = Path::new(&args[1]);
+    let input_path = Path::new(&options.input);
 
     if input_path.is_file() {
         // Single file mode (compatible with chapter 7)
-        translate_single_file(&args[1]);
+        translate_single_file(&options.input, &options);
     } else if input_path.is_dir() {
         // Directory mode (chapter 8)
-        translate_directory(&args[1]);
+        translate_directory(&options.input, &options);
     } else {
-        eprintln!("Error: {} is neither a file nor a directory", args[1]);
+        eprintln!("Error: {} is neither a file nor a directory", options.input);
         process::exit(1);
     }
 }
 
-fn translate_single_file(input_file_name: &str) {
-    let output_file_name = input_file_name.replace(".vm", ".asm");
-
-    let input_file_content =
-        fs::read_to_string(input_file_name).expect("Failed to read input file");
-
-    // Extract filename without extension for static variables
-    let filename = Path::new(input_file_name)
-        .file_stem()
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_string();
+fn translate_single_file(input_file_name: &str, options: &Options) {
+    let input_path = Path::new(input_file_name);
+    let output_stem = input_path.with_extension("");
+    let output_stem = output_stem.to_str().unwrap();
 
-    let output_file = fs::File::create(&output_file_name).expect("Failed to create output file");
-    let mut code_writer = CodeWriter::new(output_file, filename.clone());
+    let mut files = vec![load_vm_file(input_path)];
+    if options.emit == Emit::Vmb {
+        let dir = input_path.parent().unwrap_or(Path::new("."));
+        write_bytecode(&files, dir, options);
+        return;
+    }
+    check_extended_commands(&mut files, options);
+    if options.link_check(false) {
+        check_links(&files);
+    }
+    if let Some(bootstrap) = options.bootstrap(false) {
+        check_entry(&files, bootstrap);
+    }
+    run_passes(&mut files, input_file_name, options, false);
+    if options.verify {
+        verify_stack_discipline(&files);
+    }
 
-    translate_file(&input_file_content, &mut code_writer);
+    if options.analyze {
+        let dot_file_name = format!("{}.dot", output_stem);
+        analyze_program(
+            &files,
+            &dot_file_name,
+            options.entry().unwrap_or("Sys.init"),
+        );
+        return;
+    }
 
-    code_writer.close();
+    let output_file_name = write_output(&files, output_stem, options.bootstrap(false), options);
     println!(
         "Translation complete: {} -> {}",
         input_file_name, output_file_name
     );
 }
 
-fn translate_directory(dir_path: &str) {
+fn translate_directory(dir_path: &str, options: &Options) {
     let dir = Path::new(dir_path);
     let dir_name = dir.file_name().unwrap().to_str().unwrap();
-    let output_file_name = format!("{}/{}.asm", dir_path, dir_name);
-
-    let output_file = fs::File::create(&output_file_name).expect("Failed to create output file");
-    let mut code_writer = CodeWriter::new(output_file, String::new());
-
-    // Write bootstrap code for directory mode
-    code_writer.write_bootstrap();
-
-    // Process all .vm files in the directory
-    let entries = fs::read_dir(dir_path).expect("Failed to read directory");
-    let mut vm_files: Vec<_> = entries
-        .filter_map(|entry| {
-            let entry = entry.ok()?;
-            let path = entry.path();
-            if path.extension()? == "vm" {
-                Some(path)
-            } else {
-                None
-            }
+
+    let vm_files = program::directory_files(dir).unwrap_or_else(|error| {
+        eprintln!("Error: {}", error);
+        process::exit(1);
+    });
+    let mut files: Vec<VmFile> = vm_files.iter().map(|path| load_vm_file(path)).collect();
+    if options.emit == Emit::Vmb {
+        write_bytecode(&files, dir, options);
+        return;
+    }
+
+    check_extended_commands(&mut files, options);
+    if options.link_check(true) {
+        check_links(&files);
+    }
+    if let Some(bootstrap) = options.bootstrap(true) {
+        check_entry(&files, bootstrap);
+    }
+    run_passes(&mut files, dir_path, options, true);
+    if options.verify {
+        verify_stack_discipline(&files);
+    }
+
+    if options.analyze {
+        let dot_file_name = format!("{}/{}.dot", dir_path, dir_name);
+        analyze_program(
+            &files,
+            &dot_file_name,
+            options.entry().unwrap_or("Sys.init"),
+        );
+        return;
+    }
+
+    let output_stem = format!("{}/{}", dir_path, dir_name);
+    let output_file_name = write_output(&files, &output_stem, options.bootstrap(true), options);
+    println!("Translation complete: {} -> {}", dir_path, output_file_name);
+}
+
+/// Reads a .vm or .vmb file, exiting on errors.
+fn load_vm_file(path: &Path) -> VmFile {
+    VmFile::read(path).unwrap_or_else(|error| {
+        eprintln!("Error: {}", error);
+        process::exit(1);
+    })
+}
+
+/// Writes each file of the program as `<dir>/<File>.vmb`. The commands
+/// are encoded as parsed, before any pass, since the bytecode later stands
+/// in for the .vm file in builds with other options.
+fn write_bytecode(files: &[VmFile], dir: &Path, options: &Options) {
+    if options.optimize_vm
+        || options.strip_unused
+        || options.inline_threshold.is_some()
+        || options.math_calls
+    {
+        eprintln!(
+            "Warning: --emit vmb encodes the VM code as parsed; --opt-vm, --strip-unused, --inline and --ext-math apply to the builds that load it"
+        );
+    }
+    for file in files {
+        let path = dir.join(format!("{}.vmb", file.name));
+        fs::write(&path, bytecode::encode(&file.commands)).expect("Failed to write .vmb file");
+        println!("Generated: {}", path.display());
+    }
+}
+
+/// Removes functions unreachable from the entry function and reports what
+/// was dropped.
+fn strip_unused_functions(files: &mut [VmFile], entry: Option<&str>) {
+    let Some(entry) = entry else {
+        eprintln!("Warning: no entry function, keeping all functions");
+        return;
+    };
+    let Some(removed) = dead_code::strip_unused(files, entry) else {
+        eprintln!("Warning: {} is not defined, keeping all functions", entry);
+        return;
+    };
+
+    let saved: usize = removed.iter().map(|function| function.commands).sum();
+    println!(
+        "Removed {} unreachable functions ({} VM commands)",
+        removed.len(),
+        saved
+    );
+    for function in &removed {
+        println!(
+            "  {} ({}.vm, {} commands)",
+            function.name, function.file, function.commands
+        );
+    }
+}
+
+/// Without `--ext`, reports every extended command and exits if there are
+/// any. With `--ext-math`, replaces the Math calls they stand for.
+fn check_extended_commands(files: &mut [VmFile], options: &Options) {
+    if options.math_calls {
+        for function in extended::defined_math_functions(files) {
+            println!(
+                "Warning: {} is defined by the program, --ext-math replaces its calls",
+                function
+            );
+        }
+        let rewritten = extended::rewrite_math_calls(files);
+        if rewritten > 0 {
+            println!("Replaced {} Math calls with extended commands", rewritten);
+        }
+    }
+    if options.extended {
+        return;
+    }
+
+    let mut found = false;
+    for file in files.iter() {
+        for command in file.commands.iter().filter(|command| command.is_extended()) {
+            eprintln!(
+                "Error: {}.vm:{}: '{}' is an extended command, enable it with --ext",
+                file.name, command.line, command
+            );
+            found = true;
+        }
+    }
+    if found {
+        process::exit(1);
+    }
+}
+
+/// Reports every link error and exits if there are any.
+fn check_links(files: &[VmFile]) {
+    let errors = linker::check(files);
+    if errors.is_empty() {
+        return;
+    }
+    for error in &errors {
+        eprintln!("Error: {}", error);
+    }
+    process::exit(1);
+}
+
+/// Exits if the bootstrap calls a function that is not defined.
+fn check_entry(files: &[VmFile], bootstrap: &Bootstrap) {
+    let Some(entry) = &bootstrap.entry else {
+        return;
+    };
+    let defined = files.iter().flat_map(|file| &file.commands).any(
+        |command| matches!(&command.command, VmCommand::Function { name, .. } if name == entry),
+    );
+    if !defined {
+        eprintln!("Error: entry function {} is not defined", entry);
+        process::exit(1);
+    }
+}
+
+/// Reports every stack discipline violation and exits if there are any.
+fn verify_stack_discipline(files: &[VmFile]) {
+    let violations = verifier::verify(files);
+    if violations.is_empty() {
+        return;
+    }
+    for violation in &violations {
+        eprintln!("Error: {}", violation);
+    }
+    process::exit(1);
+}
+
+/// Prints the stack usage report and writes the call graph to `dot_file_name`.
+fn analyze_program(files: &[VmFile], dot_file_name: &str, entry: &str) {
+    let analysis = analysis::analyze(files);
+    print!("{}", analysis.report(entry));
+    fs::write(dot_file_name, analysis.to_dot()).expect("Failed to write DOT file");
+    println!("Generated: {}", dot_file_name);
+}
+
+/// Runs the requested VM-level passes over the program.
+/// `input_path` is the file or directory given on the command line; optimized
+/// VM code is written to an output/ folder next to it.
+fn run_passes(files: &mut [VmFile], input_path: &str, options: &Options, directory_mode: bool) {
+    if let Some(threshold) = options.inline_threshold {
+        let inlined = inliner::inline_small_functions(files, threshold, options.entry());
+        println!("Inlined {} call sites", inlined);
+    }
+
+    // Inlining first, so functions that are no longer called get dropped
+    if directory_mode && options.strip_unused {
+        strip_unused_functions(files, options.entry());
+    }
+
+    if !options.optimize_vm {
+        return;
+    }
+
+    for file in files.iter_mut() {
+        let optimized = optimizer::optimize(&file.commands);
+        println!(
+            "Optimized {}: {} -> {} VM commands",
+            file.name,
+            file.commands.len(),
+            optimized.len()
+        );
+        write_vm_output(input_path, &file.name, &optimized);
+        file.commands = optimized;
+    }
+}
+
+/// Writes VM commands to `output/<filename>.vm` for inspection.
+fn write_vm_output(input_path: &str, filename: &str, commands: &[Command]) {
+    let input_path = Path::new(input_path);
+    let base_dir = if input_path.is_dir() {
+        input_path
+    } else {
+        input_path.parent().unwrap_or(Path::new("."))
+    };
+    let output_dir = base_dir.join("output");
+    fs::create_dir_all(&output_dir).expect("Failed to create output directory");
+
+    let vm_file = output_dir.join(format!("{}.vm", filename));
+    let vm_code: String = labels::vm_labels(commands)
+        .iter()
+        .map(|command| match &command.file {
+            // Inlined static references can't be expressed in VM code
+            Some(file) => format!("{} // from {}.vm\n", command, file),
+            None => format!("{}\n", command),
         })
         .collect();
+    fs::write(&vm_file, vm_code).expect("Failed to write VM file");
+    println!("Generated: {}", vm_file.display());
+}
 
-    // Sort files for consistent output
-    vm_files.sort();
+/// Translates the program and writes it to `<output_stem>.asm`, or to
+/// `<output_stem>.hack` and `<output_stem>.sym` with `--emit hack`.
+/// Returns the name of the main output file.
+fn write_output(
+    files: &[VmFile],
+    output_stem: &str,
+    bootstrap: Option<&Bootstrap>,
+    options: &Options,
+) -> String {
+    match options.emit {
+        Emit::Vmb => unreachable!("bytecode is written before the passes"),
+        Emit::Asm => {
+            let asm = translate_to_asm(files, output_stem, bootstrap, options);
+            let output_file_name = format!("{}.asm", output_stem);
+            fs::write(&output_file_name, asm).expect("Failed to write output file");
+            output_file_name
+        }
+        Emit::Hack => {
+            let asm = translate_to_asm(files, output_stem, bootstrap, options);
+            let assembly = hack_assembler::assemble(&asm);
+            if assembly.instructions.len() > ROM_SIZE {
+                eprintln!(
+                    "Warning: {} instructions do not fit in the {} words of ROM",
+                    assembly.instructions.len(),
+                    ROM_SIZE
+                );
+            }
 
-    for vm_file in vm_files {
-        let filename = vm_file.file_stem().unwrap().to_str().unwrap().to_string();
-        let content =
-            fs::read_to_string(&vm_file).unwrap_or_else(|_| panic!("Failed to read file: {:?}", vm_file));
+            let output_file_name = format!("{}.hack", output_stem);
+            fs::write(&output_file_name, assembly.to_hack()).expect("Failed to write .hack file");
+            let symbol_file_name = format!("{}.sym", output_stem);
+            fs::write(&symbol_file_name, assembly.symbol_map())
+                .expect("Failed to write symbol map");
+            println!("Generated: {}", symbol_file_name);
+            output_file_name
+        }
+    }
+}
 
-        code_writer.set_filename(filename.clone());
-        translate_file(&content, &mut code_writer);
+/// Translates the program to assembly, writing its source map if requested.
+/// Exits if a label of the assembly is defined twice.
+fn translate_to_asm(
+    files: &[VmFile],
+    output_stem: &str,
+    bootstrap: Option<&Bootstrap>,
+    options: &Options,
+) -> String {
+    let mut code_writer = CodeWriter::new(Vec::new(), String::new());
+    translate_program(files, &mut code_writer, bootstrap, options);
+    if options.source_map {
+        write_source_map(output_stem, code_writer.source_map());
     }
+    let asm = String::from_utf8(code_writer.into_inner()).expect("Invalid assembly");
 
-    code_writer.close();
-    println!("Translation complete: {} -> {}", dir_path, output_file_name);
+    let errors = labels::check_assembly(&asm);
+    if !errors.is_empty() {
+        for error in &errors {
+            eprintln!("Error: {}", error);
+        }
+        process::exit(1);
+    }
+    if let Some(layout) = profile_layout(files, options) {
+        let map_file_name = format!("{}.profile", output_stem);
+        fs::write(&map_file_name, layout.to_map()).expect("Failed to write profile map");
+        println!(
+            "Generated: {} (counters at RAM {}-{})",
+            map_file_name,
+            layout.base,
+            layout.base + layout.size() - 1
+        );
+    }
+    asm
 }
 
-fn translate_file(content: &str, code_writer: &mut CodeWriter) {
-    let mut parser = Parser::new(content);
+/// Returns the profile counter layout of the program's functions, if
+/// profiling is enabled.
+fn profile_layout(files: &[VmFile], options: &Options) -> Option<ProfileLayout> {
+    if !options.profile {
+        return None;
+    }
+    let functions = files
+        .iter()
+        .flat_map(|file| file.functions())
+        .map(|function| function.name)
+        .collect();
+    match ProfileLayout::new(functions, options.profile_cycles, options.profile_base) {
+        Ok(layout) => Some(layout),
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            process::exit(1);
+        }
+    }
+}
+
+/// Writes `<output_stem>.srcmap`: one tab-separated line per VM command with
+/// the assembly line and ROM address of its code and its VM file, line and
+/// function.
+fn write_source_map(output_stem: &str, entries: &[SourceMapEntry]) {
+    let mut map = String::from("# asm_line\trom_address\tfile\tline\tfunction\n");
+    for entry in entries {
+        map.push_str(&format!(
+            "{}\t{}\t{}.vm\t{}\t{}\n",
+            entry.asm_line, entry.rom_address, entry.file, entry.line, entry.function
+        ));
+    }
+    let map_file_name = format!("{}.srcmap", output_stem);
+    fs::write(&map_file_name, map).expect("Failed to write source map");
+    println!("Generated: {}", map_file_name);
+}
+
+fn translate_program<W: Write>(
+    files: &[VmFile],
+    code_writer: &mut CodeWriter<W>,
+    bootstrap: Option<&Bootstrap>,
+    options: &Options,
+) {
+    configure(code_writer, files, options);
+    let mut sizes = SizeReport::default();
+
+    // Cycle counting charges each block the size of its code, measured by
+    // translating the program once beforehand
+    let costs: Option<Vec<Vec<usize>>> = options.profile_cycles.then(|| {
+        let mut dry_run = CodeWriter::new(io::sink(), String::new());
+        configure(&mut dry_run, files, options);
+        files
+            .iter()
+            .map(|file| {
+                translate_file(
+                    file,
+                    &mut dry_run,
+                    &mut SizeReport::default(),
+                    options,
+                    None,
+                )
+            })
+            .collect()
+    });
+
+    if let Some(bootstrap) = bootstrap {
+        code_writer.write_bootstrap(bootstrap);
+        sizes.add("(bootstrap)", "bootstrap", code_writer.rom_address());
+    }
+    for (i, file) in files.iter().enumerate() {
+        let costs = costs.as_ref().map(|costs| costs[i].as_slice());
+        translate_file(file, code_writer, &mut sizes, options, costs);
+    }
+    let start = code_writer.rom_address();
+    code_writer.write_extended_routines();
+    code_writer.write_stack_handlers();
+    if code_writer.rom_address() > start {
+        sizes.add("(runtime)", "runtime", code_writer.rom_address() - start);
+    }
+
+    if options.size_report {
+        print!("{}", sizes.report(ROM_SIZE));
+    }
+}
+
+fn configure<W: Write>(code_writer: &mut CodeWriter<W>, files: &[VmFile], options: &Options) {
+    let layout = profile_layout(files, options);
+    if let Some(mut limit) = options.stack_limit {
+        // The stack stops below profile counters in its way
+        if let Some(layout) = &layout
+            && (code_writer::STACK_BASE..limit).contains(&layout.base)
+        {
+            limit = layout.base;
+        }
+        code_writer.set_stack_guard(limit);
+    }
+    code_writer.set_annotate(options.annotate);
+    if let Some(layout) = layout {
+        code_writer.set_profile(layout);
+    }
+}
+
+/// Translates the commands of a file, returning the number of instructions
+/// emitted for each. With `costs`, the instruction counts of a previous
+/// translation, basic blocks are instrumented for cycle counting.
+fn translate_file<W: Write>(
+    file: &VmFile,
+    code_writer: &mut CodeWriter<W>,
+    sizes: &mut SizeReport,
+    options: &Options,
+    costs: Option<&[usize]>,
+) -> Vec<usize> {
+    let mut static_file = &file.name;
+    code_writer.set_filename(static_file.clone());
+
+    let mut function = "(top-level)";
+    let mut emitted = vec![0; file.commands.len()];
+    let mut commands = file.commands.iter().enumerate().peekable();
+    while let Some((i, command)) = commands.next() {
+        // Inlined commands keep referring to their own file's statics
+        let source = command.file.as_ref().unwrap_or(&file.name);
+        if source != static_file {
+            static_file = source;
+            code_writer.set_filename(static_file.clone());
+        }
 
-    while parser.has_more_lines() {
-        match parser.command_type() {
-            CommandType::Arithmetic => {
-                let command = parser.arg1();
-                code_writer.write_arithmetic(&command);
+        if let VmCommand::Function { name, .. } = &command.command {
+            function = name;
+        }
+        code_writer.set_source(&file.name, command.line, function);
+        let start = code_writer.rom_address();
+
+        match &command.command {
+            VmCommand::Arithmetic(op) => {
+                code_writer.write_arithmetic(*op);
             }
-            CommandType::Push | CommandType::Pop => {
-                let command_type = parser.command_type();
-                let segment = parser.arg1();
-                let index = parser.arg2();
-                code_writer.write_push_pop(command_type, &segment, index);
+            VmCommand::Push { segment, index } => {
+                code_writer.write_push(*segment, *index);
             }
-            CommandType::Label => {
-                let label = parser.arg1();
-                code_writer.write_label(&label);
+            VmCommand::Pop { segment, index } => {
+                code_writer.write_pop(*segment, *index);
             }
-            CommandType::Goto => {
-                let label = parser.arg1();
-                code_writer.write_goto(&label);
+            VmCommand::Label(label) => {
+                code_writer.write_label(label);
             }
-            CommandType::If => {
-                let label = parser.arg1();
-                code_writer.write_if(&label);
+            VmCommand::Goto(label) => {
+                code_writer.write_goto(label);
             }
-            CommandType::Function => {
-                let function_name = parser.arg1();
-                let num_locals = parser.arg2();
-                code_writer.write_function(&function_name, num_locals);
+            VmCommand::IfGoto(label) => {
+                code_writer.write_if(label);
             }
-            CommandType::Call => {
-                let function_name = parser.arg1();
-                let num_args = parser.arg2();
-                code_writer.write_call(&function_name, num_args);
+            VmCommand::Function { name, n_locals } => {
+                code_writer.write_function(name, *n_locals as usize);
             }
-            CommandType::Return => {
+            VmCommand::Call { name, n_args } => {
+                let num_args = *n_args as usize;
+                let is_tail_call = commands
+                    .peek()
+                    .is_some_and(|(_, next)| next.command == VmCommand::Return);
+                if options.tail_calls && is_tail_call {
+                    code_writer.write_tail_call(name, num_args);
+                    commands.next();
+                } else {
+                    code_writer.write_call(name, num_args);
+                }
+            }
+            VmCommand::Return => {
                 code_writer.write_return();
             }
         }
-        parser.advance();
+        let instructions = code_writer.rom_address() - start;
+        sizes.add(function, size_report::command_kind(command), instructions);
+        emitted[i] = instructions;
+
+        // A tail call does not come back to the next command
+        let returns_here = commands.peek().is_none_or(|(next, _)| *next == i + 1);
+        if let Some(costs) = costs
+            && returns_here
+            && starts_block(&command.command)
+        {
+            let start = code_writer.rom_address();
+            code_writer.write_profile_cycles(block_cost(&file.commands, costs, i));
+            sizes.add(function, "profile", code_writer.rom_address() - start);
+        }
+    }
+    emitted
+}
+
+/// Returns true if a basic block starts right after `command`.
+fn starts_block(command: &VmCommand) -> bool {
+    matches!(
+        command,
+        VmCommand::Function { .. }
+            | VmCommand::Label(_)
+            | VmCommand::IfGoto(_)
+            | VmCommand::Call { .. }
+    )
+}
+
+/// Returns the instructions of the block starting after `commands[i]`,
+/// including the entry code of a function: up to the next jump, call or
+/// return, or up to the next label, which starts a block of its own.
+fn block_cost(commands: &[Command], costs: &[usize], i: usize) -> usize {
+    let mut cost = match commands[i].command {
+        VmCommand::Function { .. } => costs[i],
+        _ => 0,
+    };
+    for (command, size) in commands[i + 1..].iter().zip(&costs[i + 1..]) {
+        match command.command {
+            VmCommand::Label(_) | VmCommand::Function { .. } => break,
+            VmCommand::Goto(_)
+            | VmCommand::IfGoto(_)
+            | VmCommand::Call { .. }
+            | VmCommand::Return => {
+                return cost + size;
+            }
+            _ => cost += size,
+        }
     }
+    cost
 }
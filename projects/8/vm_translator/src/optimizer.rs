@@ -0,0 +1,326 @@
+//! VM-to-VM peephole optimizer.
+//!
+//! Runs over parsed commands before they reach the `CodeWriter`. Every rule
+//! looks only at the tail of the already optimized output, so a label always
+//! acts as a barrier: code that can be entered from a jump is never merged
+//! with the code before it.
+
+use crate::parser::{Command, Op, Segment, VmCommand};
+
+/// Optimizes a sequence of VM commands:
+/// - folds arithmetic and comparisons on constants
+///   (`push constant 3 / push constant 4 / add` -> `push constant 7`)
+/// - canonicalizes negative constants, so `push constant 0 / not`
+///   becomes `push constant 1 / neg`
+/// - removes `push x / pop x` pairs and double `not`/`neg`
+/// - fuses `push constant 0 / eq` zero tests into the following `if-goto`
+/// - removes `goto L` immediately followed by `label L`
+/// - resolves `if-goto` on a constant condition
+pub fn optimize(commands: &[Command]) -> Vec<Command> {
+    let mut output: Vec<Command> = Vec::with_capacity(commands.len());
+
+    for command in commands {
+        output.push(command.clone());
+        while simplify_tail(&mut output) {}
+    }
+
+    output
+}
+
+/// Applies the first matching rule to the end of `output`.
+/// Returns true if the output changed.
+fn simplify_tail(output: &mut Vec<Command>) -> bool {
+    let Some(last) = output.last() else {
+        return false;
+    };
+    let line = last.line;
+
+    match last.command.clone() {
+        VmCommand::Arithmetic(op) => {
+            let rest = &output[..output.len() - 1];
+
+            if !op.is_unary() {
+                // push constant a / push constant b / op
+                if let Some((b, b_len)) = constant_at_end(rest) {
+                    let rest = &rest[..rest.len() - b_len];
+                    if let Some((a, a_len)) = constant_at_end(rest) {
+                        let start = rest.len() - a_len;
+                        output.truncate(start);
+                        output.extend(constant_commands(op.eval(a, b), line));
+                        return true;
+                    }
+                }
+                return false;
+            }
+
+            // push constant a / neg, push constant a / not
+            if let Some((a, a_len)) = constant_at_end(rest) {
+                let value = op.eval(0, a);
+                let start = rest.len() - a_len;
+                let folded = constant_commands(value, line);
+                let unchanged = output[start..].len() == folded.len()
+                    && output[start..]
+                        .iter()
+                        .zip(&folded)
+                        .all(|(old, new)| old.same_instruction(new));
+                if !unchanged {
+                    output.truncate(start);
+                    output.extend(folded);
+                    return true;
+                }
+                return false;
+            }
+
+            // not / not, neg / neg
+            if let Some(previous) = rest.last()
+                && (op == Op::Not || op == Op::Neg)
+                && previous.is_arithmetic(op)
+            {
+                output.truncate(output.len() - 2);
+                return true;
+            }
+
+            // mul / neg -> nmul, nmul / neg -> mul, and so on
+            if op == Op::Neg
+                && let Some(previous) = rest.last()
+                && let VmCommand::Arithmetic(previous_op) = previous.command
+                && let Some(negated) = previous_op.negated()
+            {
+                output.pop();
+                output.last_mut().unwrap().command = VmCommand::Arithmetic(negated);
+                return true;
+            }
+
+            false
+        }
+        VmCommand::Pop { segment, index } => {
+            // push x / pop x
+            let n = output.len();
+            if n >= 2 {
+                let previous = &output[n - 2];
+                if previous.command == (VmCommand::Push { segment, index })
+                    && previous.file == last.file
+                {
+                    output.truncate(n - 2);
+                    return true;
+                }
+            }
+            false
+        }
+        VmCommand::IfGoto(label) => {
+            let rest = &output[..output.len() - 1];
+
+            // <constant> / if-goto L
+            if let Some((value, len)) = constant_at_end(rest) {
+                output.truncate(rest.len() - len);
+                if value != 0 {
+                    output.push(Command::new(VmCommand::Goto(label), line));
+                }
+                return true;
+            }
+
+            // push constant 0 / eq / not / if-goto L
+            if ends_with_zero_test(rest, &[Op::Not]) {
+                output.truncate(rest.len() - 3);
+                output.push(Command::new(VmCommand::IfGoto(label), line));
+                return true;
+            }
+
+            // push constant 0 / eq / push constant 0 / eq / if-goto L
+            if ends_with_zero_test(rest, &[]) && ends_with_zero_test(&rest[..rest.len() - 2], &[]) {
+                output.truncate(rest.len() - 4);
+                output.push(Command::new(VmCommand::IfGoto(label), line));
+                return true;
+            }
+
+            false
+        }
+        VmCommand::Label(label) => {
+            // goto L / label L
+            let n = output.len();
+            if n >= 2 && output[n - 2].command == VmCommand::Goto(label) {
+                output.remove(n - 2);
+                return true;
+            }
+            false
+        }
+        _ => false,
+    }
+}
+
+/// Recognizes a constant at the end of `commands`, in the forms produced by
+/// `constant_commands`. Returns the value and the number of commands used.
+fn constant_at_end(commands: &[Command]) -> Option<(i16, usize)> {
+    let last = commands.last()?;
+    if let Some(value) = last.constant() {
+        return i16::try_from(value).ok().map(|value| (value, 1));
+    }
+
+    let previous = commands.get(commands.len().checked_sub(2)?)?;
+    let value = i16::try_from(previous.constant()?).ok()?;
+    if last.is_arithmetic(Op::Neg) && value != 0 {
+        Some((-value, 2))
+    } else if last.is_arithmetic(Op::Not) && value == i16::MAX {
+        Some((i16::MIN, 2))
+    } else {
+        None
+    }
+}
+
+/// Builds the canonical commands that push `value`.
+fn constant_commands(value: i16, line: usize) -> Vec<Command> {
+    let push = |n: i16| {
+        let command = VmCommand::Push {
+            segment: Segment::Constant,
+            index: n as u16,
+        };
+        Command::new(command, line)
+    };
+    let op = |op| Command::new(VmCommand::Arithmetic(op), line);
+    if value >= 0 {
+        vec![push(value)]
+    } else if value == i16::MIN {
+        vec![push(i16::MAX), op(Op::Not)]
+    } else {
+        vec![push(-value), op(Op::Neg)]
+    }
+}
+
+/// Checks whether `commands` ends with `push constant 0 / eq` followed by `suffix`.
+fn ends_with_zero_test(commands: &[Command], suffix: &[Op]) -> bool {
+    let n = suffix.len() + 2;
+    if commands.len() < n {
+        return false;
+    }
+    let tail = &commands[commands.len() - n..];
+    tail[0].constant() == Some(0)
+        && tail[1].is_arithmetic(Op::Eq)
+        && suffix
+            .iter()
+            .zip(&tail[2..])
+            .all(|(&op, command)| command.is_arithmetic(op))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn optimize_source(source: &str) -> String {
+        let commands = parser::parse(source).unwrap();
+        optimize(&commands)
+            .iter()
+            .map(|command| command.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        assert_eq!(
+            optimize_source("push constant 3\npush constant 4\nadd"),
+            "push constant 7"
+        );
+        assert_eq!(
+            optimize_source("push constant 3\npush constant 4\nsub\npush constant 2\nadd"),
+            "push constant 1"
+        );
+        assert_eq!(
+            optimize_source("push constant 3\npush constant 4\nlt"),
+            "push constant 1\nneg"
+        );
+    }
+
+    #[test]
+    fn test_extended_folding() {
+        assert_eq!(
+            optimize_source("push constant 6\npush constant 7\nmul"),
+            "push constant 42"
+        );
+        assert_eq!(
+            optimize_source("push constant 7\nneg\npush constant 2\ndiv"),
+            "push constant 3\nneg"
+        );
+        assert_eq!(
+            optimize_source("push constant 7\nneg\npush constant 2\nmod"),
+            "push constant 1\nneg"
+        );
+        assert_eq!(
+            optimize_source("push constant 1\nneg\npush constant 15\nshr"),
+            "push constant 1"
+        );
+        assert_eq!(
+            optimize_source("push constant 5\npush constant 0\ndiv"),
+            "push constant 0"
+        );
+        assert_eq!(
+            optimize_source("push constant 6\npush constant 7\nnmul"),
+            "push constant 42\nneg"
+        );
+        assert_eq!(
+            optimize_source("push constant 5\npush constant 0\nnmod"),
+            "push constant 5\nneg"
+        );
+    }
+
+    #[test]
+    fn test_neg_variants() {
+        assert_eq!(
+            optimize_source("push local 0\npush local 1\nmul\nneg"),
+            "push local 0\npush local 1\nnmul"
+        );
+        assert_eq!(
+            optimize_source("push local 0\npush local 1\nndiv\nneg\nneg"),
+            "push local 0\npush local 1\nndiv"
+        );
+        assert_eq!(
+            optimize_source("push local 0\npush local 1\nmod\nneg\nneg\nneg"),
+            "push local 0\npush local 1\nnmod"
+        );
+        assert_eq!(
+            optimize_source("push local 0\npush local 1\nshl\nneg"),
+            "push local 0\npush local 1\nshl\nneg"
+        );
+    }
+
+    #[test]
+    fn test_canonical_true() {
+        assert_eq!(
+            optimize_source("push constant 0\nnot"),
+            "push constant 1\nneg"
+        );
+        assert_eq!(
+            optimize_source("push constant 1\nneg"),
+            "push constant 1\nneg"
+        );
+    }
+
+    #[test]
+    fn test_push_pop_removal() {
+        assert_eq!(
+            optimize_source("push local 1\npop local 1\npush local 1\npop local 2"),
+            "push local 1\npop local 2"
+        );
+    }
+
+    #[test]
+    fn test_zero_test_fusion() {
+        assert_eq!(
+            optimize_source("push local 0\npush constant 0\neq\nnot\nif-goto L"),
+            "push local 0\nif-goto L"
+        );
+    }
+
+    #[test]
+    fn test_branches() {
+        assert_eq!(optimize_source("goto L\nlabel L"), "label L");
+        assert_eq!(optimize_source("push constant 0\nif-goto L"), "");
+        assert_eq!(optimize_source("push constant 0\nnot\nif-goto L"), "goto L");
+    }
+
+    #[test]
+    fn test_label_is_a_barrier() {
+        let source = "push constant 3\nlabel L\npush constant 4\nadd";
+        assert_eq!(optimize_source(source), source);
+    }
+}
@@ -1,125 +1,491 @@
-pub enum CommandType {
-    Arithmetic,
-    Push,
-    Pop,
-    Label,
-    Goto,
-    If,
-    Function,
-    Return,
-    Call,
+//! Parsing of VM source into typed commands.
+//!
+//! Each line is parsed once into a `VmCommand`; its `Display` prints the
+//! canonical VM text back, so parsing and printing round-trip.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::labels;
+
+/// A memory segment of the VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Segment {
+    Argument,
+    Local,
+    Static,
+    Constant,
+    This,
+    That,
+    Pointer,
+    Temp,
 }
 
-pub struct Parser {
-    commands: Vec<String>,
-    current_command: usize,
+impl Segment {
+    pub const ALL: [Segment; 8] = [
+        Segment::Argument,
+        Segment::Local,
+        Segment::Static,
+        Segment::Constant,
+        Segment::This,
+        Segment::That,
+        Segment::Pointer,
+        Segment::Temp,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Segment::Argument => "argument",
+            Segment::Local => "local",
+            Segment::Static => "static",
+            Segment::Constant => "constant",
+            Segment::This => "this",
+            Segment::That => "that",
+            Segment::Pointer => "pointer",
+            Segment::Temp => "temp",
+        }
+    }
 }
 
-impl Parser {
-    pub fn new(input: &str) -> Self {
-        let commands: Vec<String> = input
-            .lines()
-            .map(|line| line.trim())
-            .filter(|line| !line.is_empty() && !line.starts_with("//"))
-            .map(|line| {
-                if let Some(idx) = line.find("//") {
-                    line[..idx].trim().to_string()
-                } else {
-                    line.to_string()
-                }
-            })
-            .collect();
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
 
-        Self {
-            commands,
-            current_command: 0,
+impl FromStr for Segment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Segment::ALL
+            .into_iter()
+            .find(|segment| segment.name() == s)
+            .ok_or_else(|| format!("unknown segment: {}", s))
+    }
+}
+
+/// An arithmetic or logical command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Op {
+    Add,
+    Sub,
+    Neg,
+    Eq,
+    Gt,
+    Lt,
+    And,
+    Or,
+    Not,
+    // Extended commands, only accepted by the translator with `--ext`
+    Mul,
+    Div,
+    Mod,
+    Shl,
+    Shr,
+    // Negated results of `mul`, `div` and `mod`
+    Nmul,
+    Ndiv,
+    Nmod,
+}
+
+impl Op {
+    pub const ALL: [Op; 17] = [
+        Op::Add,
+        Op::Sub,
+        Op::Neg,
+        Op::Eq,
+        Op::Gt,
+        Op::Lt,
+        Op::And,
+        Op::Or,
+        Op::Not,
+        Op::Mul,
+        Op::Div,
+        Op::Mod,
+        Op::Shl,
+        Op::Shr,
+        Op::Nmul,
+        Op::Ndiv,
+        Op::Nmod,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Add => "add",
+            Op::Sub => "sub",
+            Op::Neg => "neg",
+            Op::Eq => "eq",
+            Op::Gt => "gt",
+            Op::Lt => "lt",
+            Op::And => "and",
+            Op::Or => "or",
+            Op::Not => "not",
+            Op::Mul => "mul",
+            Op::Div => "div",
+            Op::Mod => "mod",
+            Op::Shl => "shl",
+            Op::Shr => "shr",
+            Op::Nmul => "nmul",
+            Op::Ndiv => "ndiv",
+            Op::Nmod => "nmod",
         }
     }
 
-    pub fn has_more_lines(&self) -> bool {
-        self.current_command < self.commands.len()
+    /// Returns true for the commands beyond the standard VM language.
+    pub fn is_extended(self) -> bool {
+        matches!(
+            self,
+            Op::Mul | Op::Div | Op::Mod | Op::Shl | Op::Shr | Op::Nmul | Op::Ndiv | Op::Nmod
+        )
     }
 
-    pub fn advance(&mut self) {
-        if self.has_more_lines() {
-            self.current_command += 1;
+    /// Returns the command giving the negated result of this one, for
+    /// the commands that have one.
+    pub fn negated(self) -> Option<Op> {
+        match self {
+            Op::Mul => Some(Op::Nmul),
+            Op::Div => Some(Op::Ndiv),
+            Op::Mod => Some(Op::Nmod),
+            Op::Nmul => Some(Op::Mul),
+            Op::Ndiv => Some(Op::Div),
+            Op::Nmod => Some(Op::Mod),
+            _ => None,
         }
     }
 
-    pub fn command_type(&self) -> CommandType {
-        if let Some(command) = self.commands.get(self.current_command) {
-            let parts: Vec<&str> = command.split_whitespace().collect();
-            if let Some(first_part) = parts.first() {
-                match *first_part {
-                    "add" | "sub" | "neg" | "eq" | "gt" | "lt" | "and" | "or" | "not" => {
-                        CommandType::Arithmetic
-                    }
-                    "push" => CommandType::Push,
-                    "pop" => CommandType::Pop,
-                    "label" => CommandType::Label,
-                    "goto" => CommandType::Goto,
-                    "if-goto" => CommandType::If,
-                    "function" => CommandType::Function,
-                    "return" => CommandType::Return,
-                    "call" => CommandType::Call,
-                    _ => panic!("Unknown command: {}", first_part),
+    pub fn is_unary(self) -> bool {
+        matches!(self, Op::Neg | Op::Not)
+    }
+
+    /// Evaluates the command on 16-bit values, `y` being the top of the
+    /// stack and `x` the value below it. Unary commands only use `y`.
+    /// Comparisons give -1 for true and 0 for false; see the extended
+    /// module for the semantics of the extended commands.
+    pub fn eval(self, x: i16, y: i16) -> i16 {
+        match self {
+            Op::Add => x.wrapping_add(y),
+            Op::Sub => x.wrapping_sub(y),
+            Op::Neg => y.wrapping_neg(),
+            Op::Eq => boolean(x == y),
+            Op::Gt => boolean(x > y),
+            Op::Lt => boolean(x < y),
+            Op::And => x & y,
+            Op::Or => x | y,
+            Op::Not => !y,
+            Op::Mul => x.wrapping_mul(y),
+            Op::Div => {
+                if y == 0 {
+                    0
+                } else {
+                    x.wrapping_div(y)
                 }
-            } else {
-                panic!("Empty command");
             }
-        } else {
-            panic!("No current command");
+            Op::Mod => {
+                if y == 0 {
+                    x
+                } else {
+                    x.wrapping_rem(y)
+                }
+            }
+            Op::Shl => shift(x, y, |x, y| x << y),
+            Op::Shr => shift(x, y, |x, y| x >> y),
+            Op::Nmul | Op::Ndiv | Op::Nmod => {
+                let op = self.negated().unwrap();
+                op.eval(x, y).wrapping_neg()
+            }
         }
     }
+}
 
-    pub fn arg1(&self) -> String {
-        if let Some(command) = self.commands.get(self.current_command) {
-            let parts: Vec<&str> = command.split_whitespace().collect();
-            match self.command_type() {
-                CommandType::Arithmetic => parts[0].to_string(),
-                CommandType::Push
-                | CommandType::Pop
-                | CommandType::Label
-                | CommandType::Goto
-                | CommandType::If => {
-                    if parts.len() > 1 {
-                        parts[1].to_string()
-                    } else {
-                        panic!("Missing first argument");
-                    }
-                }
-                CommandType::Function | CommandType::Call => {
-                    if parts.len() > 1 {
-                        parts[1].to_string()
-                    } else {
-                        panic!("Missing function name");
-                    }
-                }
-                CommandType::Return => panic!("Return command has no arguments"),
+/// Shifts the bits of `x` by `y` places; shifts outside 0..16 give 0.
+fn shift(x: i16, y: i16, op: fn(u16, u16) -> u16) -> i16 {
+    if (0..16).contains(&y) {
+        op(x as u16, y as u16) as i16
+    } else {
+        0
+    }
+}
+
+fn boolean(value: bool) -> i16 {
+    if value { -1 } else { 0 }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Op {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Op::ALL
+            .into_iter()
+            .find(|op| op.name() == s)
+            .ok_or_else(|| format!("unknown command: {}", s))
+    }
+}
+
+/// A single VM command.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum VmCommand {
+    Arithmetic(Op),
+    Push { segment: Segment, index: u16 },
+    Pop { segment: Segment, index: u16 },
+    Label(String),
+    Goto(String),
+    IfGoto(String),
+    Function { name: String, n_locals: u16 },
+    Call { name: String, n_args: u16 },
+    Return,
+}
+
+impl VmCommand {
+    /// Returns the label a `label`, `goto` or `if-goto` command refers to.
+    pub fn label(&self) -> Option<&str> {
+        match self {
+            VmCommand::Label(label) | VmCommand::Goto(label) | VmCommand::IfGoto(label) => {
+                Some(label)
             }
-        } else {
-            panic!("No current command");
+            _ => None,
         }
     }
+}
 
-    pub fn arg2(&self) -> usize {
-        if let Some(command) = self.commands.get(self.current_command) {
-            let parts: Vec<&str> = command.split_whitespace().collect();
-            match self.command_type() {
-                CommandType::Push
-                | CommandType::Pop
-                | CommandType::Function
-                | CommandType::Call => {
-                    if parts.len() > 2 {
-                        parts[2].parse().expect("Invalid number in arg2")
-                    } else {
-                        panic!("Missing second argument");
-                    }
-                }
-                _ => panic!("Command type does not have arg2"),
+impl fmt::Display for VmCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmCommand::Arithmetic(op) => write!(f, "{}", op),
+            VmCommand::Push { segment, index } => write!(f, "push {} {}", segment, index),
+            VmCommand::Pop { segment, index } => write!(f, "pop {} {}", segment, index),
+            VmCommand::Label(label) => write!(f, "label {}", label),
+            VmCommand::Goto(label) => write!(f, "goto {}", label),
+            VmCommand::IfGoto(label) => write!(f, "if-goto {}", label),
+            VmCommand::Function { name, n_locals } => write!(f, "function {} {}", name, n_locals),
+            VmCommand::Call { name, n_args } => write!(f, "call {} {}", name, n_args),
+            VmCommand::Return => write!(f, "return"),
+        }
+    }
+}
+
+impl FromStr for VmCommand {
+    type Err = String;
+
+    /// Parses one line of VM code, without its comment.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        let Some((&keyword, args)) = parts.split_first() else {
+            return Err("empty command".to_string());
+        };
+
+        let arity = match keyword {
+            "push" | "pop" | "function" | "call" => 2,
+            "label" | "goto" | "if-goto" => 1,
+            "return" => 0,
+            op => {
+                op.parse::<Op>()?;
+                0
             }
-        } else {
-            panic!("No current command");
+        };
+        if args.len() != arity {
+            return Err(format!(
+                "{} expects {} argument{}, found {}",
+                keyword,
+                arity,
+                if arity == 1 { "" } else { "s" },
+                args.len()
+            ));
         }
+        let identifier = |arg: &str| {
+            if labels::is_identifier(arg) {
+                Ok(arg.to_string())
+            } else {
+                Err(format!("invalid identifier: {}", arg))
+            }
+        };
+        let number = |arg: &str| {
+            arg.parse::<u16>()
+                .map_err(|_| format!("invalid number: {}", arg))
+        };
+
+        let command = match keyword {
+            "push" => match (args[0].parse()?, number(args[1])?) {
+                // Constants are loaded with an A-instruction, which holds 15 bits
+                (Segment::Constant, index) if index > i16::MAX as u16 => {
+                    return Err(format!("constant out of range: {} (0-32767)", index));
+                }
+                (segment, index) => VmCommand::Push { segment, index },
+            },
+            "pop" => match args[0].parse()? {
+                Segment::Constant => return Err("cannot pop to constant".to_string()),
+                segment => VmCommand::Pop {
+                    segment,
+                    index: number(args[1])?,
+                },
+            },
+            "label" => VmCommand::Label(identifier(args[0])?),
+            "goto" => VmCommand::Goto(identifier(args[0])?),
+            "if-goto" => VmCommand::IfGoto(identifier(args[0])?),
+            "function" => VmCommand::Function {
+                name: identifier(args[0])?,
+                n_locals: number(args[1])?,
+            },
+            "call" => VmCommand::Call {
+                name: identifier(args[0])?,
+                n_args: number(args[1])?,
+            },
+            "return" => VmCommand::Return,
+            op => VmCommand::Arithmetic(op.parse()?),
+        };
+        Ok(command)
+    }
+}
+
+/// A VM command with its position in the source, so that passes can
+/// rewrite the program before it reaches the `CodeWriter`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    pub command: VmCommand,
+    /// 1-based line in the source `.vm` file
+    pub line: usize,
+    /// File the command was taken from when it differs from the file that
+    /// now contains it (set by the inliner), so `static` still refers to the
+    /// original file's variables
+    pub file: Option<String>,
+}
+
+impl Command {
+    pub fn new(command: VmCommand, line: usize) -> Self {
+        Self {
+            command,
+            line,
+            file: None,
+        }
+    }
+
+    /// Returns true for `push constant n`, yielding `n`.
+    pub fn constant(&self) -> Option<u16> {
+        match self.command {
+            VmCommand::Push {
+                segment: Segment::Constant,
+                index,
+            } => Some(index),
+            _ => None,
+        }
+    }
+
+    pub fn is_arithmetic(&self, op: Op) -> bool {
+        self.command == VmCommand::Arithmetic(op)
+    }
+
+    /// Returns true for the extended commands such as `mul`.
+    pub fn is_extended(&self) -> bool {
+        matches!(self.command, VmCommand::Arithmetic(op) if op.is_extended())
+    }
+
+    /// Returns true if both commands are the same VM instruction,
+    /// regardless of where they come from.
+    pub fn same_instruction(&self, other: &Command) -> bool {
+        self.command == other.command && self.file == other.file
+    }
+
+    /// Returns how many values the command pops from and pushes onto the
+    /// operand stack.
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match &self.command {
+            VmCommand::Arithmetic(op) if op.is_unary() => (1, 1),
+            VmCommand::Arithmetic(_) => (2, 1),
+            VmCommand::Push { .. } => (0, 1),
+            VmCommand::Pop { .. } | VmCommand::IfGoto(_) | VmCommand::Return => (1, 0),
+            VmCommand::Call { n_args, .. } => (*n_args as usize, 1),
+            VmCommand::Label(_) | VmCommand::Goto(_) | VmCommand::Function { .. } => (0, 0),
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.command.fmt(f)
+    }
+}
+
+/// Parses VM source, skipping blank lines and `//` comments.
+/// Errors are reported with their 1-based line number.
+pub fn parse(input: &str) -> Result<Vec<Command>, String> {
+    input
+        .lines()
+        .enumerate()
+        .filter_map(|(number, line)| {
+            let code = line.split("//").next().unwrap_or_default().trim();
+            (!code.is_empty()).then_some((number + 1, code))
+        })
+        .map(|(line, code)| {
+            code.parse()
+                .map(|command| Command::new(command, line))
+                .map_err(|error| format!("line {}: {}", line, error))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_print_round_trip() {
+        let source = "function Main.main 2\npush constant 7\npop local 1\nlabel LOOP\n\
+                      if-goto LOOP\ngoto LOOP\ncall Math.max 2\nmul\nreturn";
+        let commands = parse(source).unwrap();
+        assert_eq!(
+            commands[1].command,
+            VmCommand::Push {
+                segment: Segment::Constant,
+                index: 7
+            }
+        );
+        let printed: Vec<String> = commands.iter().map(|c| c.to_string()).collect();
+        assert_eq!(printed.join("\n"), source);
+    }
+
+    #[test]
+    fn test_positions_and_comments() {
+        let commands = parse("// header\n\n  push  local 0 // x\nadd").unwrap();
+        let lines: Vec<usize> = commands.iter().map(|c| c.line).collect();
+        assert_eq!(lines, [3, 4]);
+        assert_eq!(commands[0].to_string(), "push local 0");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            parse("push constant 1\npush stack 0"),
+            Err("line 2: unknown segment: stack".to_string())
+        );
+        assert_eq!(
+            parse("pop constant 0"),
+            Err("line 1: cannot pop to constant".to_string())
+        );
+        assert_eq!(
+            parse("push constant 65536"),
+            Err("line 1: invalid number: 65536".to_string())
+        );
+        assert_eq!(
+            parse("push constant 32767\npush constant 32768"),
+            Err("line 2: constant out of range: 32768 (0-32767)".to_string())
+        );
+        assert!(parse("push static 40000").is_ok());
+        assert_eq!(
+            parse("label"),
+            Err("line 1: label expects 1 argument, found 0".to_string())
+        );
+        assert_eq!(
+            parse("label LOOP\ngoto f$ret.0"),
+            Err("line 2: invalid identifier: f$ret.0".to_string())
+        );
+        assert_eq!(
+            parse("jump L"),
+            Err("line 1: unknown command: jump".to_string())
+        );
     }
 }
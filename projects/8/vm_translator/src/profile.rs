@@ -0,0 +1,310 @@
+//! Runtime profiling of translated programs.
+//!
+//! With `--profile` every function entry increments a call counter, and
+//! with `--profile-cycles` every basic block also adds its instruction count
+//! to a cycle counter of its function. The counters live in a reserved RAM
+//! region described by the `.profile` map written next to the output;
+//! `vm-profile` combines the map with a RAM dump taken after the run.
+//!
+//! Each counter takes two words holding `high * 32768 + low`, so it counts
+//! up to 2^30 without needing unsigned comparisons in Hack code. Cycles are
+//! estimated: a block is charged the size of its code, so comparisons count
+//! both branches and calls to the extended routines count only the call.
+//!
+//! By default the counters take the top of the stack segment, just below
+//! the heap at 2048: the heap belongs to `Memory.alloc`, and the statics
+//! and temps below 256 to the program. The stack must not grow into them;
+//! with `--stack-guard` its limit is lowered to the counters. A layout that
+//! leaves less than `MIN_STACK_SIZE` words of stack is rejected.
+
+use std::fmt::Write;
+
+use crate::code_writer::STACK_BASE;
+
+/// Default end of the counter region: the top of the stack, below the heap.
+pub const DEFAULT_END: usize = 2048;
+/// Words of stack the counters must leave above its base.
+pub const MIN_STACK_SIZE: usize = 256;
+/// End of the RAM available to the counters, where the screen starts.
+const SCREEN: usize = 16384;
+
+/// Where the counters of each function are stored.
+#[derive(Debug, PartialEq)]
+pub struct ProfileLayout {
+    /// First RAM address of the region
+    pub base: usize,
+    /// Whether cycle counters follow the call counters
+    pub cycles: bool,
+    /// Profiled functions, in counter order
+    pub functions: Vec<String>,
+}
+
+impl ProfileLayout {
+    /// Lays out the counters of `functions` at `base`, or just below the
+    /// heap if no base is given. Fails if they would not fit between the
+    /// minimum stack and the screen.
+    pub fn new(functions: Vec<String>, cycles: bool, base: Option<usize>) -> Result<Self, String> {
+        let words = functions.len() * words_per_function(cycles);
+        let lowest = STACK_BASE + MIN_STACK_SIZE;
+        let base = match base {
+            Some(base) if base < lowest || base.saturating_add(words) > SCREEN => {
+                return Err(format!(
+                    "the {} profile counter words at --profile-base {} must lie within {}..{}",
+                    words, base, lowest, SCREEN
+                ));
+            }
+            Some(base) => base,
+            None => DEFAULT_END
+                .checked_sub(words)
+                .filter(|&base| base >= lowest)
+                .ok_or_else(|| {
+                    format!(
+                        "the {} profile counter words of {} functions leave less than {} words of stack below {}, use --profile-base",
+                        words,
+                        functions.len(),
+                        MIN_STACK_SIZE,
+                        DEFAULT_END
+                    )
+                })?,
+        };
+        Ok(Self {
+            base,
+            cycles,
+            functions,
+        })
+    }
+
+    /// Returns the number of RAM words used by the counters.
+    pub fn size(&self) -> usize {
+        self.functions.len() * words_per_function(self.cycles)
+    }
+
+    pub fn index(&self, function: &str) -> Option<usize> {
+        self.functions.iter().position(|name| name == function)
+    }
+
+    /// Returns the address of the low word of a function's call counter.
+    pub fn calls_address(&self, index: usize) -> usize {
+        self.base + index * words_per_function(self.cycles)
+    }
+
+    /// Returns the address of the low word of a function's cycle counter.
+    pub fn cycles_address(&self, index: usize) -> Option<usize> {
+        self.cycles.then(|| self.calls_address(index) + 2)
+    }
+
+    /// Formats the layout as a `.profile` map.
+    pub fn to_map(&self) -> String {
+        let mut map = format!(
+            "# base {} cycles {}\n# function\tcalls\tcycles\n",
+            self.base, self.cycles
+        );
+        for (index, function) in self.functions.iter().enumerate() {
+            let cycles = self
+                .cycles_address(index)
+                .map_or("-".to_string(), |address| address.to_string());
+            writeln!(
+                map,
+                "{}\t{}\t{}",
+                function,
+                self.calls_address(index),
+                cycles
+            )
+            .unwrap();
+        }
+        map
+    }
+
+    /// Reads a `.profile` map written by `to_map`.
+    pub fn parse_map(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines();
+        let header: Vec<&str> = lines
+            .next()
+            .unwrap_or_default()
+            .split_whitespace()
+            .collect();
+        let (base, cycles) = match header[..] {
+            ["#", "base", base, "cycles", cycles] => (
+                base.parse().map_err(|_| "invalid base address")?,
+                cycles.parse().map_err(|_| "invalid cycles flag")?,
+            ),
+            _ => return Err("not a profile map".to_string()),
+        };
+
+        let functions = lines
+            .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
+            .map(|line| line.split('\t').next().unwrap_or_default().to_string())
+            .collect();
+        Ok(Self {
+            base,
+            cycles,
+            functions,
+        })
+    }
+}
+
+fn words_per_function(cycles: bool) -> usize {
+    if cycles { 4 } else { 2 }
+}
+
+/// Reads a RAM dump: one value per line, either `value` for consecutive
+/// addresses from 0 or `address value`. Values are decimal, negative values
+/// meaning two's complement, or 16-digit binary. Lines with words that are
+/// not numbers, such as the status lines the emulators print around their
+/// `--dump-ram` output, are skipped.
+pub fn parse_ram_dump(text: &str) -> Result<Vec<u16>, String> {
+    let is_number = |word: &str| {
+        let digits = word.trim_end_matches(':');
+        let digits = digits.strip_prefix('-').unwrap_or(digits);
+        !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+    };
+    let mut ram = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || !line.split_whitespace().all(is_number) {
+            continue;
+        }
+        let invalid = || format!("line {}: invalid RAM entry: {}", number + 1, line);
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let (address, value) = match parts[..] {
+            [value] => (ram.len(), value),
+            [address, value] => (
+                address
+                    .trim_end_matches(':')
+                    .parse()
+                    .map_err(|_| invalid())?,
+                value,
+            ),
+            _ => return Err(invalid()),
+        };
+        let value = if value.len() == 16 && value.chars().all(|c| c == '0' || c == '1') {
+            u16::from_str_radix(value, 2).map_err(|_| invalid())?
+        } else {
+            value.parse::<i32>().map_err(|_| invalid())? as u16
+        };
+        if ram.len() <= address {
+            ram.resize(address + 1, 0);
+        }
+        ram[address] = value;
+    }
+    Ok(ram)
+}
+
+/// Reads the counter stored at `address` and `address + 1`.
+pub fn counter(ram: &[u16], address: usize) -> u64 {
+    let word = |address: usize| ram.get(address).copied().unwrap_or(0) as u64;
+    word(address + 1) * 32768 + (word(address) & 0x7fff)
+}
+
+/// Formats the profile of a run, hottest functions first. Functions never
+/// called are left out.
+pub fn report(layout: &ProfileLayout, ram: &[u16]) -> String {
+    let mut rows: Vec<(&str, u64, Option<u64>)> = layout
+        .functions
+        .iter()
+        .enumerate()
+        .map(|(index, function)| {
+            let calls = counter(ram, layout.calls_address(index));
+            let cycles = layout
+                .cycles_address(index)
+                .map(|address| counter(ram, address));
+            (function.as_str(), calls, cycles)
+        })
+        .filter(|(_, calls, _)| *calls > 0)
+        .collect();
+    rows.sort_by(|a, b| (b.2, b.1).cmp(&(a.2, a.1)).then(a.0.cmp(b.0)));
+
+    let total_cycles: u64 = rows.iter().filter_map(|row| row.2).sum();
+    let width = rows.iter().map(|row| row.0.len()).max().unwrap_or(8).max(8);
+    let mut report = String::new();
+    if layout.cycles {
+        writeln!(
+            report,
+            "{:<width$}  {:>10}  {:>12}  {:>6}",
+            "Function", "Calls", "Cycles", "%"
+        )
+        .unwrap();
+    } else {
+        writeln!(report, "{:<width$}  {:>10}", "Function", "Calls").unwrap();
+    }
+    for (function, calls, cycles) in rows {
+        match cycles {
+            Some(cycles) => writeln!(
+                report,
+                "{:<width$}  {:>10}  {:>12}  {:>5.1}%",
+                function,
+                calls,
+                cycles,
+                100.0 * cycles as f64 / total_cycles.max(1) as f64
+            ),
+            None => writeln!(report, "{:<width$}  {:>10}", function, calls),
+        }
+        .unwrap();
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_round_trip() {
+        let layout =
+            ProfileLayout::new(vec!["Main.main".into(), "Main.f".into()], true, None).unwrap();
+        assert_eq!(layout.base, DEFAULT_END - 8);
+        assert_eq!(layout.cycles_address(1), Some(DEFAULT_END - 2));
+        assert_eq!(ProfileLayout::parse_map(&layout.to_map()), Ok(layout));
+    }
+
+    #[test]
+    fn test_layout_bounds() {
+        let functions = |count| (0..count).map(|i| format!("Main.f{}", i)).collect();
+        let layout = ProfileLayout::new(functions(768), false, None).unwrap();
+        assert_eq!(layout.base, STACK_BASE + MIN_STACK_SIZE);
+        assert_eq!(
+            ProfileLayout::new(functions(769), false, None),
+            Err("the 1538 profile counter words of 769 functions leave less than 256 words of stack below 2048, use --profile-base".to_string())
+        );
+        assert!(ProfileLayout::new(functions(2000), true, None).is_err());
+        assert!(ProfileLayout::new(functions(2000), true, Some(4000)).is_ok());
+        assert_eq!(
+            ProfileLayout::new(functions(2), false, Some(16382)),
+            Err(
+                "the 4 profile counter words at --profile-base 16382 must lie within 512..16384"
+                    .to_string()
+            )
+        );
+        assert!(ProfileLayout::new(functions(2), false, Some(100)).is_err());
+    }
+
+    #[test]
+    fn test_report() {
+        let layout = ProfileLayout {
+            base: 100,
+            cycles: false,
+            functions: vec!["Main.main".into(), "Main.f".into(), "Main.g".into()],
+        };
+        let dump = "100 1\n101 0\n102: 5\n103 2\n";
+        let ram = parse_ram_dump(dump).unwrap();
+        assert_eq!(counter(&ram, 102), 2 * 32768 + 5);
+
+        let report = report(&layout, &ram);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("Main.f") && lines[1].ends_with("65541"));
+        assert!(lines[2].starts_with("Main.main"));
+    }
+
+    #[test]
+    fn test_ram_dump_formats() {
+        assert_eq!(
+            parse_ram_dump("7\n-1\n0000000000000011\n").unwrap(),
+            [7, 0xffff, 3]
+        );
+        assert!(parse_ram_dump("1 2 3").is_err());
+        // As printed by cpu_emulator --dump-ram
+        let dump = "Halted after 40 cycles\nPC=12 A=12 D=0\n100 3\n101 -1\n";
+        assert_eq!(parse_ram_dump(dump).unwrap()[100..], [3, 0xffff]);
+    }
+}
@@ -0,0 +1,168 @@
+//! In-memory representation of a VM program, shared by the passes that run
+//! over the whole program before translation.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::bytecode;
+use crate::parser::{self, Command, VmCommand};
+
+/// The parsed commands of a single .vm file.
+pub struct VmFile {
+    /// File name without extension, used as the prefix of static variables
+    pub name: String,
+    pub commands: Vec<Command>,
+}
+
+impl VmFile {
+    /// Parses the source of a file, panicking on syntax errors.
+    pub fn parse(name: &str, content: &str) -> Self {
+        let commands = parser::parse(content)
+            .unwrap_or_else(|error| panic!("Invalid VM code in {}.vm: {}", name, error));
+        Self {
+            name: name.to_string(),
+            commands,
+        }
+    }
+
+    /// Reads a .vm file, or a .vmb file written by `--emit vmb`. Errors
+    /// are prefixed with the path.
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let error = |message: String| format!("{}: {}", path.display(), message);
+        let commands = if path.extension().is_some_and(|extension| extension == "vmb") {
+            let bytes = fs::read(path).map_err(|e| error(e.to_string()))?;
+            bytecode::decode(&bytes).map_err(error)?
+        } else {
+            let content = fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+            parser::parse(&content).map_err(error)?
+        };
+        Ok(Self {
+            name: path.file_stem().unwrap().to_string_lossy().into_owned(),
+            commands,
+        })
+    }
+
+    /// Returns the functions defined in this file, in order.
+    pub fn functions(&self) -> Vec<FunctionSpan> {
+        function_spans(&self.commands)
+    }
+}
+
+/// Returns the files of a program directory, sorted by name: its .vm files,
+/// or its .vmb files if it has no .vm file. The two are never mixed, so
+/// that the translator and the emulator run the same code.
+pub fn directory_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries =
+        fs::read_dir(dir).map_err(|error| format!("cannot read {}: {}", dir.display(), error))?;
+    let paths: Vec<PathBuf> = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .collect();
+    let with_extension = |extension: &str| {
+        let mut files: Vec<PathBuf> = paths
+            .iter()
+            .filter(|path| path.extension().is_some_and(|e| e == extension))
+            .cloned()
+            .collect();
+        files.sort_by(|a, b| a.file_stem().cmp(&b.file_stem()));
+        files
+    };
+    let files = with_extension("vm");
+    Ok(if files.is_empty() {
+        with_extension("vmb")
+    } else {
+        files
+    })
+}
+
+/// The range of commands making up one function: from its `function`
+/// command up to (not including) the next `function` command.
+pub struct FunctionSpan {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+pub fn function_spans(commands: &[Command]) -> Vec<FunctionSpan> {
+    let mut spans: Vec<FunctionSpan> = Vec::new();
+    for (i, command) in commands.iter().enumerate() {
+        if let VmCommand::Function { name, .. } = &command.command {
+            if let Some(previous) = spans.last_mut() {
+                previous.end = i;
+            }
+            spans.push(FunctionSpan {
+                name: name.clone(),
+                start: i,
+                end: commands.len(),
+            });
+        }
+    }
+    spans
+}
+
+/// Control flow between the commands of a function body.
+pub struct ControlFlow<'a> {
+    labels: HashMap<&'a str, usize>,
+}
+
+impl<'a> ControlFlow<'a> {
+    pub fn new(body: &'a [Command]) -> Self {
+        let labels = body
+            .iter()
+            .enumerate()
+            .filter_map(|(i, command)| match &command.command {
+                VmCommand::Label(label) => Some((label.as_str(), i)),
+                _ => None,
+            })
+            .collect();
+        Self { labels }
+    }
+
+    /// Returns the index of the `label` command defining `label`.
+    pub fn label(&self, label: &str) -> Option<usize> {
+        self.labels.get(label).copied()
+    }
+
+    /// Returns the commands that may execute after `body[i]`.
+    /// Jumps to undefined labels have no successor.
+    pub fn successors(&self, body: &[Command], i: usize) -> Vec<usize> {
+        let command = &body[i];
+        let next = (i + 1 < body.len()).then_some(i + 1);
+        match &command.command {
+            VmCommand::Goto(label) => self.label(label).into_iter().collect(),
+            VmCommand::IfGoto(label) => next.into_iter().chain(self.label(label)).collect(),
+            VmCommand::Return => Vec::new(),
+            _ => next.into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_directory_files() {
+        let dir = std::env::temp_dir().join(format!("directory_files_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let names = |files: Vec<PathBuf>| -> Vec<String> {
+            files
+                .iter()
+                .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+
+        for file in ["Sys.vmb", "Main.vmb", "notes.txt"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        assert_eq!(
+            names(directory_files(&dir).unwrap()),
+            ["Main.vmb", "Sys.vmb"]
+        );
+        // A .vm file, even for only some of the files, replaces them all
+        fs::write(dir.join("Sys.vm"), "").unwrap();
+        assert_eq!(names(directory_files(&dir).unwrap()), ["Sys.vm"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
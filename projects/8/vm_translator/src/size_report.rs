@@ -0,0 +1,137 @@
+//! Code size accounting for the generated Hack program.
+//!
+//! Counts the instructions emitted for each VM function and each kind of VM
+//! command, to find out what to optimize when a program outgrows the ROM.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::parser::{Command, Op, VmCommand};
+
+/// Instruction counts per function and per command kind.
+#[derive(Default)]
+pub struct SizeReport {
+    functions: HashMap<String, usize>,
+    kinds: HashMap<&'static str, usize>,
+}
+
+/// Returns the kind a command is counted under.
+pub fn command_kind(command: &Command) -> &'static str {
+    match command.command {
+        VmCommand::Arithmetic(Op::Eq | Op::Gt | Op::Lt) => "comparison",
+        VmCommand::Arithmetic(_) => "arithmetic",
+        VmCommand::Push { .. } => "push",
+        VmCommand::Pop { .. } => "pop",
+        VmCommand::Label(_) | VmCommand::Goto(_) | VmCommand::IfGoto(_) => "branch",
+        VmCommand::Function { .. } => "function",
+        VmCommand::Call { .. } => "call",
+        VmCommand::Return => "return",
+    }
+}
+
+impl SizeReport {
+    /// Counts `instructions` emitted for a command of `kind` in `function`.
+    pub fn add(&mut self, function: &str, kind: &'static str, instructions: usize) {
+        *self.functions.entry(function.to_string()).or_default() += instructions;
+        *self.kinds.entry(kind).or_default() += instructions;
+    }
+
+    pub fn total(&self) -> usize {
+        self.functions.values().sum()
+    }
+
+    /// Formats both tables, largest first, and the total against `rom_size`.
+    pub fn report(&self, rom_size: usize) -> String {
+        let total = self.total();
+        let percent = |count: usize| 100.0 * count as f64 / total.max(1) as f64;
+        let mut report = String::new();
+
+        let width = self
+            .functions
+            .keys()
+            .map(String::len)
+            .max()
+            .unwrap_or(8)
+            .max(8);
+        writeln!(
+            report,
+            "{:<width$}  {:>12}  {:>6}",
+            "Function", "Instructions", "%"
+        )
+        .unwrap();
+        for (function, count) in sorted(&self.functions) {
+            writeln!(
+                report,
+                "{:<width$}  {:>12}  {:>5.1}%",
+                function,
+                count,
+                percent(count)
+            )
+            .unwrap();
+        }
+
+        writeln!(report).unwrap();
+        writeln!(
+            report,
+            "{:<10}  {:>12}  {:>6}",
+            "Command", "Instructions", "%"
+        )
+        .unwrap();
+        for (kind, count) in sorted(&self.kinds) {
+            writeln!(
+                report,
+                "{:<10}  {:>12}  {:>5.1}%",
+                kind,
+                count,
+                percent(count)
+            )
+            .unwrap();
+        }
+
+        writeln!(report).unwrap();
+        writeln!(
+            report,
+            "Total: {} of {} ROM words ({:.1}%)",
+            total,
+            rom_size,
+            100.0 * total as f64 / rom_size as f64
+        )
+        .unwrap();
+        if total > rom_size {
+            writeln!(report, "Over the ROM limit by {} words", total - rom_size).unwrap();
+        }
+        report
+    }
+}
+
+/// Returns the entries largest first, ties by name.
+fn sorted<K: AsRef<str>>(counts: &HashMap<K, usize>) -> Vec<(&str, usize)> {
+    let mut entries: Vec<(&str, usize)> = counts
+        .iter()
+        .map(|(key, &count)| (key.as_ref(), count))
+        .collect();
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_is_sorted_by_size() {
+        let mut sizes = SizeReport::default();
+        sizes.add("Main.small", "push", 7);
+        sizes.add("Main.big", "call", 40);
+        sizes.add("Main.big", "push", 7);
+
+        assert_eq!(sizes.total(), 54);
+        let report = sizes.report(50);
+        let lines: Vec<&str> = report.lines().collect();
+        assert!(lines[1].starts_with("Main.big"));
+        assert!(lines[2].starts_with("Main.small"));
+        assert!(lines[5].starts_with("call"));
+        assert!(report.contains("Total: 54 of 50 ROM words"));
+        assert!(report.contains("Over the ROM limit by 4 words"));
+    }
+}
@@ -0,0 +1,164 @@
+//! Stack discipline verifier for VM functions.
+//!
+//! Follows every path through a function and checks that the operand stack
+//! never underflows, that each command is always reached with the same stack
+//! depth whatever the incoming edge, and that exactly one value is on the
+//! stack at each `return`.
+
+use std::fmt;
+
+use crate::parser::{Command, VmCommand};
+use crate::program::{ControlFlow, VmFile};
+
+/// A stack discipline violation at a VM command.
+#[derive(Debug)]
+pub struct Violation {
+    pub file: String,
+    pub line: usize,
+    pub function: String,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}.vm:{}: in {}: {}",
+            self.file, self.line, self.function, self.message
+        )
+    }
+}
+
+/// Verifies every function of the program. Code outside functions, as in
+/// the chapter 7 tests, has no frame and is not checked.
+pub fn verify(files: &[VmFile]) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    for file in files {
+        for function in file.functions() {
+            let body = &file.commands[function.start + 1..function.end];
+            for (line, message) in verify_function(body) {
+                violations.push(Violation {
+                    file: file.name.clone(),
+                    line,
+                    function: function.name.clone(),
+                    message,
+                });
+            }
+        }
+    }
+    violations
+}
+
+/// Returns the violations in a function body as (line, message) pairs.
+fn verify_function(body: &[Command]) -> Vec<(usize, String)> {
+    let flow = ControlFlow::new(body);
+    let mut problems = Vec::new();
+    let mut depths: Vec<Option<usize>> = vec![None; body.len()];
+    let mut worklist = Vec::new();
+    if !body.is_empty() {
+        depths[0] = Some(0);
+        worklist.push(0);
+    }
+
+    while let Some(i) = worklist.pop() {
+        let command = &body[i];
+        let depth = depths[i].unwrap();
+        let (pops, pushes) = command.stack_effect();
+
+        if depth < pops {
+            problems.push((
+                command.line,
+                format!(
+                    "stack underflow: '{}' needs {} values but the stack has {}",
+                    command, pops, depth
+                ),
+            ));
+        }
+        if command.command == VmCommand::Return && depth != 1 {
+            problems.push((
+                command.line,
+                format!("return with {} values on the stack instead of 1", depth),
+            ));
+        }
+
+        let after = depth.saturating_sub(pops) + pushes;
+        let successors = flow.successors(body, i);
+        let falls_off_end = i + 1 == body.len()
+            && !matches!(command.command, VmCommand::Goto(_) | VmCommand::Return);
+        if falls_off_end {
+            problems.push((command.line, "function ends without return".to_string()));
+        }
+
+        for next in successors {
+            match depths[next] {
+                None => {
+                    depths[next] = Some(after);
+                    worklist.push(next);
+                }
+                Some(expected) if expected != after => {
+                    problems.push((
+                        body[next].line,
+                        format!(
+                            "'{}' is reached with {} values on the stack after '{}' (line {}), but with {} on another path",
+                            body[next], after, command, command.line, expected
+                        ),
+                    ));
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    problems.sort();
+    problems.dedup();
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn violations(source: &str) -> Vec<String> {
+        verify(&[VmFile::parse("Main", source)])
+            .iter()
+            .map(|violation| violation.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_balanced_function() {
+        let source = "function Main.max 0\npush argument 0\npush argument 1\ngt\nif-goto A\n\
+                      push argument 1\nreturn\nlabel A\npush argument 0\nreturn";
+        assert!(violations(source).is_empty());
+    }
+
+    #[test]
+    fn test_underflow_and_return() {
+        assert_eq!(
+            violations("function Main.f 0\npush constant 1\nadd\npush constant 2\nreturn"),
+            [
+                "Main.vm:3: in Main.f: stack underflow: 'add' needs 2 values but the stack has 1",
+                "Main.vm:5: in Main.f: return with 2 values on the stack instead of 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inconsistent_label_depth() {
+        let source = "function Main.f 0\npush constant 1\nif-goto L\npush constant 2\nlabel L\npush constant 3\nreturn";
+        assert_eq!(
+            violations(source),
+            [
+                "Main.vm:5: in Main.f: 'label L' is reached with 1 values on the stack after 'push constant 2' (line 4), but with 0 on another path"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_return() {
+        assert_eq!(
+            violations("function Main.f 0\npush constant 1\npop temp 0"),
+            ["Main.vm:3: in Main.f: function ends without return"]
+        );
+    }
+}
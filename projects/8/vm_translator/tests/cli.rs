@@ -0,0 +1,255 @@
+//! End-to-end tests of the translator and its tools, run as commands.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Creates an empty directory `<temp>/<test>_<pid>/<name>` holding the
+/// given files.
+fn program_dir(test: &str, name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir()
+        .join(format!("{}_{}", test, std::process::id()))
+        .join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    for (file, source) in files {
+        fs::write(dir.join(file), source).unwrap();
+    }
+    dir
+}
+
+/// Runs the translator, panicking on failure.
+fn translate(args: &[&str], input: &Path) {
+    let output = Command::new(env!("CARGO_BIN_EXE_vm_translator"))
+        .args(args)
+        .arg(input)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// Runs a program on the CPU emulator of project 5 and returns its output.
+fn run_cpu_emulator(args: &[&str], hack: &Path) -> String {
+    let emulator = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../5/cpu_emulator/Cargo.toml");
+    let run = Command::new(env!("CARGO"))
+        .args(["run", "--quiet", "--manifest-path"])
+        .arg(&emulator)
+        .arg("--")
+        .args(args)
+        .arg(hack)
+        .output()
+        .unwrap();
+    assert!(run.status.success());
+    String::from_utf8(run.stdout).unwrap()
+}
+
+/// Parses the `<address> <value>` lines of a RAM dump.
+fn ram_values(output: &str) -> Vec<(usize, i16)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (address, value) = line.split_once(' ')?;
+            Some((address.parse().ok()?, value.parse().ok()?))
+        })
+        .collect()
+}
+
+#[test]
+fn test_bytecode_does_not_carry_passes() {
+    let dir = program_dir(
+        "vmb",
+        "Prog",
+        &[
+            (
+                "Sys.vm",
+                "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END\n",
+            ),
+            (
+                "Main.vm",
+                "function Main.main 0\npush constant 6\npush constant 7\ncall Math.multiply 2\nreturn\nfunction Main.unused 0\npush constant 0\nreturn\n",
+            ),
+            (
+                "Math.vm",
+                "function Math.multiply 0\npush argument 0\npush argument 1\nadd\nreturn\n",
+            ),
+        ],
+    );
+    let asm = dir.join("Prog.asm");
+    translate(&[], &dir);
+    let plain = fs::read_to_string(&asm).unwrap();
+
+    // Without the .vm files, the .vmb files are loaded by the next builds,
+    // which must not inherit the stripping or the `mul` commands
+    translate(&["--emit", "vmb", "--strip-unused", "--ext-math"], &dir);
+    for file in ["Sys", "Main", "Math"] {
+        fs::remove_file(dir.join(format!("{}.vm", file))).unwrap();
+    }
+    translate(&[], &dir);
+    assert_eq!(fs::read_to_string(&asm).unwrap(), plain);
+    fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn test_profile_of_cpu_emulator_run() {
+    let dir = program_dir(
+        "profile",
+        "Prog",
+        &[(
+            "Sys.vm",
+            "function Sys.init 0\ncall Sys.f 0\ncall Sys.f 0\ncall Sys.f 0\nlabel END\ngoto END\nfunction Sys.f 0\npush constant 0\nreturn\n",
+        )],
+    );
+    translate(&["--profile", "--emit", "hack"], &dir);
+
+    // The emulator prints status lines around the dump, which vm-profile
+    // reads as is
+    let output = run_cpu_emulator(&["--dump-ram", "2000-2047"], &dir.join("Prog.hack"));
+    let dump = dir.join("ram.txt");
+    fs::write(&dump, output).unwrap();
+    let profile = Command::new(env!("CARGO_BIN_EXE_vm-profile"))
+        .arg(dir.join("Prog.profile"))
+        .arg(&dump)
+        .output()
+        .unwrap();
+    assert!(
+        profile.status.success(),
+        "{}",
+        String::from_utf8_lossy(&profile.stderr)
+    );
+    let report = String::from_utf8(profile.stdout).unwrap();
+    let calls: Vec<Vec<&str>> = report
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect())
+        .collect();
+    assert_eq!(calls, [["Sys.f", "3"], ["Sys.init", "1"]]);
+    fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn test_stack_guard_stops_before_the_heap() {
+    let dir = program_dir(
+        "overflow",
+        "Prog",
+        &[(
+            "Sys.vm",
+            "function Sys.init 0\nlabel LOOP\npush constant 7\ngoto LOOP\n",
+        )],
+    );
+    translate(&["--stack-guard", "--emit", "hack"], &dir);
+    let output = run_cpu_emulator(
+        &[
+            "--dump-ram",
+            "0-0",
+            "--dump-ram",
+            "15-15",
+            "--dump-ram",
+            "2047-2048",
+        ],
+        &dir.join("Prog.hack"),
+    );
+    // SP at the limit, an overflow reported in R15, and the first heap
+    // word untouched
+    assert_eq!(
+        ram_values(&output),
+        [(0, 2048), (15, 1), (2047, 7), (2048, 0)]
+    );
+    fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn test_extended_commands_leave_r15_to_stack_guards() {
+    let dir = program_dir(
+        "ext_guard",
+        "Prog",
+        &[(
+            "Sys.vm",
+            "function Sys.init 0\npush constant 6\npush constant 7\nmul\npop static 0\nlabel END\ngoto END\n",
+        )],
+    );
+    translate(&["--ext", "--stack-guard", "--emit", "hack"], &dir);
+    let output = run_cpu_emulator(&["--dump-ram", "15-16"], &dir.join("Prog.hack"));
+    // No stack error, and the product in the first static
+    assert_eq!(ram_values(&output), [(15, 0), (16, 42)]);
+    fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn test_written_vm_code_translates_again() {
+    let dir = program_dir(
+        "vm_output",
+        "Prog",
+        &[
+            (
+                "Sys.vm",
+                "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END\n",
+            ),
+            (
+                "Main.vm",
+                "function Main.main 0\npush constant 3\ncall Main.sign 1\nreturn\n\
+                 function Main.sign 0\npush argument 0\nif-goto NONZERO\npush constant 0\nreturn\n\
+                 label NONZERO\npush constant 1\nreturn\n",
+            ),
+        ],
+    );
+    translate(&["--opt-vm", "--inline"], &dir);
+    let written = fs::read_to_string(dir.join("output/Main.vm")).unwrap();
+    assert!(written.contains("label INLINE0.NONZERO"));
+    translate(&[], &dir.join("output"));
+    fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn test_inlined_statics_test_passes() {
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("../FunctionCalls/StaticsTest");
+    let files: Vec<(String, String)> = [
+        "Sys.vm",
+        "Class1.vm",
+        "Class2.vm",
+        "StaticsTest.tst",
+        "StaticsTest.cmp",
+    ]
+    .iter()
+    .map(|file| {
+        (
+            file.to_string(),
+            fs::read_to_string(source.join(file)).unwrap(),
+        )
+    })
+    .collect();
+    let files: Vec<(&str, &str)> = files
+        .iter()
+        .map(|(file, text)| (file.as_str(), text.as_str()))
+        .collect();
+    let dir = program_dir("inline", "StaticsTest", &files);
+    translate(&["--inline"], &dir);
+    // The script compares the registers and statics after the run
+    run_cpu_emulator(&[], &dir.join("StaticsTest.tst"));
+    fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn test_single_file_calls_other_files() {
+    let dir = program_dir(
+        "single_file",
+        "Prog",
+        &[(
+            "Main.vm",
+            "function Main.double 0\npush argument 0\npush constant 2\ncall Math.multiply 2\nreturn\n",
+        )],
+    );
+    let file = dir.join("Main.vm");
+    translate(&[], &file);
+    assert!(dir.join("Main.asm").exists());
+    let checked = Command::new(env!("CARGO_BIN_EXE_vm_translator"))
+        .arg("--link-check")
+        .arg(&file)
+        .output()
+        .unwrap();
+    assert!(!checked.status.success());
+    fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+}